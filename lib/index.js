@@ -3,6 +3,18 @@ const path = require("path");
 const util = require("util");
 const realpath = util.promisify(require("fs").realpath);
 
+// Resolve symlinks in a path that may not exist yet, by resolving as much of
+// it as does exist.
+async function resolvePath(target) {
+  try {
+    return await realpath(target);
+  } catch (error) {
+    const parent = path.dirname(target);
+    if (error.code !== "ENOENT" || parent === target) throw error;
+    return path.join(await resolvePath(parent), path.basename(target));
+  }
+}
+
 module.exports = class Watcher {
   constructor(params) {
     this.nextRequestId = 0;
@@ -22,8 +34,31 @@ module.exports = class Watcher {
     const args = [];
     if (params && params.pollInterval)
       args.push("--poll-interval", params.pollInterval);
+    if (params && params.debounceMs)
+      args.push("--debounce-ms", params.debounceMs);
+    if (params && params.keepaliveMs)
+      args.push("--keepalive-ms", params.keepaliveMs);
+    if (params && params.drainMs) args.push("--drain-ms", params.drainMs);
+    if (params && params.eventLog) args.push("--event-log", params.eventLog);
+    if (params && params.batchEmit) args.push("--batch-emit");
+    if (params && params.globalSeq) args.push("--global-seq");
+    if (params && params.eventIds) args.push("--event-ids");
+    if (params && params.canonicalizeTimeoutMs)
+      args.push("--canonicalize-timeout-ms", params.canonicalizeTimeoutMs);
+    if (params && params.maxWatches)
+      args.push("--max-watches", params.maxWatches);
+    if (params && params.measureLatency) args.push("--measure-latency");
+    if (params && params.pathMap) {
+      for (const mapping of params.pathMap) args.push("--path-map", mapping);
+    }
+    // The subprocess would answer a watch preloaded from the environment with
+    // a response that collides with ours
+    const env = Object.assign({}, process.env);
+    delete env.NOTIFY_WATCH_ROOT;
+    delete env.NOTIFY_WATCH_IGNORE;
     this.childProcess = spawn(binPath, args, {
-      stdio: ["pipe", "pipe", "pipe"]
+      stdio: ["pipe", "pipe", "pipe"],
+      env
     });
     this.kill = this.kill.bind(this);
     process.on("exit", this.kill);
@@ -64,24 +99,331 @@ module.exports = class Watcher {
     }
   }
 
-  // Create a new watch for the given path
-  async watchPath(path, callback) {
+  // Shut the child process down once the events that were pending when this
+  // was called have been delivered, waiting no longer than `drainMs` for them.
+  // Defaults to the `drainMs` constructor parameter.
+  async shutdown(drainMs) {
+    if (!this.killed) {
+      process.removeListener("exit", this.kill);
+      this.killed = true;
+      const exited = new Promise(resolve =>
+        this.childProcess.once("exit", resolve)
+      );
+      await this._sendRequest({ type: "shutdown", drainMs });
+      await exited;
+    }
+  }
+
+  // Resolve once all events that were pending when this was called have been
+  // delivered to their callbacks
+  async flush() {
+    if (!this.killed) {
+      await this._sendRequest({ type: "flush" });
+    }
+  }
+
+  // Check whether the subprocess is still delivering events. Resolves with an
+  // object of the form `{notifyThreadAlive, lastTickMsAgo, restarted}`.
+  async health() {
+    const response = await this._sendRequest({ type: "health" });
+    return {
+      notifyThreadAlive: response.notifyThreadAlive,
+      lastTickMsAgo: response.lastTickMsAgo,
+      restarted: response.restarted
+    };
+  }
+
+  // Resolve with the versions of the subprocess, in the form
+  // `{crateVersion, protocolVersion, notifyVersion}`. `protocolVersion` only
+  // changes when the protocol changes incompatibly.
+  async version() {
+    const response = await this._sendRequest({ type: "version" });
+    return {
+      crateVersion: response.crateVersion,
+      protocolVersion: response.protocolVersion,
+      notifyVersion: response.notifyVersion
+    };
+  }
+
+  // Resolve with the inotify limits and how many watch descriptors the
+  // subprocess holds, in the form
+  // `{maxUserWatches, maxUserInstances, currentInstanceWatches}`, for keeping
+  // an eye on how close it is to `fs.inotify.max_user_watches`. Every field is
+  // null on platforms other than Linux.
+  async systemInfo() {
+    const response = await this._sendRequest({ type: "systemInfo" });
+    return {
+      maxUserWatches: response.maxUserWatches,
+      maxUserInstances: response.maxUserInstances,
+      currentInstanceWatches: response.currentInstanceWatches
+    };
+  }
+
+  // Resolve with the number of events that the subprocess has received from
+  // its debouncers but not yet delivered, for tuning `debounceMs`.
+  async debugQueue() {
+    const response = await this._sendRequest({ type: "debugQueue" });
+    return response.pending;
+  }
+
+  // Resolve with `{monotonicMs, wallMs}`, the subprocess's current time since
+  // it started and since the Unix epoch, for measuring how far its clock is off
+  // from this process's.
+  async time() {
+    const response = await this._sendRequest({ type: "time" });
+    return { monotonicMs: response.monotonicMs, wallMs: response.wallMs };
+  }
+
+  // Resolve with `{p50Ms, p95Ms, maxMs, count}`, summarizing how long the
+  // subprocess took to handle its last requests. Requires the `measureLatency`
+  // constructor option.
+  async latencies() {
+    const response = await this._sendRequest({ type: "latencies" });
+    return {
+      p50Ms: response.p50Ms,
+      p95Ms: response.p95Ms,
+      maxMs: response.maxMs,
+      count: response.count
+    };
+  }
+
+  // Resolve with `{input, canonical, error}`, where `canonical` is the given
+  // path with its symlinks resolved by the subprocess, as in the paths of
+  // events, or null along with the `error` that prevented it.
+  async canonicalize(path) {
+    const response = await this._sendRequest({ type: "canonicalize", path });
+    return {
+      input: response.input,
+      canonical: response.canonical,
+      error: response.error
+    };
+  }
+
+  // Resolve with the ids of the watches that would emit events for the given
+  // path, taking their options into account.
+  async isWatched(path) {
+    const response = await this._sendRequest({ type: "isWatched", path });
+    return response.watchIds;
+  }
+
+  // Resolve with the sorted paths of everything under the given directory that
+  // passes `options`, any of the `include`, `ignore` and `extensions` of a
+  // filter profile, without watching it.
+  async ls(path, options = {}) {
+    const response = await this._sendRequest({
+      type: "ls",
+      path,
+      include: options.include,
+      ignore: options.ignore,
+      extensions: options.extensions
+    });
+    return response.entries;
+  }
+
+  // Resolve with the current watches in the format of a config file, of the
+  // form `{watches: [{watchId, root, ...options}]}`. Watches created with
+  // `watchFiles` aren't included.
+  async exportConfig() {
+    const response = await this._sendRequest({ type: "exportConfig" });
+    return { watches: response.watches };
+  }
+
+  // Call `callback` with each change to the subprocess's own state from now
+  // on, of the form `{kind, watchId, detail}`, where `kind` is one of
+  // "watchAdded", "watchRemoved", "notifyThreadRestarted" or "queueOverflow".
+  async subscribeLifecycle(callback) {
+    this.lifecycleCallback = callback;
+    await this._sendRequest({ type: "subscribeLifecycle" });
+  }
+
+  // Change how long native events are debounced for, as with the `debounceMs`
+  // constructor option. Every watch then receives a `rescan` event for its
+  // root, since events can be lost while the change is made.
+  async setDebounce(debounceMs) {
+    await this._sendRequest({ type: "setDebounce", debounceMs });
+  }
+
+  // Stop delivering events to every watch without unregistering any of them.
+  // Resolves with the number of watches that were paused.
+  async pauseAll() {
+    const response = await this._sendRequest({ type: "pauseAll" });
+    return response.affectedWatches;
+  }
+
+  // Resume delivering events after `pauseAll`. Each watch then receives a
+  // `rescan` event for its root, since the events in between were dropped.
+  // Resolves with the number of watches that were resumed.
+  async resumeAll() {
+    const response = await this._sendRequest({ type: "resumeAll" });
+    return response.affectedWatches;
+  }
+
+  // Create a new watch for the given path, which doesn't need to exist yet.
+  // The optional `options` are sent along with the watch request.
+  async watchPath(path, options, callback) {
+    if (typeof options === "function") {
+      callback = options;
+      options = {};
+    }
     if (this.killed) {
       throw new Error("This watcher has been killed");
     }
 
     const watchId = this.nextWatchId++;
-    const root = await realpath(path);
+    const root = await resolvePath(path);
 
     this.watchCallbacks.set(watchId, callback);
-    await this._sendRequest({
+    const request = Object.assign({}, options, {
       type: "watch",
       watchId,
       root
     });
+    if (options.inheritFrom) {
+      // Inherit the filters of another object returned by `watchPath`
+      request.inheritFrom = options.inheritFrom.watchId;
+    }
+    const response = await this._sendRequest(request);
 
+    return Object.assign(this._watchHandle(watchId), {
+      // Directories under the root that couldn't be read and aren't being watched
+      unwatchedSubdirs: response.unwatchedSubdirs || []
+    });
+  }
+
+  // Create a new watch for exactly the given files, without reporting changes
+  // to anything else in the directories that contain them. The files don't
+  // need to exist yet, but their directories do.
+  async watchFiles(paths, options, callback) {
+    if (typeof options === "function") {
+      callback = options;
+      options = {};
+    }
+    if (this.killed) {
+      throw new Error("This watcher has been killed");
+    }
+
+    const watchId = this.nextWatchId++;
+    const files = await Promise.all(paths.map(resolvePath));
+
+    this.watchCallbacks.set(watchId, callback);
+    await this._sendRequest(
+      Object.assign({}, options, {
+        type: "watchFiles",
+        watchId,
+        files
+      })
+    );
+    return this._watchHandle(watchId);
+  }
+
+  _watchHandle(watchId) {
     let disposed = false;
     return {
+      // The id the subprocess knows the watch by, as in `exportConfig`
+      watchId,
+      // Start or stop emitting events with the given actions, resolving with
+      // the actions that the watch emits afterwards
+      addActions: actions => this._updateActions("addActions", watchId, actions),
+      removeActions: actions =>
+        this._updateActions("removeActions", watchId, actions),
+      // Switch between watching the whole tree and only the path's direct
+      // children, as with the `recursive` option. The watch receives a `rescan`
+      // event for its path, since events can be missed while switching.
+      setRecursive: async recursive => {
+        await this._sendRequest({ type: "setRecursive", watchId, recursive });
+      },
+      // Change how many distinct paths the watch delivers events for, as with
+      // the `maxEntries` option, or lift the limit when it's omitted
+      setMaxEntries: async maxEntries => {
+        await this._sendRequest({
+          type: "setMaxEntries",
+          watchId,
+          maxEntries
+        });
+      },
+      // Replace the file name patterns the watch ignores with the ones in
+      // `rulesFile`, one per line. With `{watch: true}`, they're read again
+      // whenever the file changes.
+      reloadRules: async (rulesFile, { watch = false } = {}) => {
+        await this._sendRequest({
+          type: "reloadRules",
+          watchId,
+          rulesFile,
+          watch
+        });
+      },
+      // Resolve with `{emit, reason}`, telling whether the watch would deliver
+      // an event with `action` for `path` and if not, which of its options is
+      // in the way
+      wouldEmit: async (path, action) => {
+        const response = await this._sendRequest({
+          type: "wouldEmit",
+          watchId,
+          path,
+          action
+        });
+        return { emit: response.emit, reason: response.reason };
+      },
+      // Resolve with the actions the watch's events can have, which leaves out
+      // `modified` for watches created with the `createdOnly` option
+      effectiveActions: async () => {
+        const response = await this._sendRequest({
+          type: "effectiveActions",
+          watchId
+        });
+        return response.actions;
+      },
+      // Resolve with a hash of the watched tree, for watches created with the
+      // `treeHash` option
+      treeHash: async () => {
+        const response = await this._sendRequest({ type: "treeHash", watchId });
+        return response.hash;
+      },
+      // Debounce this watch's native events for `debounceMs` instead, such as
+      // to coalesce a bulk operation on its tree, or go back to the usual
+      // debounce when it's omitted. The watch receives a `rescan` event for
+      // its root whenever this changes how its events are debounced.
+      setDebounce: async debounceMs => {
+        await this._sendRequest({
+          type: "setWatchDebounce",
+          watchId,
+          debounceMs
+        });
+      },
+      // Hold on to up to `maxBuffer` of the watch's events instead of
+      // delivering them, until `resume` delivers them in order
+      bufferedPause: async maxBuffer => {
+        await this._sendRequest({ type: "bufferedPause", watchId, maxBuffer });
+      },
+      // Deliver the events kept since `bufferedPause`, and then new ones as
+      // usual. If more events occurred than it kept, the watch then receives a
+      // `rescan` event for its root.
+      resume: async () => {
+        await this._sendRequest({ type: "resume", watchId });
+      },
+      // Resolve with the last events the watch emitted, for watches created
+      // with the `history` option
+      history: async () => {
+        const response = await this._sendRequest({ type: "history", watchId });
+        return response.events;
+      },
+      // Resolve with `{contains, containedBy}`, the ids of the other watches
+      // whose paths are all within this one's, and of those that cover all of
+      // this one's paths
+      overlaps: async () => {
+        const response = await this._sendRequest({ type: "overlaps", watchId });
+        return {
+          contains: response.contains,
+          containedBy: response.containedBy
+        };
+      },
+      // Resolve with `{path, mtimeMs, size}` for every entry the watch would
+      // report events for, ordered by path, to reconcile against the events
+      // received so far. Directories have no `size`.
+      snapshot: async () => {
+        const response = await this._sendRequest({ type: "snapshot", watchId });
+        return response.entries;
+      },
       dispose: async () => {
         if (!disposed) {
           disposed = true;
@@ -101,6 +443,11 @@ module.exports = class Watcher {
     }
   }
 
+  async _updateActions(type, watchId, actions) {
+    const response = await this._sendRequest({ type, watchId, actions });
+    return response.actions;
+  }
+
   _sendRequest(message) {
     const requestId = this.nextRequestId++;
     const request = Object.assign({}, message, { requestId });
@@ -111,17 +458,37 @@ module.exports = class Watcher {
   }
 
   _handleMessage(message) {
-    if (message.type === "okResponse") {
-      this.pendingResponses.get(message.requestId).resolve();
-      this.pendingResponses.delete(message.requestId);
+    if (
+      message.reason === "limitReached" ||
+      message.reason === "durationElapsed"
+    ) {
+      // A second response to a watch request, sent when the watch is removed
+      this.watchCallbacks.delete(message.watchId);
     } else if (message.type === "errorResponse") {
       this.pendingResponses.get(message.requestId).reject(message.description);
       this.pendingResponses.delete(message.requestId);
+    } else if (message.type.endsWith("Response")) {
+      this.pendingResponses.get(message.requestId).resolve(message);
+      this.pendingResponses.delete(message.requestId);
     } else if (message.type === "watchEvents") {
       const callback = this.watchCallbacks.get(message.watchId);
       if (callback) callback(message.events);
     } else if (message.type === "watcherError") {
       this.onError(message.description);
+    } else if (message.type === "lifecycle") {
+      if (this.lifecycleCallback) {
+        this.lifecycleCallback({
+          kind: message.kind,
+          watchId: message.watchId,
+          detail: message.detail
+        });
+      }
+    } else if (message.type === "keepalive") {
+      this.lastKeepalive = message.timestampMs;
+    } else if (message.type === "batch") {
+      for (const batchedMessage of message.messages) {
+        this._handleMessage(batchedMessage);
+      }
     } else {
       throw new Error(`Unexpected message type ${message.type}`);
     }
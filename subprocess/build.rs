@@ -0,0 +1,32 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// Embed the version of notify that's actually being built against, as resolved in the nearest
+// Cargo.lock, so that the `version` request can report it.
+fn main() {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let lockfile = manifest_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|lockfile| lockfile.exists());
+
+    let version = lockfile
+        .as_ref()
+        .and_then(|lockfile| fs::read_to_string(lockfile).ok())
+        .and_then(|contents| locked_version(&contents, "notify"))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    if let Some(lockfile) = lockfile {
+        println!("cargo:rerun-if-changed={}", lockfile.display());
+    }
+    println!("cargo:rustc-env=NOTIFY_VERSION={}", version);
+}
+
+fn locked_version(lockfile: &str, package: &str) -> Option<String> {
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lockfile.lines().skip_while(|line| *line != name);
+    lines.next()?;
+    let version = lines.next()?.strip_prefix("version = \"")?;
+    Some(version.trim_end_matches('"').to_string())
+}
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The number of paths whose attributes are remembered per watch. Once this many are cached, the
+/// cache is cleared and starts over, so the next modification of each path is always reported.
+const MAX_CACHED_PATHS: usize = 10_000;
+
+/// An attribute whose change causes a `modified` event to be emitted.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ModifiedAttribute {
+    Content,
+    Mtime,
+    Size,
+}
+
+#[derive(Debug, PartialEq)]
+struct FileAttributes {
+    size: Option<u64>,
+    mtime: Option<SystemTime>,
+    content_hash: Option<u64>,
+}
+
+/// Remembers the last observed attributes of each path so that modifications which didn't change
+/// any of the attributes a watch cares about can be suppressed.
+#[derive(Default)]
+pub struct AttributeCache {
+    entries: HashMap<PathBuf, FileAttributes>,
+}
+
+impl AttributeCache {
+    /// Record the current attributes of `path` and return whether any of `watched` changed since
+    /// they were last recorded. Paths that weren't cached are always considered changed.
+    pub fn update(&mut self, path: &Path, watched: &[ModifiedAttribute]) -> bool {
+        let attributes = FileAttributes::read(path, watched);
+        let changed = self.entries.get(path) != Some(&attributes);
+
+        if self.entries.len() >= MAX_CACHED_PATHS && !self.entries.contains_key(path) {
+            self.entries.clear();
+        }
+        self.entries.insert(path.to_path_buf(), attributes);
+        changed
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.entries.remove(path);
+    }
+
+    pub fn rename(&mut self, old_path: &Path, new_path: &Path) {
+        if let Some(attributes) = self.entries.remove(old_path) {
+            self.entries.insert(new_path.to_path_buf(), attributes);
+        }
+    }
+}
+
+impl FileAttributes {
+    fn read(path: &Path, watched: &[ModifiedAttribute]) -> Self {
+        let metadata = fs::metadata(path).ok();
+        let watches = |attribute| watched.contains(&attribute);
+
+        Self {
+            size: metadata
+                .as_ref()
+                .filter(|_| watches(ModifiedAttribute::Size))
+                .map(|metadata| metadata.len()),
+            mtime: metadata
+                .as_ref()
+                .filter(|_| watches(ModifiedAttribute::Mtime))
+                .and_then(|metadata| metadata.modified().ok()),
+            content_hash: metadata
+                .filter(|metadata| watches(ModifiedAttribute::Content) && metadata.is_file())
+                .and_then(|_| fs::read(path).ok())
+                .map(|content| {
+                    let mut hasher = DefaultHasher::new();
+                    hasher.write(&content);
+                    hasher.finish()
+                }),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use notify::{DebouncedEvent, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The interval used by watches that ask for polling without specifying one, unless polling is
+/// also the default backend.
+const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+
+/// Identifies a watcher instance. Watches that resolve to the same backend share one watcher.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Backend {
+    Native,
+    Poll {
+        interval_ms: u64,
+    },
+    /// The native backend with a debounce of its own, for watches moved to it with
+    /// `setWatchDebounce`
+    Debounced {
+        debounce_ms: u64,
+    },
+}
+
+/// The backend requested by a watch.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum BackendKind {
+    Native,
+    Poll,
+}
+
+/// A watcher for one of the supported backends. notify's `Watcher` trait can't be made into a
+/// trait object, so we dispatch over the concrete implementations instead.
+pub enum BackendWatcher {
+    Native(RecommendedWatcher),
+    Poll(PollWatcher),
+}
+
+/// The watchers for every backend in use. Each one delivers its events, tagged with the backend
+/// they came from, on the same channel.
+pub struct Watchers {
+    watchers: HashMap<Backend, BackendWatcher>,
+    default: Backend,
+    native_delay: Duration,
+    tx: EventSender,
+}
+
+/// The sending end of the channel on which watchers deliver their debounced events. It keeps
+/// count of the events that have been sent but not received yet, for diagnostics.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: Sender<(Backend, DebouncedEvent)>,
+    queued: Arc<AtomicUsize>,
+}
+
+/// The receiving end of the channel created by [`event_channel`].
+pub struct EventReceiver {
+    rx: Receiver<(Backend, DebouncedEvent)>,
+    queued: Arc<AtomicUsize>,
+}
+
+pub fn event_channel() -> (EventSender, EventReceiver) {
+    let (tx, rx) = mpsc::channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    (
+        EventSender {
+            tx,
+            queued: queued.clone(),
+        },
+        EventReceiver { rx, queued },
+    )
+}
+
+impl Backend {
+    /// Resolve a watch's requested backend, falling back to `default` when none was requested.
+    pub fn resolve(
+        kind: Option<BackendKind>,
+        poll_interval_ms: Option<u64>,
+        default: Backend,
+    ) -> Self {
+        match (kind, poll_interval_ms) {
+            (Some(BackendKind::Native), _) => Backend::Native,
+            (None, None) => default,
+            (_, Some(interval_ms)) => Backend::Poll { interval_ms },
+            (Some(BackendKind::Poll), None) => match default {
+                Backend::Poll { interval_ms } => Backend::Poll { interval_ms },
+                Backend::Native | Backend::Debounced { .. } => Backend::Poll {
+                    interval_ms: DEFAULT_POLL_INTERVAL_MS,
+                },
+            },
+        }
+    }
+
+    pub fn is_native(self) -> bool {
+        matches!(self, Backend::Native | Backend::Debounced { .. })
+    }
+}
+
+impl EventSender {
+    fn send(
+        &self,
+        event: (Backend, DebouncedEvent),
+    ) -> Result<(), SendError<(Backend, DebouncedEvent)>> {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.tx.send(event).inspect_err(|_| {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        })
+    }
+
+    /// How many events have been sent but not received yet.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+impl EventReceiver {
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<(Backend, DebouncedEvent), RecvTimeoutError> {
+        let event = self.rx.recv_timeout(timeout)?;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        Ok(event)
+    }
+
+    pub fn try_iter(&self) -> impl Iterator<Item = (Backend, DebouncedEvent)> + '_ {
+        self.rx.try_iter().inspect(move |_| {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        })
+    }
+}
+
+impl BackendWatcher {
+    fn new(backend: Backend, delay: Duration, tx: EventSender) -> notify::Result<Self> {
+        let (backend_tx, backend_rx) = mpsc::channel();
+        let watcher = match backend {
+            Backend::Native | Backend::Debounced { .. } => {
+                BackendWatcher::Native(Watcher::new(backend_tx, delay)?)
+            }
+            Backend::Poll { .. } => BackendWatcher::Poll(Watcher::new(backend_tx, delay)?),
+        };
+
+        // Forward events until the watcher is dropped or the notify thread goes away
+        thread::spawn(move || {
+            for event in backend_rx {
+                if tx.send((backend, event)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(watcher)
+    }
+
+    pub fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        match self {
+            BackendWatcher::Native(watcher) => watcher.watch(path, recursive_mode),
+            BackendWatcher::Poll(watcher) => watcher.watch(path, recursive_mode),
+        }
+    }
+
+    pub fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        match self {
+            BackendWatcher::Native(watcher) => watcher.unwatch(path),
+            BackendWatcher::Poll(watcher) => watcher.unwatch(path),
+        }
+    }
+}
+
+impl Watchers {
+    /// Create the set of watchers, eagerly starting the one for the `default` backend so that
+    /// problems with it surface immediately. The native backend debounces events for
+    /// `native_delay`, whereas the poll backend uses its interval.
+    pub fn new(default: Backend, native_delay: Duration, tx: EventSender) -> notify::Result<Self> {
+        let mut watchers = Self {
+            watchers: HashMap::new(),
+            default,
+            native_delay,
+            tx,
+        };
+        watchers.get(default)?;
+        Ok(watchers)
+    }
+
+    /// A fresh set of watchers that deliver their events on the same channel as these, with the
+    /// native backend debouncing for `native_delay`. Nothing is registered on them yet.
+    pub fn with_native_delay(&self, native_delay: Duration) -> notify::Result<Self> {
+        Self::new(self.default, native_delay, self.tx.clone())
+    }
+
+    pub fn default_backend(&self) -> Backend {
+        self.default
+    }
+
+    pub fn native_delay(&self) -> Duration {
+        self.native_delay
+    }
+
+    /// How long the backend's debouncer holds on to events.
+    pub fn delay(&self, backend: Backend) -> Duration {
+        match backend {
+            Backend::Native => self.native_delay,
+            Backend::Poll { interval_ms } => Duration::from_millis(interval_ms),
+            Backend::Debounced { debounce_ms } => Duration::from_millis(debounce_ms),
+        }
+    }
+
+    /// Get the watcher for `backend`, starting it if necessary.
+    pub fn get(&mut self, backend: Backend) -> notify::Result<&mut BackendWatcher> {
+        if !self.watchers.contains_key(&backend) {
+            let watcher = BackendWatcher::new(backend, self.delay(backend), self.tx.clone())?;
+            self.watchers.insert(backend, watcher);
+        }
+        Ok(self.watchers.get_mut(&backend).unwrap())
+    }
+
+    /// Stop the watcher for `backend` if it isn't the default one.
+    pub fn release(&mut self, backend: Backend) {
+        if backend != self.default {
+            self.watchers.remove(&backend);
+        }
+    }
+
+    /// How many debounced events have been delivered by the watchers without being received yet.
+    pub fn queued_events(&self) -> usize {
+        self.tx.queued()
+    }
+
+    /// The longest time any running watcher holds on to events before delivering them.
+    pub fn max_delay(&self) -> Duration {
+        self.watchers
+            .keys()
+            .map(|backend| self.delay(*backend))
+            .max()
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,215 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// How requests and messages are framed, as selected with `--protocol`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Json,
+    Binary,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Protocol::Json),
+            "binary" => Ok(Protocol::Binary),
+            _ => Err(format!("Expected \"json\" or \"binary\", got {:?}", s)),
+        }
+    }
+}
+
+/// A frame whose payload is a request or message encoded as JSON, for everything without a
+/// layout of its own.
+pub const JSON: u8 = 0;
+
+// Request opcodes
+pub const WATCH: u8 = 1;
+pub const UNWATCH: u8 = 2;
+pub const UNWATCH_ALL: u8 = 3;
+pub const FLUSH: u8 = 4;
+pub const PAUSE_ALL: u8 = 5;
+pub const RESUME_ALL: u8 = 6;
+
+// Message opcodes
+pub const OK_RESPONSE: u8 = 1;
+pub const ERROR_RESPONSE: u8 = 2;
+pub const WATCH_EVENTS: u8 = 3;
+
+// Event actions within a `WATCH_EVENTS` frame
+pub const MODIFIED: u8 = 1;
+pub const CREATED: u8 = 2;
+pub const DELETED: u8 = 3;
+pub const RENAMED: u8 = 4;
+pub const ERROR: u8 = 5;
+pub const QUEUE_OVERFLOW: u8 = 6;
+pub const RESCAN: u8 = 7;
+pub const ROOT_CREATED: u8 = 8;
+pub const IDLE: u8 = 9;
+
+/// The longest payload of a request frame that's read. The length comes from the client, so it's
+/// not to be trusted with the memory it would take.
+pub const MAX_PAYLOAD_LENGTH: u32 = 16 * 1024 * 1024;
+
+/// A request frame whose payload is longer than `MAX_PAYLOAD_LENGTH`, of which only the first 8
+/// bytes are kept. They hold the request id of every request with a layout of its own.
+pub struct TooLong {
+    pub length: u32,
+    pub head: Vec<u8>,
+}
+
+impl fmt::Display for TooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Payload of {} bytes exceeds the limit of {} bytes",
+            self.length, MAX_PAYLOAD_LENGTH
+        )
+    }
+}
+
+/// The payload of a request frame, unless it was too long to be read.
+pub type FramePayload = Result<Vec<u8>, TooLong>;
+
+/// Read the next frame, a one-byte opcode followed by the length of its payload as a
+/// little-endian `u32` and then the payload itself. Returns `None` once the input ends between
+/// frames. A payload that's too long is skipped rather than read, so that reading can carry on
+/// with the next frame.
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Option<(u8, FramePayload)>> {
+    let mut opcode = [0; 1];
+    if reader.read(&mut opcode)? == 0 {
+        return Ok(None);
+    }
+    let mut length = [0; 4];
+    reader.read_exact(&mut length)?;
+    let length = u32::from_le_bytes(length);
+    if length > MAX_PAYLOAD_LENGTH {
+        let mut head = vec![0; 8];
+        reader.read_exact(&mut head)?;
+        let skip = u64::from(length) - head.len() as u64;
+        if io::copy(&mut reader.by_ref().take(skip), &mut io::sink())? < skip {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Ok(Some((opcode[0], Err(TooLong { length, head }))));
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((opcode[0], Ok(payload))))
+}
+
+/// Reads the fields of a frame's payload in order.
+pub struct Payload<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        if self.bytes.len() < 8 {
+            return Err(format!("Expected 8 more bytes, got {}", self.bytes.len()));
+        }
+        let (field, rest) = self.bytes.split_at(8);
+        self.bytes = rest;
+        Ok(u64::from_le_bytes(field.try_into().unwrap()))
+    }
+
+    /// Whatever is left of the payload, such as a path at the end of it.
+    pub fn rest(self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Check that every field has been read.
+    pub fn finish(self) -> Result<(), String> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Unexpected {} bytes left in frame",
+                self.bytes.len()
+            ))
+        }
+    }
+}
+
+/// Builds a frame by appending fields to its payload.
+pub struct Frame {
+    bytes: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: u8) -> Self {
+        // The length is filled in by `finish`
+        Self {
+            bytes: vec![opcode, 0, 0, 0, 0],
+        }
+    }
+
+    pub fn u8(mut self, value: u8) -> Self {
+        self.bytes.push(value);
+        self
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Append `bytes` preceded by their length as a `u32`.
+    pub fn bytes(self, bytes: &[u8]) -> Self {
+        self.u32(bytes.len() as u32).rest(bytes)
+    }
+
+    /// Append `bytes` as they are, which only works for the last field.
+    pub fn rest(mut self, bytes: &[u8]) -> Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let length = (self.bytes.len() - 5) as u32;
+        self.bytes[1..5].copy_from_slice(&length.to_le_bytes());
+        self.bytes
+    }
+}
+
+/// The bytes of `path` on the wire: exactly those of the path on Unix, and UTF-8 elsewhere.
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+        Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+    }
+}
+
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
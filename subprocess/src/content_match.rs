@@ -0,0 +1,31 @@
+use crate::pattern::Pattern;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+/// At most this much of a file is read when looking for the end of its first line.
+pub const CONTENT_MATCH_MAX_BYTES: u64 = 4096;
+
+/// Check whether the first line of the file at `path` matches `pattern`, where a first line that
+/// exceeds `CONTENT_MATCH_MAX_BYTES` is cut off at that point. Returns `None` for anything that
+/// isn't a regular file, including paths that no longer exist.
+pub fn first_line_matches(path: &Path, pattern: &Pattern) -> Option<bool> {
+    if !fs::metadata(path).ok()?.is_file() {
+        return None;
+    }
+
+    let mut contents = Vec::new();
+    if File::open(path)
+        .and_then(|file| {
+            file.take(CONTENT_MATCH_MAX_BYTES)
+                .read_to_end(&mut contents)
+        })
+        .is_err()
+    {
+        return Some(false);
+    }
+
+    let first_line = contents.split(|byte| *byte == b'\n').next().unwrap_or(&[]);
+    let first_line = first_line.strip_suffix(b"\r").unwrap_or(first_line);
+    Some(pattern.is_match(&String::from_utf8_lossy(first_line)))
+}
@@ -0,0 +1,40 @@
+use std::str::FromStr;
+
+/// How messages are written, as selected with `--format`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Csv,
+}
+
+/// The line written once at startup with `--format csv`, naming the columns of the rows after it.
+pub const HEADER: &str = "action,watch_id,path,old_path";
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!("Expected \"json\" or \"csv\", got {:?}", s)),
+        }
+    }
+}
+
+/// Join `fields` into a line of CSV. As RFC 4180 describes, fields containing a comma, a quote,
+/// or a line break are quoted, with any quotes in them doubled, so a path with a newline in it
+/// spans several physical lines but is still a single field.
+pub fn row(fields: &[&str]) -> String {
+    fields
+        .iter()
+        .map(|field| {
+            if field.contains(&[',', '"', '\n', '\r'][..]) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
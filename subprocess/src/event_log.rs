@@ -0,0 +1,154 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Lines that can be waiting for the writer thread before we start dropping them.
+const QUEUE_CAPACITY: usize = 4096;
+
+/// How many rotated files (`<path>.1` through `<path>.N`) are kept around.
+const ROTATED_FILES: usize = 3;
+
+/// How long dropped lines can go unreported while no other lines arrive.
+const DROPPED_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long closing waits for the writer to catch up.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Tees emitted lines to a file on a background thread, rotating the file once it grows past
+/// `max_bytes`. Appending never blocks: if the writer falls behind, lines are dropped and a
+/// count of the dropped lines is written once the writer catches up, along with the next line or
+/// on its own when there's none for a while or the log is closed.
+pub struct EventLog {
+    tx: SyncSender<Request>,
+    dropped: Arc<AtomicUsize>,
+}
+
+enum Request {
+    Line(String),
+    // Stop writing, and report back once everything before has been written
+    Close(Sender<()>),
+}
+
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    file: File,
+    len: u64,
+}
+
+impl EventLog {
+    pub fn open(path: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        let writer = Writer::open(path, max_bytes)?;
+        let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        let dropped_2 = dropped.clone();
+        thread::spawn(move || writer.run(rx, &dropped_2));
+
+        Ok(Self { tx, dropped })
+    }
+
+    pub fn append(&self, line: &str) {
+        match self.tx.try_send(Request::Line(line.to_string())) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    /// Write what's still queued, including the count of dropped lines, and stop writing. Lines
+    /// appended afterwards are ignored.
+    pub fn close(&self) {
+        let (done_tx, done_rx) = mpsc::channel();
+        if self.tx.send(Request::Close(done_tx)).is_ok() {
+            let _ = done_rx.recv_timeout(CLOSE_TIMEOUT);
+        }
+    }
+}
+
+impl Writer {
+    fn open(path: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            file,
+            len,
+        })
+    }
+
+    fn run(mut self, rx: Receiver<Request>, dropped: &AtomicUsize) {
+        loop {
+            let request = rx.recv_timeout(DROPPED_REPORT_INTERVAL);
+            let mut result = self.write_dropped(dropped);
+            if let Ok(Request::Line(line)) = &request {
+                result = result.and_then(|_| self.write_line(line));
+            }
+
+            if let Err(error) = result {
+                eprintln!("Error writing event log {:?}: {:?}", self.path, error);
+                return;
+            }
+            match request {
+                Ok(Request::Close(done)) => {
+                    let _ = done.send(());
+                    return;
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+                _ => {}
+            }
+        }
+    }
+
+    // Write how many lines have been dropped since this was last written, if any were.
+    fn write_dropped(&mut self, dropped: &AtomicUsize) -> io::Result<()> {
+        match dropped.swap(0, Ordering::Relaxed) {
+            0 => Ok(()),
+            count => self.write_line(&format!(
+                "{{\"type\":\"eventLogDropped\",\"count\":{}}}",
+                count
+            )),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let line_len = line.len() as u64 + 1;
+        if self.len > 0 && self.len + line_len > self.max_bytes {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.len += line_len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        for i in (1..ROTATED_FILES).rev() {
+            let from = rotated_path(&self.path, i);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, i + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+
+        self.file = File::create(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
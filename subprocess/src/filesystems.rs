@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The number of directories whose file system is remembered per watch. Once this many are
+/// cached, the cache is cleared and starts over.
+const MAX_CACHED_DIRECTORIES: usize = 10_000;
+
+/// Remembers the file system that each directory is on, so that each event's path doesn't need
+/// a `statfs` call of its own.
+#[derive(Default)]
+pub struct FilesystemCache {
+    entries: HashMap<PathBuf, Option<u32>>,
+}
+
+impl FilesystemCache {
+    /// Whether `path` is on a file system with one of the names in `allowed`. A path counts as
+    /// being on the file system of the directory that contains it, which also works for paths
+    /// that were just deleted, and a path on a file system that can't be identified never is.
+    pub fn allows(&mut self, path: &Path, allowed: &[String]) -> bool {
+        let directory = directory_of(path);
+        let magic = match self.entries.get(directory) {
+            Some(magic) => *magic,
+            None => {
+                let magic = magic_of(directory);
+                if self.entries.len() >= MAX_CACHED_DIRECTORIES {
+                    self.entries.clear();
+                }
+                self.entries.insert(directory.to_path_buf(), magic);
+                magic
+            }
+        };
+        is_allowed(magic, allowed)
+    }
+}
+
+/// Like `FilesystemCache::allows`, for a one-off check that isn't worth caching.
+pub fn allows(path: &Path, allowed: &[String]) -> bool {
+    is_allowed(magic_of(directory_of(path)), allowed)
+}
+
+/// Whether file systems can be identified on this platform at all.
+pub fn supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+fn directory_of(path: &Path) -> &Path {
+    path.parent().unwrap_or(path)
+}
+
+fn is_allowed(magic: Option<u32>, allowed: &[String]) -> bool {
+    magic.is_some_and(|magic| {
+        names(magic)
+            .iter()
+            .any(|name| allowed.iter().any(|allowed| allowed == name))
+    })
+}
+
+// The magic number of the file system that `directory`, or its closest ancestor that still
+// exists, is on.
+fn magic_of(directory: &Path) -> Option<u32> {
+    directory.ancestors().find_map(statfs_magic)
+}
+
+#[cfg(target_os = "linux")]
+fn statfs_magic(path: &Path) -> Option<u32> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut buf = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `buf` is large enough for `statfs` to fill in, which
+    // it has done whenever it returns 0.
+    let buf = unsafe {
+        if libc::statfs(path.as_ptr(), buf.as_mut_ptr()) != 0 {
+            return None;
+        }
+        buf.assume_init()
+    };
+    // The magic numbers all fit in 32 bits, but `f_type`'s width varies between architectures
+    Some(buf.f_type as u32)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn statfs_magic(_path: &Path) -> Option<u32> {
+    None
+}
+
+// The names a file system with the magic number `magic` can be allowed by, as `/proc/mounts`
+// would list them. Some file systems share a magic number, so they can't be told apart.
+fn names(magic: u32) -> &'static [&'static str] {
+    match magic {
+        0xef53 => &["ext2", "ext3", "ext4"],
+        0x5846_5342 => &["xfs"],
+        0x9123_683e => &["btrfs"],
+        0x2fc1_2fc1 => &["zfs"],
+        0xf2f5_2010 => &["f2fs"],
+        0x5265_4973 => &["reiserfs"],
+        0x0102_1994 => &["tmpfs", "devtmpfs"],
+        0x8584_58f6 => &["ramfs"],
+        0x794c_7630 => &["overlay"],
+        0x7371_7368 => &["squashfs"],
+        0x9660 => &["iso9660"],
+        0x4d44 => &["vfat", "msdos"],
+        0x2011_bab0 => &["exfat"],
+        0x5346_544e => &["ntfs", "ntfs3"],
+        0x6969 => &["nfs", "nfs4"],
+        0xff53_4d42 => &["cifs"],
+        0xfe53_4d42 => &["smb3"],
+        0x00c3_6400 => &["ceph"],
+        0x6573_5546 => &["fuse"],
+        0x9fa0 => &["proc"],
+        0x6265_6572 => &["sysfs"],
+        _ => &[],
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of the files whose rules are picked up from a watched tree with `gitignoreFiles`.
+pub const GITIGNORE: &str = ".gitignore";
+
+/// How the patterns in a rules file read with `reloadRules` are matched, as chosen with
+/// `ignoreSyntax`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IgnoreSyntax {
+    /// File name patterns, as in `profiles`
+    Glob,
+    /// The rules of `.gitignore` files
+    Gitignore,
+}
+
+impl IgnoreSyntax {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "glob" => Ok(IgnoreSyntax::Glob),
+            "gitignore" => Ok(IgnoreSyntax::Gitignore),
+            _ => Err(format!(
+                "Unsupported ignoreSyntax {:?}, expected \"glob\" or \"gitignore\"",
+                name
+            )),
+        }
+    }
+}
+
+/// Which rules ignored a path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    /// The ones read with `reloadRules`
+    RulesFile,
+    /// Those of a `.gitignore` file in the tree
+    Tree,
+}
+
+/// A line of a `.gitignore` file.
+#[derive(Debug)]
+pub struct Rule {
+    // The pattern split into path components, starting with `**` unless it's anchored
+    segments: Vec<String>,
+    negated: bool,
+    dir_only: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = trim_trailing_spaces(line);
+        // `\#` and `\!` start a pattern with those characters literally, since backslashes
+        // escape the character after them when matching
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        // A slash anywhere but at the end anchors the pattern to the directory of its file,
+        // while other patterns match at any depth beneath it
+        let anchored = line.contains('/');
+        let mut segments: Vec<String> = line
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(String::from)
+            .collect();
+        if segments.is_empty() {
+            return None;
+        }
+        if !anchored {
+            segments.insert(0, "**".to_string());
+        }
+        Some(Self {
+            segments,
+            negated,
+            dir_only,
+        })
+    }
+
+    // Whether the rule matches the path with `components` under the directory of its file.
+    fn matches(&self, components: &[&str], is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && matches_segments(&self.segments, components)
+    }
+}
+
+/// Read the rules in the gitignore file at `path`.
+pub fn read_rules(path: &Path) -> io::Result<Vec<Rule>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter_map(Rule::parse)
+        .collect())
+}
+
+/// The gitignore rules that apply to a watch's tree: those of the `.gitignore` files in it, with
+/// `gitignoreFiles`, and those read with `reloadRules` with `ignoreSyntax` set to `"gitignore"`,
+/// which apply from the root and give way to the files in the tree, like `.git/info/exclude`.
+pub struct Gitignore {
+    root: PathBuf,
+    // The rules of each `.gitignore` file in the tree, by the directory it's in
+    files: BTreeMap<PathBuf, Vec<Rule>>,
+    rules: Vec<Rule>,
+}
+
+impl Gitignore {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            files: BTreeMap::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        self.rules = rules;
+    }
+
+    /// Read the `.gitignore` files in the tree again, forgetting the ones read before. Like git,
+    /// this doesn't look inside ignored directories, and doesn't follow symlinks.
+    pub fn reload_files(&mut self) {
+        self.files.clear();
+        let root = self.root.clone();
+        self.load_files(&root);
+    }
+
+    fn load_files(&mut self, dir: &Path) {
+        if let Ok(rules) = read_rules(&dir.join(GITIGNORE)) {
+            self.files.insert(dir.to_path_buf(), rules);
+        }
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            let path = entry.path();
+            if is_dir && self.ignored_by(&path).is_none() {
+                self.load_files(&path);
+            }
+        }
+    }
+
+    /// Which rules ignore `path`, if any. As with git, once a directory is ignored, nothing
+    /// under it can be included again. Patterns ending in `/` only match directories, which a
+    /// path that no longer exists isn't taken to be.
+    pub fn ignored_by(&self, path: &Path) -> Option<Source> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let names: Vec<String> = relative
+            .iter()
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+        let components: Vec<&str> = names.iter().map(String::as_str).collect();
+
+        for len in 1..components.len() {
+            if let Some((true, source)) = self.verdict(&components[..len], true) {
+                return Some(source);
+            }
+        }
+        let is_dir = fs::metadata(path).is_ok_and(|metadata| metadata.is_dir());
+        match self.verdict(&components, is_dir) {
+            Some((true, source)) => Some(source),
+            _ => None,
+        }
+    }
+
+    // Whether the path with `components` under the root is ignored according to the last rule
+    // matching it in the closest `.gitignore` file with one, or else in the rules file, along
+    // with where that rule came from. `None` if no rule matches it at all.
+    fn verdict(&self, components: &[&str], is_dir: bool) -> Option<(bool, Source)> {
+        for depth in (0..components.len()).rev() {
+            let mut dir = self.root.clone();
+            dir.extend(&components[..depth]);
+            if let Some(ignored) = self
+                .files
+                .get(&dir)
+                .and_then(|rules| last_match(rules, &components[depth..], is_dir))
+            {
+                return Some((ignored, Source::Tree));
+            }
+        }
+        last_match(&self.rules, components, is_dir).map(|ignored| (ignored, Source::RulesFile))
+    }
+}
+
+// Whether the last of `rules` matching `components` ignores them, if any does.
+fn last_match(rules: &[Rule], components: &[&str], is_dir: bool) -> Option<bool> {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| rule.matches(components, is_dir))
+        .map(|rule| !rule.negated)
+}
+
+// Trailing spaces are dropped unless they're escaped with a backslash.
+fn trim_trailing_spaces(line: &str) -> &str {
+    let trimmed = line.trim_end_matches(' ');
+    if trimmed.ends_with('\\') && trimmed.len() < line.len() {
+        &line[..trimmed.len() + 1]
+    } else {
+        trimmed
+    }
+}
+
+// Whether the path `components` match the pattern `segments`, in which `**` matches any number
+// of components, except at the end, where it matches everything under a directory but not the
+// directory itself.
+fn matches_segments(segments: &[String], components: &[&str]) -> bool {
+    match segments.split_first() {
+        None => components.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            if rest.is_empty() {
+                return !components.is_empty();
+            }
+            (0..=components.len()).any(|skip| matches_segments(rest, &components[skip..]))
+        }
+        Some((first, rest)) => match components.split_first() {
+            Some((name, remaining)) => {
+                matches_name(first, name) && matches_segments(rest, remaining)
+            }
+            None => false,
+        },
+    }
+}
+
+// Whether the file name `name` matches `pattern`, in which `*` matches any run of characters,
+// `?` any single one, `[...]` one in the class, negated by a leading `!` or `^`, and a
+// backslash escapes the next character.
+fn matches_name(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches_chars(&pattern, &name)
+}
+
+fn matches_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| matches_chars(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && matches_chars(&pattern[1..], &name[1..]),
+        Some('[') => match (parse_class(&pattern[1..]), name.first()) {
+            (Some((class, len)), Some(&c)) => {
+                class.contains(c) && matches_chars(&pattern[1 + len..], &name[1..])
+            }
+            (Some(_), None) => false,
+            // An unclosed bracket is taken literally
+            (None, _) => name.first() == Some(&'[') && matches_chars(&pattern[1..], &name[1..]),
+        },
+        Some('\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1]) && matches_chars(&pattern[2..], &name[1..])
+        }
+        Some(c) => name.first() == Some(c) && matches_chars(&pattern[1..], &name[1..]),
+    }
+}
+
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn contains(&self, c: char) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(start, end)| start <= c && c <= end)
+            != self.negated
+    }
+}
+
+// Parse the class after a `[`, returning it along with how many characters it took up, including
+// the closing `]`. A `]` right after the opening bracket belongs to the class.
+fn parse_class(pattern: &[char]) -> Option<(Class, usize)> {
+    let negated = matches!(pattern.first(), Some('!') | Some('^'));
+    let start = usize::from(negated);
+    let mut i = start;
+    let mut ranges = Vec::new();
+    loop {
+        let c = *pattern.get(i)?;
+        if c == ']' && i > start {
+            return Some((Class { ranges, negated }, i + 1));
+        }
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(&end)) if end != ']' => {
+                ranges.push((c, end));
+                i += 3;
+            }
+            _ => {
+                ranges.push((c, c));
+                i += 1;
+            }
+        }
+    }
+}
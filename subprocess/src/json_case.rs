@@ -0,0 +1,88 @@
+use serde_json::{Map, Value};
+use std::str::FromStr;
+
+/// The naming convention for the field names of the JSON protocol, as selected with
+/// `--json-case`. Field values, like the `type` of a message, are never renamed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JsonCase {
+    Camel,
+    Snake,
+}
+
+impl FromStr for JsonCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "camel" => Ok(JsonCase::Camel),
+            "snake" => Ok(JsonCase::Snake),
+            _ => Err(format!("Expected \"camel\" or \"snake\", got {:?}", s)),
+        }
+    }
+}
+
+impl JsonCase {
+    /// Rename the fields of a message we're about to write, which are camelCase to begin with.
+    pub fn outgoing(self, value: Value) -> Value {
+        match self {
+            JsonCase::Camel => value,
+            JsonCase::Snake => rename_fields(value, &camel_to_snake),
+        }
+    }
+
+    /// Rename the fields of a message we've read into the camelCase that we expect. Fields that
+    /// are camelCase already are left alone, so clients can migrate one field at a time.
+    pub fn incoming(self, value: Value) -> Value {
+        match self {
+            JsonCase::Camel => value,
+            JsonCase::Snake => rename_fields(value, &snake_to_camel),
+        }
+    }
+}
+
+fn rename_fields(value: Value, rename: &impl Fn(&str) -> String) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (rename(&name), rename_fields(value, rename)))
+                .collect::<Map<_, _>>(),
+        ),
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| rename_fields(value, rename))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+fn camel_to_snake(name: &str) -> String {
+    let mut renamed = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            renamed.push('_');
+            renamed.push(c.to_ascii_lowercase());
+        } else {
+            renamed.push(c);
+        }
+    }
+    renamed
+}
+
+fn snake_to_camel(name: &str) -> String {
+    let mut renamed = String::with_capacity(name.len());
+    let mut capitalize = false;
+    for c in name.chars() {
+        if c == '_' {
+            capitalize = true;
+        } else if capitalize {
+            renamed.push(c.to_ascii_uppercase());
+            capitalize = false;
+        } else {
+            renamed.push(c);
+        }
+    }
+    renamed
+}
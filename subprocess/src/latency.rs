@@ -0,0 +1,64 @@
+use crate::RequestId;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// The number of most recent requests whose latencies are kept. Older ones are forgotten, so the
+/// summary follows how the supervisor is keeping up now rather than since it started.
+const WINDOW: usize = 1000;
+
+/// How long the supervisor took to answer each of the last requests, with `--measure-latency`.
+#[derive(Default)]
+pub struct Latencies {
+    window: VecDeque<Duration>,
+    // When each request that hasn't been answered yet started arriving
+    pending: HashMap<RequestId, Instant>,
+}
+
+/// The percentiles of the latencies in the window, in milliseconds.
+pub struct LatencySummary {
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub max_ms: f64,
+    pub count: usize,
+}
+
+impl Latencies {
+    /// Start measuring the latency of a request that started arriving at `read_at`.
+    pub fn start(&mut self, request_id: RequestId, read_at: Instant) {
+        self.pending.insert(request_id, read_at);
+    }
+
+    /// Record the latency of a request whose response has just been written. Responses after the
+    /// first, like those for watches removed later on, are ignored.
+    pub fn finish(&mut self, request_id: RequestId) {
+        if let Some(read_at) = self.pending.remove(&request_id) {
+            self.record(read_at.elapsed());
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        if self.window.len() == WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back(latency);
+    }
+
+    /// Summarize the window, with every figure 0 while it's empty.
+    pub fn summary(&self) -> LatencySummary {
+        let mut sorted: Vec<Duration> = self.window.iter().cloned().collect();
+        sorted.sort();
+        // The nearest-rank percentile: the smallest latency that at least `percent` of the
+        // requests took no longer than
+        let percentile = |percent: usize| {
+            let rank = (sorted.len() * percent).div_ceil(100);
+            rank.checked_sub(1)
+                .map_or(0.0, |index| sorted[index].as_secs_f64() * 1000.0)
+        };
+        LatencySummary {
+            p50_ms: percentile(50),
+            p95_ms: percentile(95),
+            max_ms: percentile(100),
+            count: sorted.len(),
+        }
+    }
+}
@@ -1,32 +1,559 @@
-use notify::{DebouncedEvent, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+mod attributes;
+mod backend;
+mod binary;
+mod content_match;
+mod csv;
+mod event_id;
+mod event_log;
+mod filesystems;
+mod gitignore;
+mod hash;
+mod json_case;
+mod latency;
+mod path_map;
+mod pattern;
+mod profiles;
+mod raw_paths;
+mod sink;
+mod size_delta;
+mod snapshot;
+mod summary;
+mod symlinks;
+mod system_info;
+mod temp_files;
+mod template;
+mod tree_hash;
+mod watchdog;
+
+use attributes::{AttributeCache, ModifiedAttribute};
+use backend::{Backend, BackendKind, EventReceiver, Watchers};
+use binary::Protocol;
+use csv::OutputFormat;
+use event_id::EventIds;
+use event_log::EventLog;
+use filesystems::FilesystemCache;
+use gitignore::{Gitignore, IgnoreSyntax, GITIGNORE};
+use hash::{HashAlgorithm, HashSpec, DEFAULT_HASH_MAX_BYTES};
+use json_case::JsonCase;
+use latency::Latencies;
+use notify::{DebouncedEvent, RecursiveMode};
+use path_map::{PathMap, PathMapping};
+use pattern::Pattern;
+use profiles::FilterProfile;
+use raw_paths::RawPaths;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use sink::{EventSink, FileSink, SharedSink, StdoutSink};
+use size_delta::{SizeCache, SizeChange};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs;
 use std::io::{self, BufRead};
 use std::path::{Path, PathBuf};
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use structopt::StructOpt;
+use summary::Summary;
+use symlinks::{SymlinkCache, SymlinkFilter};
+use system_info::WatchCapacity;
+use temp_files::is_temp_file;
+use template::EventTemplate;
+use tree_hash::TreeHash;
+use watchdog::Watchdog;
 
 type RequestId = usize;
 type WatchId = usize;
 
+/// How often the notify thread wakes up when no events are arriving.
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long the notify thread can go without ticking before it's reported as unhealthy.
+const STALL_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// How long the native backend debounces events for unless configured otherwise.
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Extra time allowed for the debouncer's timer thread to deliver events that became due.
+const FLUSH_MARGIN: Duration = Duration::from_millis(20);
+
+/// How long a watch with `smart_renames` holds on to a deletion, waiting for a creation that it
+/// can be paired with.
+const SMART_RENAME_WINDOW: Duration = Duration::from_millis(500);
+
+/// Bumped whenever a change to the protocol could break existing clients, as opposed to adding
+/// requests, options, or fields that they can ignore.
+const PROTOCOL_VERSION: u32 = 1;
+
+static EVENT_LOG: OnceLock<EventLog> = OnceLock::new();
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "subprocess")]
 struct Opt {
     /// Enable polling mode with the specified interval in milliseconds
     #[structopt(long = "poll-interval")]
     poll_interval: Option<u64>,
+
+    /// Debounce native events for the specified number of milliseconds
+    #[structopt(long = "debounce-ms", default_value = "100")]
+    debounce_ms: u64,
+
+    /// Emit all the events from each debouncer delivery as a single batch message
+    #[structopt(long = "batch-emit")]
+    batch_emit: bool,
+
+    /// Register the watches listed in the specified JSON file on startup
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Reject watch requests once the specified number of watches are active
+    #[structopt(long = "max-watches")]
+    max_watches: Option<usize>,
+
+    /// Reject watch requests for ids that a watch had before, even once it has been removed
+    #[structopt(long = "single-use-ids")]
+    single_use_ids: bool,
+
+    /// Measure how long each request takes to handle, to be reported by `latencies`
+    #[structopt(long = "measure-latency")]
+    measure_latency: bool,
+
+    /// Also append every emitted event as a JSON line to the specified file
+    #[structopt(long = "event-log", parse(from_os_str))]
+    event_log: Option<PathBuf>,
+
+    /// Rotate the event log once it exceeds the specified size in bytes
+    #[structopt(long = "event-log-max-bytes", default_value = "10485760")]
+    event_log_max_bytes: u64,
+
+    /// Also append everything written to stdout, responses included, to the specified file
+    #[structopt(long = "tee", parse(from_os_str))]
+    tee: Option<PathBuf>,
+
+    /// Translate paths under <host-prefix> in requests to <container-prefix>, and back in events
+    #[structopt(long = "path-map", number_of_values = 1)]
+    path_map: Vec<PathMapping>,
+
+    /// Name the fields of requests and responses in "camel" or "snake" case
+    #[structopt(long = "json-case", default_value = "camel")]
+    json_case: JsonCase,
+
+    /// Write a keepalive message whenever nothing else has been written for the specified number
+    /// of milliseconds
+    #[structopt(long = "keepalive-ms")]
+    keepalive_ms: Option<u64>,
+
+    /// On reaching the end of stdin, keep emitting the events that are still pending for up to
+    /// the specified number of milliseconds before exiting
+    #[structopt(long = "drain-ms", default_value = "0")]
+    drain_ms: u64,
+
+    /// Number every emitted event with a `globalSeq` that increases across all watches
+    #[structopt(long = "global-seq")]
+    global_seq: bool,
+
+    /// Give every emitted event a random UUID as its `eventId`
+    #[structopt(long = "event-ids")]
+    event_ids: bool,
+
+    /// Give up on resolving the symlinks in a request's path after the specified number of
+    /// milliseconds, for file systems that can take arbitrarily long to respond
+    #[structopt(long = "canonicalize-timeout-ms")]
+    canonicalize_timeout_ms: Option<u64>,
+
+    /// Write messages as "json", or write only events, as rows of "csv"
+    #[structopt(long = "format", default_value = "json")]
+    format: OutputFormat,
+
+    /// Write each event as this template filled in with its `{action}`, `{watchId}`, `{path}`,
+    /// `{oldPath}`, and `{timestamp}`, instead of as JSON
+    #[structopt(long = "event-template")]
+    event_template: Option<EventTemplate>,
+
+    /// Frame requests and messages as lines of "json", or as "binary" frames
+    #[structopt(long = "protocol", default_value = "json")]
+    protocol: Protocol,
+}
+
+struct Supervisor {
+    // Shared with the notify thread, which narrows watches once their roots appear. When both
+    // this and `watches` are locked, `watches` is locked first.
+    watchers: Arc<Mutex<Watchers>>,
+    watches: Arc<Mutex<Vec<Watch>>>,
+    flushes: Arc<Mutex<Vec<PendingFlush>>>,
+    last_tick: Arc<Mutex<Instant>>,
+    notify_thread: JoinHandle<()>,
+    output: Sender<Outgoing>,
+    output_thread: JoinHandle<()>,
+    sink: Arc<MessageSink>,
+    // Set by `pauseAll`, in which case the notify thread drops events rather than emitting them
+    paused: Arc<AtomicBool>,
+    // Set once the supervisor has drained its events for shutting down, which makes the notify
+    // thread exit
+    stopping: Arc<AtomicBool>,
+    // Set by `subscribeLifecycle`, after which changes to the supervisor's own state are reported
+    lifecycle: Arc<AtomicBool>,
+    max_watches: Option<usize>,
+    // Every id a watch has had, with `--single-use-ids`
+    used_ids: Option<HashSet<WatchId>>,
+    // How long resolving a path may take, with `--canonicalize-timeout-ms`
+    canonicalize_timeout: Option<Duration>,
+    // What the monotonic times of `time` responses count from
+    started_at: Instant,
+    batch_emit: bool,
+    drain: Duration,
+}
+
+/// Configures and creates a [`Supervisor`]. Everything is optional: by default the native backend
+/// is used with a 100ms debounce, the number of watches is unlimited, and messages are written to
+/// stdout as lines of JSON with camelCase field names.
+struct SupervisorBuilder {
+    backend: Backend,
+    debounce: Duration,
+    max_watches: Option<usize>,
+    single_use_ids: bool,
+    measure_latency: bool,
+    batch_emit: bool,
+    keepalive: Option<Duration>,
+    drain: Duration,
+    canonicalize_timeout: Option<Duration>,
+    protocol: Protocol,
+    format: OutputFormat,
+    event_template: Option<EventTemplate>,
+    path_map: Option<PathMap>,
+    json_case: JsonCase,
+    global_seq: bool,
+    event_ids: bool,
+    sinks: Vec<Box<dyn EventSink>>,
 }
 
-struct Supervisor<W> {
-    watcher: W,
+/// The sinks that messages are written to, along with how they're written. Shared by every thread
+/// that writes messages.
+struct MessageSink {
+    sinks: SharedSink,
+    protocol: Protocol,
+    format: OutputFormat,
+    event_template: Option<EventTemplate>,
+    // Also used to translate the paths of requests
+    path_map: Option<PathMap>,
+    // Also the naming convention of requests
+    json_case: JsonCase,
+    /// The `globalSeq` of the next event to be written, with `--global-seq`
+    global_seq: Option<AtomicU64>,
+    /// Where the `eventId` of each event comes from, with `--event-ids`
+    event_ids: Option<EventIds>,
+    // Only measured with `--measure-latency`, up to when the response to each request is written
+    latencies: Option<Mutex<Latencies>>,
+}
+
+// The parts of a `Supervisor` that its notify thread works with.
+#[derive(Clone)]
+struct NotifyThreadState {
+    watchers: Arc<Mutex<Watchers>>,
     watches: Arc<Mutex<Vec<Watch>>>,
+    flushes: Arc<Mutex<Vec<PendingFlush>>>,
+    last_tick: Arc<Mutex<Instant>>,
+    paused: Arc<AtomicBool>,
+    stopping: Arc<AtomicBool>,
+    lifecycle: Arc<AtomicBool>,
+    output: Sender<Outgoing>,
+    batch_emit: bool,
+}
+
+struct PendingFlush {
+    // None when the supervisor is draining its events on reaching the end of its input, in which
+    // case there's nobody to acknowledge the flush to
+    request_id: Option<RequestId>,
+    deadline: Instant,
+}
+
+// The events held back for a watch during a `bufferedPause`, in the order they'd have been
+// emitted.
+struct PauseBuffer {
+    max_events: usize,
+    events: Vec<Event>,
+    // Set once more than `max_events` events arrived, in which case nothing more is kept and the
+    // watch is told to rescan on resuming
+    overflowed: bool,
+}
+
+impl PauseBuffer {
+    fn new(max_events: usize) -> Self {
+        Self {
+            max_events,
+            events: Vec::new(),
+            overflowed: false,
+        }
+    }
+
+    // Keep the events of a batch that would have been emitted, unless there isn't room for all
+    // of them
+    fn store(&mut self, events: Vec<Event>) {
+        if self.overflowed {
+            return;
+        }
+        if self.events.len() + events.len() > self.max_events {
+            self.overflowed = true;
+        } else {
+            self.events.extend(events);
+        }
+    }
 }
 
 struct Watch {
-    ids: Vec<usize>,
+    id: WatchId,
+    // The request that created the watch, which is answered again once the watch reaches its
+    // `limit`
+    request_id: RequestId,
+    // For a watch of individual files, the closest ancestor that they share
+    root: PathBuf,
+    // The files that a `watchFiles` request asked for, which are the only paths it reports
+    files: Option<HashSet<PathBuf>>,
+    // While the root doesn't exist, its closest ancestor that does, which is watched instead
+    ancestor: Option<PathBuf>,
+    // Set once the watch has reported the creation of its root, after which the notify thread
+    // stops watching the ancestor
+    root_created: bool,
+    backend: Backend,
+    options: WatchOptions,
+    // The last known (device, inode) pair of each path, so they can still be reported once the
+    // path has been deleted. Only maintained with `include_inode`.
+    file_ids: HashMap<PathBuf, (u64, u64)>,
+    // The last known attributes of each path. Only maintained with `modified_on`.
+    attributes: AttributeCache,
+    // The last known contents of each path, as hashes. Only maintained with
+    // `suppress_nop_changes`.
+    contents: AttributeCache,
+    // The last known size of each file. Only maintained with `track_size_delta`.
+    sizes: SizeCache,
+    hash: Option<HashSpec>,
+    content_match: Option<Arc<Pattern>>,
+    // The `profiles` option with their prefixes resolved against the root
+    profiles: Vec<FilterProfile>,
+    actions: BTreeSet<Action>,
+    // Modifications waiting for their path to be quiet for `trailing_quiet_ms`, along with when
+    // the path was last modified. Only maintained with `trailing_quiet_ms`.
+    held_modifications: HashMap<PathBuf, (Instant, Event)>,
+    // Deletions waiting for up to `SMART_RENAME_WINDOW` for a creation to pair them with, in the
+    // order they happened. Only maintained with `smart_renames`.
+    held_deletions: Vec<(Instant, Event)>,
+    // How many events the watch has emitted, which counts towards `limit`
+    emitted_events: usize,
+    // Only maintained with `tree_hash`
+    tree_hash: Option<TreeHash>,
+    // The file system of each directory that events happened in. Only maintained with
+    // `filesystems`.
+    filesystem_cache: FilesystemCache,
+    symlink_filter: SymlinkFilter,
+    // The paths of the symlinks that events happened for. Only maintained with `symlinks`.
+    symlink_cache: SymlinkCache,
+    ignore_syntax: IgnoreSyntax,
+    // The patterns last read with `reloadRules`, resolved against the root. Only maintained
+    // with `ignore_syntax` set to `glob`.
+    rules: Option<FilterProfile>,
+    // Only maintained with `gitignore_files`, or once `reloadRules` read patterns with
+    // `ignore_syntax` set to `gitignore`
+    gitignore: Option<Gitignore>,
+    // The file `rules` are read again from whenever it changes, if it's watched
+    rules_file: Option<PathBuf>,
+    // The last events the watch emitted, oldest first. Only maintained with `history`.
+    history: VecDeque<Event>,
+    // When the watch last emitted an event, until it has been reported as idle. Only maintained
+    // with `idle_after_ms`.
+    last_emitted_at: Option<Instant>,
+    // The id of the next batch surrounded by markers, with `batch_markers`
+    next_batch_id: u64,
+    // The root's contents as of the last listing, which the next one is compared with. Only
+    // maintained with `listing_delta`.
+    last_listing: Vec<PathBuf>,
+    // The distinct paths the watch has emitted events for, and whether it has reported going past
+    // `max_entries` since the limit was last set. Only maintained with `max_entries`.
+    seen_entries: HashSet<PathBuf>,
+    entries_exceeded: bool,
+    rename_mode: RenameMode,
+    // The id shared by the events of the next rename emitted in both forms, with `emit_rename_as`
+    next_rename_id: u64,
+    // Only maintained with `watchdog_ms`
+    watchdog: Option<Watchdog>,
+    // Only maintained with `summary_interval_ms`
+    summary: Option<Summary>,
+    // The watch's `flush_on` file resolved against its root, and the events held until it fires
+    flush_trigger: Option<PathBuf>,
+    held_for_trigger: Vec<Event>,
+    // The events buffered for `coalesce_ms`, in order, and when the first of them arrived. Only
+    // maintained with `coalesce_ms`.
+    coalescing: Vec<Event>,
+    coalescing_since: Option<Instant>,
+    // When the watch's `duration_ms` runs out
+    expires_at: Option<Instant>,
+    // Set by `bufferedPause`, in which case the watch keeps its events here until `resume`
+    pause_buffer: Option<PauseBuffer>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct WatchSpec {
+    watch_id: WatchId,
     root: PathBuf,
+    /// Report entries that changed after this many milliseconds since the Unix epoch
+    #[serde(default, skip_serializing)]
+    since_ms: Option<u64>,
+    #[serde(flatten)]
+    options: WatchOptions,
+}
+
+#[derive(Deserialize, Debug)]
+struct Config {
+    watches: Vec<WatchSpec>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct WatchOptions {
+    /// Replace the platform's path separator with `/` in emitted paths
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forward_slashes: Option<bool>,
+    /// Include the device and inode numbers of files in events on Unix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include_inode: Option<bool>,
+    /// List the directories between the root and the path of each event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include_ancestors: Option<bool>,
+    /// Mark files with more than one hard link in events on Unix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detect_hardlinks: Option<bool>,
+    /// Only emit modifications that changed one of these attributes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    modified_on: Vec<ModifiedAttribute>,
+    /// Watch with this backend instead of the one selected on the command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<BackendKind>,
+    /// The poll interval to use with the poll backend
+    #[serde(skip_serializing_if = "Option::is_none")]
+    poll_interval_ms: Option<u64>,
+    /// Drop events for editor swap files and other transient files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ignore_temp_files: Option<bool>,
+    /// Emit events for the root itself, which is the default, rather than only for its contents
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include_root_events: Option<bool>,
+    /// Include a hash of the contents of created and modified files, computed with this algorithm
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    /// Don't hash files larger than this
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash_max_bytes: Option<u64>,
+    /// Only emit events with these actions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actions: Option<Vec<Action>>,
+    /// Report whether paths are valid UTF-8, and their exact bytes if they aren't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    binary_paths: Option<bool>,
+    /// Only emit a modification once its path hasn't been modified again for this long
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trailing_quiet_ms: Option<u64>,
+    /// Hold back all events until this file, relative to the root unless absolute, is created or
+    /// modified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flush_on: Option<PathBuf>,
+    /// Buffer events for this long after the first of them, merging repeated events of the same
+    /// kind for a path into one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coalesce_ms: Option<u64>,
+    /// Report a deletion shortly followed by the creation of a file with the same name as a rename
+    #[serde(skip_serializing_if = "Option::is_none")]
+    smart_renames: Option<bool>,
+    /// Emit renames as `paired` renames, the default, `split` into a deletion and a creation, or
+    /// `both`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    emit_rename_as: Option<String>,
+    /// Only emit creations and modifications of files whose first line matches this pattern
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_match: Option<String>,
+    /// Only emit events for paths under one of these profiles' prefixes that its rules allow
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    profiles: Vec<FilterProfile>,
+    /// Report the creation of a root that didn't exist yet as `rootCreated` instead of `created`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root_created_event: Option<bool>,
+    /// Only watch the root's direct children, and report the root's contents as a whole instead
+    /// of the changes to them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listing_mode: Option<bool>,
+    /// In listing mode, only report the entries added to and removed from the root since the
+    /// last listing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listing_delta: Option<bool>,
+    /// Watch the whole tree under the root, which is the default, or only the root's direct
+    /// children
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recursive: Option<bool>,
+    /// Remove the watch once it has emitted this many events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+    /// Stop emitting events for new paths once events for this many distinct paths were emitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_entries: Option<usize>,
+    /// Remove the watch once this many milliseconds have passed since it was created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    /// Maintain a hash of the tree that can be requested with `treeHash`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tree_hash: Option<bool>,
+    /// Only emit creations and modifications of files at least this many bytes long
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_size: Option<u64>,
+    /// Only emit creations and modifications of files at most this many bytes long
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_size: Option<u64>,
+    /// Only emit events for paths on file systems of these types, such as `ext4`, on Linux
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    filesystems: Vec<String>,
+    /// Emit events for symlinks along with everything else with `include`, the default, leave
+    /// them out with `exclude`, or only emit events for them with `only`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    symlinks: Option<String>,
+    /// Match the patterns read with `reloadRules` as file names with `glob`, the default, or
+    /// like a `.gitignore` file with `gitignore`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ignore_syntax: Option<String>,
+    /// Drop events for the paths ignored by the `.gitignore` files in the tree
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gitignore_files: Option<bool>,
+    /// Remember this many of the last events emitted, to be replayed with `history`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history: Option<usize>,
+    /// Emit an `idle` event once nothing else has been emitted for this long after an event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idle_after_ms: Option<u64>,
+    /// Count creations, modifications, deletions, and renames instead of emitting them, and emit
+    /// a `summary` event with the counts once per interval of this length that had any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary_interval_ms: Option<u64>,
+    /// Replace the first of these prefixes of emitted paths with the second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rewrite: Option<(String, String)>,
+    /// Surround the events emitted together with `batchBegin` and `batchEnd` events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    batch_markers: Option<bool>,
+    /// Only report files as they appear and disappear, leaving out their modifications
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_only: Option<bool>,
+    /// Drop modifications that left the contents of files as they were
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suppress_nop_changes: Option<bool>,
+    /// Include the sizes of modified files before and after, and whether they grew or shrank
+    #[serde(skip_serializing_if = "Option::is_none")]
+    track_size_delta: Option<bool>,
+    /// Report an error if the native backend delivers nothing for this long while the root changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    watchdog_ms: Option<u64>,
+    /// Move the watch to the poll backend once the watchdog finds its native backend silent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    watchdog_fallback: Option<bool>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -35,9 +562,19 @@ struct Watch {
 enum Incoming {
     #[serde(rename_all = "camelCase")]
     Watch {
+        request_id: RequestId,
+        #[serde(flatten)]
+        spec: WatchSpec,
+        /// Default to the filters of this watch for the options that aren't given
+        inherit_from: Option<WatchId>,
+    },
+    #[serde(rename_all = "camelCase")]
+    WatchFiles {
         request_id: RequestId,
         watch_id: WatchId,
-        root: PathBuf,
+        files: Vec<PathBuf>,
+        #[serde(flatten)]
+        options: WatchOptions,
     },
     #[serde(rename_all = "camelCase")]
     Unwatch {
@@ -46,6 +583,157 @@ enum Incoming {
     },
     #[serde(rename_all = "camelCase")]
     UnwatchAll { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    Flush { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    Health { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    PauseAll { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    ResumeAll { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    BufferedPause {
+        request_id: RequestId,
+        watch_id: WatchId,
+        max_buffer: usize,
+    },
+    #[serde(rename_all = "camelCase")]
+    Resume {
+        request_id: RequestId,
+        watch_id: WatchId,
+    },
+    #[serde(rename_all = "camelCase")]
+    DebugQueue { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    Latencies { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    Time { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    Version { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    SystemInfo { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    ExportConfig { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    SubscribeLifecycle { request_id: RequestId },
+    #[serde(rename_all = "camelCase")]
+    SetDebounce {
+        request_id: RequestId,
+        debounce_ms: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetWatchDebounce {
+        request_id: RequestId,
+        watch_id: WatchId,
+        /// Go back to the watcher the watch's options ask for when omitted
+        debounce_ms: Option<u64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    RenameWatch {
+        request_id: RequestId,
+        watch_id: WatchId,
+        new_watch_id: WatchId,
+    },
+    #[serde(rename_all = "camelCase")]
+    TreeHash {
+        request_id: RequestId,
+        watch_id: WatchId,
+    },
+    #[serde(rename_all = "camelCase")]
+    History {
+        request_id: RequestId,
+        watch_id: WatchId,
+    },
+    #[serde(rename_all = "camelCase")]
+    Snapshot {
+        request_id: RequestId,
+        watch_id: WatchId,
+    },
+    #[serde(rename_all = "camelCase")]
+    Overlaps {
+        request_id: RequestId,
+        watch_id: WatchId,
+    },
+    #[serde(rename_all = "camelCase")]
+    EffectiveActions {
+        request_id: RequestId,
+        watch_id: WatchId,
+    },
+    #[serde(rename_all = "camelCase")]
+    Shutdown {
+        request_id: RequestId,
+        /// Keep emitting pending events for up to this long before exiting, instead of the
+        /// `--drain-ms` given on the command line
+        drain_ms: Option<u64>,
+    },
+    #[serde(rename_all = "camelCase")]
+    IsWatched {
+        request_id: RequestId,
+        path: PathBuf,
+    },
+    #[serde(rename_all = "camelCase")]
+    Canonicalize {
+        request_id: RequestId,
+        path: PathBuf,
+    },
+    #[serde(rename_all = "camelCase")]
+    Ls {
+        request_id: RequestId,
+        path: PathBuf,
+        /// Only list entries whose file name matches one of these patterns
+        include: Option<Vec<String>>,
+        /// Leave out entries whose file name matches one of these patterns
+        #[serde(default)]
+        ignore: Vec<String>,
+        /// Only list entries with one of these extensions
+        extensions: Option<Vec<String>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    WouldEmit {
+        request_id: RequestId,
+        watch_id: WatchId,
+        path: PathBuf,
+        action: Action,
+    },
+    #[serde(rename_all = "camelCase")]
+    AddActions {
+        request_id: RequestId,
+        watch_id: WatchId,
+        actions: Vec<Action>,
+    },
+    #[serde(rename_all = "camelCase")]
+    RemoveActions {
+        request_id: RequestId,
+        watch_id: WatchId,
+        actions: Vec<Action>,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetRecursive {
+        request_id: RequestId,
+        watch_id: WatchId,
+        recursive: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetMaxEntries {
+        request_id: RequestId,
+        watch_id: WatchId,
+        /// Lift the limit when omitted
+        max_entries: Option<usize>,
+    },
+    #[serde(rename_all = "camelCase")]
+    ReloadRules {
+        request_id: RequestId,
+        watch_id: WatchId,
+        rules_file: PathBuf,
+        /// Reload the rules whenever the file changes
+        #[serde(default)]
+        watch: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetWatches {
+        request_id: RequestId,
+        watches: Vec<WatchSpec>,
+    },
 }
 
 #[derive(Debug, Serialize)]
@@ -55,11 +743,151 @@ enum Outgoing {
     #[serde(rename_all = "camelCase")]
     OkResponse {
         request_id: RequestId,
+        /// Directories under a newly watched root that couldn't be read, and so aren't covered
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        unwatched_subdirs: Vec<PathBuf>,
+        /// How many watches were paused or resumed by `pauseAll` or `resumeAll`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        affected_watches: Option<usize>,
+        /// Why the watch created by the request has been removed, for responses sent after the
+        /// request's own
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<RemovalReason>,
+        /// The watch that was removed, along with `reason`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        watch_id: Option<WatchId>,
     },
     #[serde(rename_all = "camelCase")]
     ErrorResponse {
-        request_id: WatchId,
+        request_id: RequestId,
         description: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+    },
+    #[serde(rename_all = "camelCase")]
+    DiffResponse {
+        request_id: RequestId,
+        added: Vec<WatchId>,
+        removed: Vec<WatchId>,
+        kept: Vec<WatchId>,
+        /// Directories under the added watches' roots that couldn't be read, as for `okResponse`
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        unwatched_subdirs: Vec<PathBuf>,
+    },
+    #[serde(rename_all = "camelCase")]
+    HealthResponse {
+        request_id: RequestId,
+        notify_thread_alive: bool,
+        last_tick_ms_ago: u64,
+        restarted: bool,
+    },
+    #[serde(rename_all = "camelCase")]
+    QueueDepthResponse {
+        request_id: RequestId,
+        /// Events that the debouncers have delivered but haven't been emitted yet
+        pending: usize,
+    },
+    #[serde(rename_all = "camelCase")]
+    TimeResponse {
+        request_id: RequestId,
+        /// Milliseconds since the supervisor started, which never go backwards
+        monotonic_ms: u64,
+        /// Milliseconds since the Unix epoch, by the system clock
+        wall_ms: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    LatenciesResponse {
+        request_id: RequestId,
+        p50_ms: f64,
+        p95_ms: f64,
+        max_ms: f64,
+        /// The number of requests the figures are taken from
+        count: usize,
+    },
+    #[serde(rename_all = "camelCase")]
+    TreeHashResponse {
+        request_id: RequestId,
+        watch_id: WatchId,
+        hash: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    HistoryResponse {
+        request_id: RequestId,
+        watch_id: WatchId,
+        events: Vec<Event>,
+    },
+    #[serde(rename_all = "camelCase")]
+    SnapshotResponse {
+        request_id: RequestId,
+        watch_id: WatchId,
+        entries: Vec<snapshot::Entry>,
+    },
+    #[serde(rename_all = "camelCase")]
+    ConfigResponse {
+        request_id: RequestId,
+        watches: Vec<WatchSpec>,
+    },
+    #[serde(rename_all = "camelCase")]
+    CanonicalResponse {
+        request_id: RequestId,
+        input: PathBuf,
+        canonical: Option<PathBuf>,
+        error: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    LsResponse {
+        request_id: RequestId,
+        path: PathBuf,
+        #[serde(serialize_with = "raw_paths::serialize_all_lossy")]
+        entries: Vec<PathBuf>,
+    },
+    #[serde(rename_all = "camelCase")]
+    WouldEmitResponse {
+        request_id: RequestId,
+        emit: bool,
+        /// The option that keeps the event from being emitted, when `emit` is false
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<&'static str>,
+    },
+    #[serde(rename_all = "camelCase")]
+    CoverageResponse {
+        request_id: RequestId,
+        path: PathBuf,
+        /// The watches that would emit events for `path`, in ascending order
+        watch_ids: Vec<WatchId>,
+    },
+    #[serde(rename_all = "camelCase")]
+    OverlapsResponse {
+        request_id: RequestId,
+        watch_id: WatchId,
+        /// The other watches whose paths are all within this one's, in ascending order
+        contains: Vec<WatchId>,
+        /// The other watches that this one's paths are all within, in ascending order
+        contained_by: Vec<WatchId>,
+    },
+    #[serde(rename_all = "camelCase")]
+    VersionResponse {
+        request_id: RequestId,
+        crate_version: &'static str,
+        protocol_version: u32,
+        notify_version: &'static str,
+    },
+    #[serde(rename_all = "camelCase")]
+    SystemInfoResponse {
+        request_id: RequestId,
+        max_user_watches: Option<u64>,
+        max_user_instances: Option<u64>,
+        current_instance_watches: Option<u64>,
+    },
+    /// Written when nothing else has been for the interval given to `--keepalive-ms`
+    #[serde(rename_all = "camelCase")]
+    Keepalive {
+        timestamp_ms: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    ActionsResponse {
+        request_id: RequestId,
+        actions: Vec<Action>,
     },
     #[serde(rename_all = "camelCase")]
     WatchEvents {
@@ -69,6 +897,33 @@ enum Outgoing {
     WatcherError {
         description: String,
     },
+    /// A change to the supervisor's own state, written after `subscribeLifecycle`
+    #[serde(rename_all = "camelCase")]
+    Lifecycle {
+        kind: LifecycleKind,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        watch_id: Option<WatchId>,
+        /// What caused the change, such as the request that added or removed a watch
+        detail: String,
+    },
+    // All the messages produced by a single delivery of events from the debouncer, used in place
+    // of emitting them individually when `--batch-emit` is passed
+    Batch {
+        messages: Vec<Outgoing>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum ErrorCode {
+    WatchLimitExceeded,
+    Timeout,
+    InvalidEncoding,
+    InvalidFrame,
+    InvalidRequest,
+    BackendSilent,
+    IdRetired,
+    TooManyEntries,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -76,292 +931,5459 @@ enum Outgoing {
 #[serde(rename_all = "camelCase")]
 enum Event {
     Modified {
+        #[serde(serialize_with = "raw_paths::serialize_lossy")]
         path: PathBuf,
+        #[serde(flatten)]
+        info: FileInfo,
+        #[serde(flatten)]
+        raw: RawPaths,
     },
     Created {
+        #[serde(serialize_with = "raw_paths::serialize_lossy")]
         path: PathBuf,
+        #[serde(flatten)]
+        info: FileInfo,
+        #[serde(flatten)]
+        raw: RawPaths,
     },
     Deleted {
+        #[serde(serialize_with = "raw_paths::serialize_lossy")]
         path: PathBuf,
+        #[serde(flatten)]
+        info: FileInfo,
+        #[serde(flatten)]
+        raw: RawPaths,
     },
     #[serde(rename_all = "camelCase")]
     Renamed {
+        #[serde(serialize_with = "raw_paths::serialize_lossy")]
         path: PathBuf,
+        #[serde(serialize_with = "raw_paths::serialize_lossy")]
         old_path: PathBuf,
+        #[serde(flatten)]
+        info: FileInfo,
+        #[serde(flatten)]
+        raw: RawPaths,
     },
     Error {
+        #[serde(serialize_with = "raw_paths::serialize_lossy")]
         path: PathBuf,
         description: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+        #[serde(flatten)]
+        raw: RawPaths,
+    },
+    /// The backend's event queue overflowed, so some events for this watch were lost
+    QueueOverflow,
+    /// The watch hasn't emitted anything else for its `idle_after_ms`
+    Idle,
+    /// The events up to the matching `BatchEnd` were emitted together, with `batch_markers`
+    #[serde(rename_all = "camelCase")]
+    BatchBegin { batch_id: u64 },
+    #[serde(rename_all = "camelCase")]
+    BatchEnd { batch_id: u64 },
+    /// Whatever is known about `path` may be stale and should be rebuilt by rescanning it
+    Rescan {
+        #[serde(serialize_with = "raw_paths::serialize_lossy")]
+        path: PathBuf,
+        #[serde(flatten)]
+        raw: RawPaths,
+    },
+    /// The root of a watch that started before it existed has been created
+    RootCreated {
+        #[serde(serialize_with = "raw_paths::serialize_lossy")]
+        path: PathBuf,
+        #[serde(flatten)]
+        raw: RawPaths,
+    },
+    /// Something in the directory at `path` changed, and `entries` are what it now contains
+    Listing {
+        #[serde(serialize_with = "raw_paths::serialize_lossy")]
+        path: PathBuf,
+        #[serde(serialize_with = "raw_paths::serialize_all_lossy")]
+        entries: Vec<PathBuf>,
+        #[serde(flatten)]
+        raw: RawPaths,
+    },
+    /// The entries of the directory at `path` that appeared and disappeared since its last
+    /// listing, with `listing_delta`
+    ListingDelta {
+        #[serde(serialize_with = "raw_paths::serialize_lossy")]
+        path: PathBuf,
+        #[serde(serialize_with = "raw_paths::serialize_all_lossy")]
+        added: Vec<PathBuf>,
+        #[serde(serialize_with = "raw_paths::serialize_all_lossy")]
+        removed: Vec<PathBuf>,
+        #[serde(flatten)]
+        raw: RawPaths,
+    },
+    /// How many changes of each kind were counted over the last `window_ms`, with
+    /// `summary_interval_ms`
+    #[serde(rename_all = "camelCase")]
+    Summary {
+        watch_id: WatchId,
+        created: u64,
+        modified: u64,
+        deleted: u64,
+        renamed: u64,
+        window_ms: u64,
     },
 }
 
-impl<W: Watcher> Supervisor<W> {
-    fn new(delay: Duration) -> Result<Self, notify::Error> {
-        let (tx, rx) = mpsc::channel();
+/// Why a watch was removed without being unwatched.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum RemovalReason {
+    /// The watch emitted as many events as its `limit` allowed
+    LimitReached,
+    /// The watch's `duration_ms` passed
+    DurationElapsed,
+}
 
-        let watcher = W::new(tx, delay)?;
-        let watches = Arc::new(Mutex::new(Vec::new()));
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum LifecycleKind {
+    WatchAdded,
+    WatchRemoved,
+    NotifyThreadRestarted,
+    /// A backend's queue of events overflowed, so some were lost
+    QueueOverflow,
+}
 
-        let watches_2 = watches.clone();
-        thread::spawn(move || {
-            loop {
-                if let Ok(event) = rx.recv() {
-                    let mut events = vec![event];
-                    events.extend(rx.try_iter()); // Collect more pending events without blocking
-                    Self::notify(&watches_2, events);
-                } else {
-                    break;
-                }
-            }
-        });
+/// The kinds of event, as named by their `action` field.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+enum Action {
+    Modified,
+    Created,
+    Deleted,
+    Renamed,
+    Error,
+}
 
-        Ok(Self { watcher, watches })
-    }
+impl Action {
+    const ALL: [Action; 5] = [
+        Action::Modified,
+        Action::Created,
+        Action::Deleted,
+        Action::Renamed,
+        Action::Error,
+    ];
+}
+
+/// The patterns read from a rules file with `reloadRules`, according to the watch's
+/// `ignore_syntax`.
+enum IgnoreRules {
+    Glob(FilterProfile),
+    Gitignore(Vec<gitignore::Rule>),
+}
 
-    fn notify(watches: &Arc<Mutex<Vec<Watch>>>, events: Vec<DebouncedEvent>) {
-        for watch in watches.lock().unwrap().iter() {
-            watch.notify(&events)
+/// How a watch reports renames, as chosen with `emit_rename_as`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RenameMode {
+    /// As a single `renamed` event
+    Paired,
+    /// As the deletion of the old path followed by the creation of the new one
+    Split,
+    /// As the `renamed` event followed by the deletion and the creation, sharing a `rename_id`
+    Both,
+}
+
+impl RenameMode {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "paired" => Ok(RenameMode::Paired),
+            "split" => Ok(RenameMode::Split),
+            "both" => Ok(RenameMode::Both),
+            _ => Err(format!(
+                "Unsupported emitRenameAs {:?}, expected \"paired\", \"split\" or \"both\"",
+                name
+            )),
         }
+    }
+}
+
+/// Optional details about the file an event refers to, populated according to the watch's
+/// options.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inode: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dev: Option<u64>,
+    // Only set for files with more than one link
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hardlink: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nlink: Option<u64>,
+    // Serialized as `null` when the file couldn't be hashed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<Option<String>>,
+    // Only set on modifications of files, with `track_size_delta`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size_before: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size_after: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<SizeChange>,
+    // Shared by a rename and the deletion and creation it's also emitted as, with
+    // `emit_rename_as: "both"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rename_id: Option<u64>,
+    // The directories between the event's path and the root, nearest first, with
+    // `include_ancestors`
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "raw_paths::serialize_all_lossy"
+    )]
+    ancestors: Vec<PathBuf>,
+    // Filled in on the output thread to produce `hash`
+    #[serde(skip)]
+    pending_hash: Option<HashSpec>,
+    // Checked on the output thread, which drops the event if the file's first line doesn't match
+    #[serde(skip)]
+    pending_content_match: Option<Arc<Pattern>>,
+    // The path the output thread reads the file at when `rewrite` changed the event's path
+    #[serde(skip)]
+    local_path: Option<PathBuf>,
+}
+
+impl FileInfo {
+    // Whether there's nothing to serialize. Fields filled in on the output thread have been by the
+    // time this is checked.
+    fn is_empty(&self) -> bool {
+        self.inode.is_none()
+            && self.dev.is_none()
+            && self.hardlink.is_none()
+            && self.nlink.is_none()
+            && self.hash.is_none()
+            && self.kind.is_none()
+            && self.rename_id.is_none()
+            && self.ancestors.is_empty()
+    }
+}
+
+impl Incoming {
+    fn request_id(&self) -> RequestId {
+        match self {
+            Incoming::Watch { request_id, .. }
+            | Incoming::WatchFiles { request_id, .. }
+            | Incoming::Unwatch { request_id, .. }
+            | Incoming::UnwatchAll { request_id, .. }
+            | Incoming::Flush { request_id, .. }
+            | Incoming::Health { request_id, .. }
+            | Incoming::PauseAll { request_id, .. }
+            | Incoming::ResumeAll { request_id, .. }
+            | Incoming::BufferedPause { request_id, .. }
+            | Incoming::Resume { request_id, .. }
+            | Incoming::DebugQueue { request_id, .. }
+            | Incoming::Latencies { request_id, .. }
+            | Incoming::Time { request_id, .. }
+            | Incoming::Version { request_id, .. }
+            | Incoming::SystemInfo { request_id, .. }
+            | Incoming::ExportConfig { request_id, .. }
+            | Incoming::SubscribeLifecycle { request_id, .. }
+            | Incoming::SetDebounce { request_id, .. }
+            | Incoming::SetWatchDebounce { request_id, .. }
+            | Incoming::RenameWatch { request_id, .. }
+            | Incoming::TreeHash { request_id, .. }
+            | Incoming::History { request_id, .. }
+            | Incoming::Snapshot { request_id, .. }
+            | Incoming::Overlaps { request_id, .. }
+            | Incoming::EffectiveActions { request_id, .. }
+            | Incoming::Shutdown { request_id, .. }
+            | Incoming::IsWatched { request_id, .. }
+            | Incoming::Canonicalize { request_id, .. }
+            | Incoming::Ls { request_id, .. }
+            | Incoming::WouldEmit { request_id, .. }
+            | Incoming::AddActions { request_id, .. }
+            | Incoming::RemoveActions { request_id, .. }
+            | Incoming::SetRecursive { request_id, .. }
+            | Incoming::SetMaxEntries { request_id, .. }
+            | Incoming::ReloadRules { request_id, .. }
+            | Incoming::SetWatches { request_id, .. } => *request_id,
+        }
+    }
+
+    // Translate the roots in the request from the client's paths to ours.
+    fn map_roots(&mut self, path_map: &PathMap) {
+        match self {
+            Incoming::Watch { spec, .. } => spec.map_roots(path_map),
+            Incoming::WatchFiles { files, options, .. } => {
+                for file in files {
+                    *file = path_map.to_container(file);
+                }
+                options.map_paths(&|path: &Path| path_map.to_container(path));
+            }
+            Incoming::IsWatched { path, .. }
+            | Incoming::Canonicalize { path, .. }
+            | Incoming::Ls { path, .. }
+            | Incoming::WouldEmit { path, .. } => *path = path_map.to_container(path),
+            Incoming::ReloadRules { rules_file, .. } => {
+                *rules_file = path_map.to_container(rules_file)
+            }
+            Incoming::SetWatches { watches, .. } => {
+                for spec in watches {
+                    spec.map_roots(path_map);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Outgoing {
+    // The request that this is a response to, if it is one
+    fn request_id(&self) -> Option<RequestId> {
+        match self {
+            Outgoing::OkResponse { request_id, .. }
+            | Outgoing::ErrorResponse { request_id, .. }
+            | Outgoing::DiffResponse { request_id, .. }
+            | Outgoing::HealthResponse { request_id, .. }
+            | Outgoing::QueueDepthResponse { request_id, .. }
+            | Outgoing::TimeResponse { request_id, .. }
+            | Outgoing::LatenciesResponse { request_id, .. }
+            | Outgoing::TreeHashResponse { request_id, .. }
+            | Outgoing::HistoryResponse { request_id, .. }
+            | Outgoing::SnapshotResponse { request_id, .. }
+            | Outgoing::ConfigResponse { request_id, .. }
+            | Outgoing::CanonicalResponse { request_id, .. }
+            | Outgoing::LsResponse { request_id, .. }
+            | Outgoing::WouldEmitResponse { request_id, .. }
+            | Outgoing::CoverageResponse { request_id, .. }
+            | Outgoing::OverlapsResponse { request_id, .. }
+            | Outgoing::VersionResponse { request_id, .. }
+            | Outgoing::SystemInfoResponse { request_id, .. }
+            | Outgoing::ActionsResponse { request_id, .. } => Some(*request_id),
+            _ => None,
+        }
+    }
+
+    fn map_paths(&mut self, f: &impl Fn(&Path) -> PathBuf) {
+        match self {
+            Outgoing::OkResponse {
+                unwatched_subdirs, ..
+            }
+            | Outgoing::DiffResponse {
+                unwatched_subdirs, ..
+            } => {
+                for path in unwatched_subdirs {
+                    *path = f(path);
+                }
+            }
+            Outgoing::CoverageResponse { path, .. } => *path = f(path),
+            Outgoing::LsResponse { path, entries, .. } => {
+                *path = f(path);
+                for entry in entries {
+                    *entry = f(entry);
+                }
+            }
+            Outgoing::CanonicalResponse {
+                input, canonical, ..
+            } => {
+                *input = f(input);
+                if let Some(canonical) = canonical {
+                    *canonical = f(canonical);
+                }
+            }
+            Outgoing::ConfigResponse { watches, .. } => {
+                for spec in watches {
+                    spec.map_paths(f);
+                }
+            }
+            Outgoing::WatchEvents { events, .. } | Outgoing::HistoryResponse { events, .. } => {
+                for event in events {
+                    event.map_paths(f);
+                }
+            }
+            Outgoing::SnapshotResponse { entries, .. } => {
+                for entry in entries {
+                    entry.path = f(&entry.path);
+                }
+            }
+            Outgoing::Batch { messages } => {
+                for message in messages {
+                    message.map_paths(f);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Drop the events for files whose contents don't match their watch's `content_match`,
+    // returning whether anything is left to emit.
+    fn retain_content_matches(&mut self) -> bool {
+        match self {
+            Outgoing::WatchEvents { events, .. } => {
+                events.retain_mut(Event::matches_content);
+                !events.is_empty()
+            }
+            Outgoing::Batch { messages } => {
+                messages.retain_mut(Outgoing::retain_content_matches);
+                !messages.is_empty()
+            }
+            _ => true,
+        }
+    }
+
+    fn compute_hashes(&mut self) {
+        match self {
+            Outgoing::WatchEvents { events, .. } => events.iter_mut().for_each(Event::compute_hash),
+            Outgoing::Batch { messages } => messages.iter_mut().for_each(Outgoing::compute_hashes),
+            _ => {}
+        }
+    }
+}
+
+impl WatchSpec {
+    // The directories under the root that notify skips without reporting them, because they
+    // can't be read, so we look for them ourselves. Only a recursive watch would register them.
+    fn unwatched_subdirs(&self) -> Vec<PathBuf> {
+        if self.options.is_recursive() {
+            snapshot::inaccessible_dirs(&self.root)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn map_roots(&mut self, path_map: &PathMap) {
+        self.map_paths(&|path: &Path| path_map.to_container(path));
+    }
+
+    fn map_paths(&mut self, f: &impl Fn(&Path) -> PathBuf) {
+        self.root = f(&self.root);
+        self.options.map_paths(f);
+    }
+}
+
+impl WatchOptions {
+    // Relative profile prefixes are resolved against the root later, so only absolute ones are
+    // translated.
+    fn map_paths(&mut self, f: &impl Fn(&Path) -> PathBuf) {
+        for profile in &mut self.profiles {
+            if profile.prefix.is_absolute() {
+                profile.prefix = f(&profile.prefix);
+            }
+        }
+        if let Some(flush_on) = &mut self.flush_on {
+            if flush_on.is_absolute() {
+                *flush_on = f(flush_on);
+            }
+        }
+    }
+
+    // Whether the whole tree under the root is watched, rather than only its direct children.
+    fn is_recursive(&self) -> bool {
+        self.listing_mode != Some(true) && self.recursive != Some(false)
+    }
+
+    // Fill in the options that weren't given from the filters inherited from another watch.
+    fn inherit(&mut self, filters: WatchOptions) {
+        self.ignore_temp_files = self.ignore_temp_files.or(filters.ignore_temp_files);
+        self.actions = self.actions.take().or(filters.actions);
+        self.content_match = self.content_match.take().or(filters.content_match);
+        if self.profiles.is_empty() {
+            self.profiles = filters.profiles;
+        }
+        self.min_size = self.min_size.or(filters.min_size);
+        self.max_size = self.max_size.or(filters.max_size);
+        if self.filesystems.is_empty() {
+            self.filesystems = filters.filesystems;
+        }
+        self.symlinks = self.symlinks.take().or(filters.symlinks);
+        if self.modified_on.is_empty() {
+            self.modified_on = filters.modified_on;
+        }
+        self.created_only = self.created_only.or(filters.created_only);
+    }
+
+    // Check the options that can't be validated while deserializing them.
+    fn validate(&self) -> Result<(), String> {
+        self.hash_spec()?;
+        self.content_pattern()?;
+        self.rename_mode()?;
+        self.symlink_filter()?;
+        self.ignore_syntax()?;
+        if let (Some(min_size), Some(max_size)) = (self.min_size, self.max_size) {
+            if min_size > max_size {
+                return Err(format!(
+                    "minSize {} is greater than maxSize {}",
+                    min_size, max_size
+                ));
+            }
+        }
+        if let Some((from, _)) = &self.rewrite {
+            if from.is_empty() {
+                return Err("rewrite's prefix to replace can't be empty".to_string());
+            }
+        }
+        if self.listing_mode == Some(true) && self.recursive == Some(true) {
+            return Err("listingMode watches can't be recursive".to_string());
+        }
+        if self.listing_delta == Some(true) && self.listing_mode != Some(true) {
+            return Err("listingDelta only applies along with listingMode".to_string());
+        }
+        if !self.filesystems.is_empty() && !filesystems::supported() {
+            return Err("filesystems is only supported on Linux".to_string());
+        }
+        Ok(())
+    }
+
+    fn has_size_bounds(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some()
+    }
+
+    // Whether `path` is within `min_size` and `max_size`. Anything that isn't a file, including
+    // paths that no longer exist, has no size to check.
+    fn allows_size(&self, path: &Path) -> bool {
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                let size = metadata.len();
+                self.min_size.is_none_or(|min_size| size >= min_size)
+                    && self.max_size.is_none_or(|max_size| size <= max_size)
+            }
+            _ => true,
+        }
+    }
+
+    fn content_pattern(&self) -> Result<Option<Arc<Pattern>>, String> {
+        match &self.content_match {
+            Some(source) => Ok(Some(Arc::new(Pattern::parse(source)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn symlink_filter(&self) -> Result<SymlinkFilter, String> {
+        match &self.symlinks {
+            Some(name) => SymlinkFilter::parse(name),
+            None => Ok(SymlinkFilter::Include),
+        }
+    }
+
+    fn ignore_syntax(&self) -> Result<IgnoreSyntax, String> {
+        match &self.ignore_syntax {
+            Some(name) => IgnoreSyntax::parse(name),
+            None => Ok(IgnoreSyntax::Glob),
+        }
+    }
+
+    fn rename_mode(&self) -> Result<RenameMode, String> {
+        match &self.emit_rename_as {
+            Some(name) => RenameMode::parse(name),
+            None => Ok(RenameMode::Paired),
+        }
+    }
+
+    fn hash_spec(&self) -> Result<Option<HashSpec>, String> {
+        match &self.hash {
+            Some(name) => Ok(Some(HashSpec {
+                algorithm: HashAlgorithm::parse(name)?,
+                max_bytes: self.hash_max_bytes.unwrap_or(DEFAULT_HASH_MAX_BYTES),
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Outgoing {
+    fn ok_response(request_id: RequestId) -> Self {
+        Outgoing::OkResponse {
+            request_id,
+            unwatched_subdirs: Vec::new(),
+            affected_watches: None,
+            reason: None,
+            watch_id: None,
+        }
+    }
+
+    fn lifecycle(kind: LifecycleKind, watch_id: Option<WatchId>, detail: &str) -> Self {
+        Outgoing::Lifecycle {
+            kind,
+            watch_id,
+            detail: detail.to_string(),
+        }
+    }
+
+    fn error_response(request_id: RequestId, description: String) -> Self {
+        Outgoing::ErrorResponse {
+            request_id,
+            description,
+            code: None,
+        }
+    }
+
+    fn error_response_with_code(
+        request_id: RequestId,
+        code: ErrorCode,
+        description: String,
+    ) -> Self {
+        Outgoing::ErrorResponse {
+            request_id,
+            description,
+            code: Some(code),
+        }
+    }
+}
+
+impl SupervisorBuilder {
+    fn new() -> Self {
+        Self {
+            backend: Backend::Native,
+            debounce: DEFAULT_DEBOUNCE,
+            max_watches: None,
+            single_use_ids: false,
+            measure_latency: false,
+            batch_emit: false,
+            keepalive: None,
+            drain: Duration::from_millis(0),
+            canonicalize_timeout: None,
+            protocol: Protocol::Json,
+            format: OutputFormat::Json,
+            event_template: None,
+            path_map: None,
+            json_case: JsonCase::Camel,
+            global_seq: false,
+            event_ids: false,
+            sinks: vec![Box::new(StdoutSink)],
+        }
+    }
+
+    /// How long the native backend debounces events for.
+    fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// The backend used by watches that don't ask for a particular one.
+    fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Reject watch requests once this many watches are active.
+    fn max_watches(mut self, max_watches: usize) -> Self {
+        self.max_watches = Some(max_watches);
+        self
+    }
+
+    /// Reject watch requests for ids that were used before, so that events meant for a removed
+    /// watch can't be mistaken for those of a new one.
+    fn single_use_ids(mut self, single_use_ids: bool) -> Self {
+        self.single_use_ids = single_use_ids;
+        self
+    }
+
+    /// Keep track of how long requests take to handle, for the `latencies` request.
+    fn measure_latency(mut self, measure_latency: bool) -> Self {
+        self.measure_latency = measure_latency;
+        self
+    }
+
+    /// Emit all the messages from each debouncer delivery as a single batch.
+    fn batch_emit(mut self, batch_emit: bool) -> Self {
+        self.batch_emit = batch_emit;
+        self
+    }
+
+    /// Write a keepalive message whenever nothing else has been written for this long.
+    fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// How long to keep emitting pending events for when shutting down.
+    fn drain(mut self, drain: Duration) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    /// Give up on resolving a path after this long, for requests that resolve the paths they're
+    /// given.
+    fn canonicalize_timeout(mut self, timeout: Duration) -> Self {
+        self.canonicalize_timeout = Some(timeout);
+        self
+    }
+
+    /// How requests and messages are framed.
+    fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// How messages are written with the JSON protocol.
+    fn format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Write each event as this template filled in, instead of as JSON.
+    fn event_template(mut self, template: EventTemplate) -> Self {
+        self.event_template = Some(template);
+        self
+    }
+
+    /// Translate the paths of requests and messages between the client's view of the file system
+    /// and ours.
+    fn path_map(mut self, path_map: PathMap) -> Self {
+        self.path_map = Some(path_map);
+        self
+    }
+
+    /// The naming convention for the field names of requests and messages.
+    fn json_case(mut self, json_case: JsonCase) -> Self {
+        self.json_case = json_case;
+        self
+    }
+
+    /// Number every event written with a `globalSeq`, across watches.
+    fn global_seq(mut self, global_seq: bool) -> Self {
+        self.global_seq = global_seq;
+        self
+    }
+
+    /// Give every event written a unique `eventId`.
+    fn event_ids(mut self, event_ids: bool) -> Self {
+        self.event_ids = event_ids;
+        self
+    }
+
+    /// Where responses and events are written, instead of any sinks given so far.
+    fn sink(mut self, sink: Box<dyn EventSink>) -> Self {
+        self.sinks = vec![sink];
+        self
+    }
+
+    /// Write responses and events to `sink` as well, after the sinks given so far.
+    fn add_sink(mut self, sink: Box<dyn EventSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    fn build(self) -> Result<Supervisor, notify::Error> {
+        let (tx, rx) = backend::event_channel();
+
+        let watchers = Arc::new(Mutex::new(Watchers::new(self.backend, self.debounce, tx)?));
+        let watches = Arc::new(Mutex::new(Vec::new()));
+        let flushes = Arc::new(Mutex::new(Vec::new()));
+        let last_tick = Arc::new(Mutex::new(Instant::now()));
+        let sink = Arc::new(MessageSink {
+            sinks: SharedSink::new(self.sinks),
+            protocol: self.protocol,
+            format: self.format,
+            event_template: self.event_template,
+            path_map: self.path_map,
+            json_case: self.json_case,
+            global_seq: self.global_seq.then(|| AtomicU64::new(1)),
+            event_ids: self.event_ids.then(EventIds::new),
+            latencies: self.measure_latency.then(Mutex::default),
+        });
+        if let Some(interval) = self.keepalive {
+            spawn_keepalive_thread(sink.clone(), interval);
+        }
+        let paused = Arc::new(AtomicBool::new(false));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let lifecycle = Arc::new(AtomicBool::new(false));
+        let (output, output_thread) = spawn_output_thread(sink.clone());
+        let notify_thread = Supervisor::spawn_notify_thread(
+            rx,
+            NotifyThreadState {
+                watchers: watchers.clone(),
+                watches: watches.clone(),
+                flushes: flushes.clone(),
+                last_tick: last_tick.clone(),
+                paused: paused.clone(),
+                stopping: stopping.clone(),
+                lifecycle: lifecycle.clone(),
+                output: output.clone(),
+                batch_emit: self.batch_emit,
+            },
+        );
+
+        Ok(Supervisor {
+            watchers,
+            watches,
+            flushes,
+            last_tick,
+            notify_thread,
+            output,
+            output_thread,
+            sink,
+            paused,
+            stopping,
+            lifecycle,
+            max_watches: self.max_watches,
+            used_ids: self.single_use_ids.then(HashSet::new),
+            canonicalize_timeout: self.canonicalize_timeout,
+            started_at: Instant::now(),
+            batch_emit: self.batch_emit,
+            drain: self.drain,
+        })
+    }
+}
+
+impl Supervisor {
+    fn builder() -> SupervisorBuilder {
+        SupervisorBuilder::new()
+    }
+
+    // Run the event loop on a thread of its own, watched over by the returned thread. If the event
+    // loop panics, the watchdog replaces the watchers, whose channel went down with the loop, and
+    // starts a new one. The watchdog exits along with the event loop otherwise.
+    fn spawn_notify_thread(rx: EventReceiver, shared: NotifyThreadState) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut rx = rx;
+            loop {
+                let event_loop = Self::spawn_event_loop(rx, shared.clone());
+                if event_loop.join().is_ok() || shared.stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                rx = match Self::replace_watchers(&shared) {
+                    Ok(rx) => rx,
+                    Err(description) => {
+                        let _ = shared.output.send(Outgoing::WatcherError { description });
+                        break;
+                    }
+                };
+                if shared.lifecycle.load(Ordering::SeqCst) {
+                    let _ = shared.output.send(Outgoing::lifecycle(
+                        LifecycleKind::NotifyThreadRestarted,
+                        None,
+                        "panic",
+                    ));
+                }
+            }
+        })
+    }
+
+    fn spawn_event_loop(rx: EventReceiver, shared: NotifyThreadState) -> JoinHandle<()> {
+        let NotifyThreadState {
+            watchers,
+            watches,
+            flushes,
+            last_tick,
+            paused,
+            stopping,
+            lifecycle,
+            output,
+            batch_emit,
+        } = shared;
+        *lock(&last_tick) = Instant::now();
+        thread::spawn(move || loop {
+            // Wake up periodically even when no events arrive so that pending flushes get
+            // acknowledged and liveness can be checked.
+            let received = rx.recv_timeout(TICK_INTERVAL);
+            let drained_at = Instant::now();
+            *lock(&last_tick) = drained_at;
+            let mut events = match received {
+                Ok(event) => vec![event],
+                Err(RecvTimeoutError::Timeout) => Vec::new(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            events.extend(rx.try_iter()); // Collect more pending events without blocking
+            if !events.is_empty() {
+                let mut watches = lock(&watches);
+                let mut messages = Self::notify(&mut watches, events, &paused, &lifecycle);
+                // Narrow before emitting, so that clients reacting to the creation of a root by
+                // changing something in it can't race with the switch to the root's own
+                // registration
+                messages.extend(Self::narrow_watches(&mut watches, &watchers));
+                // Send while the watches are still locked, so that the events of a watch that's
+                // being unwatched are queued before the response to `unwatch` rather than after
+                Self::send_messages(messages, &output, batch_emit);
+            }
+            Self::release_held_modifications(&watches, drained_at, &paused, &output, batch_emit);
+            Self::report_idle_watches(&watches, drained_at, &paused, &output, batch_emit);
+            Self::report_summaries(&watches, drained_at, &paused, &output, batch_emit);
+            Self::check_watchdogs(
+                &watches, &watchers, drained_at, &paused, &output, batch_emit,
+            );
+            Self::remove_finished_watches(
+                &watches, &watchers, drained_at, &paused, &output, &lifecycle, batch_emit,
+            );
+            Self::acknowledge_flushes(&flushes, drained_at, &output);
+            if stopping.load(Ordering::SeqCst) {
+                break;
+            }
+        })
+    }
+
+    // Replace a notify thread that has exited with a fresh one.
+    fn restart_notify_thread(&mut self) -> Result<(), String> {
+        let shared = self.notify_thread_state();
+        let rx = Self::replace_watchers(&shared)?;
+        self.notify_thread = Self::spawn_notify_thread(rx, shared);
+        Ok(())
+    }
+
+    // A notify thread that has exited took the receiving end of the watchers' channel with it, so
+    // restarting it requires new watchers, on which all the current roots are registered again.
+    // Returns the receiving end of the new watchers' channel.
+    fn replace_watchers(shared: &NotifyThreadState) -> Result<EventReceiver, String> {
+        let (tx, rx) = backend::event_channel();
+        let (default_backend, native_delay) = {
+            let watchers = lock(&shared.watchers);
+            (watchers.default_backend(), watchers.native_delay())
+        };
+        let mut watchers = Watchers::new(default_backend, native_delay, tx)
+            .map_err(|error| describe_error(&error))?;
+
+        {
+            let watches = lock(&shared.watches);
+            for (backend, path, mode) in effective_registrations(&watches) {
+                if let Err(error) = watchers
+                    .get(backend)
+                    .and_then(|watcher| watcher.watch(path, mode))
+                {
+                    return Err(format!(
+                        "Error re-watching {:?} after restarting the notify thread: {}",
+                        path,
+                        describe_error(&error)
+                    ));
+                }
+            }
+        }
+
+        *lock(&shared.watchers) = watchers;
+        Ok(rx)
+    }
+
+    fn notify_thread_state(&self) -> NotifyThreadState {
+        NotifyThreadState {
+            watchers: self.watchers.clone(),
+            watches: self.watches.clone(),
+            flushes: self.flushes.clone(),
+            last_tick: self.last_tick.clone(),
+            paused: self.paused.clone(),
+            stopping: self.stopping.clone(),
+            lifecycle: self.lifecycle.clone(),
+            output: self.output.clone(),
+            batch_emit: self.batch_emit,
+        }
+    }
+
+    // Acknowledge the flushes whose deadline had passed by the time the event channel was last
+    // drained, which guarantees that every event debounced before the deadline has been emitted.
+    // The acknowledgements go through the output thread so that they follow the events.
+    fn acknowledge_flushes(
+        flushes: &Mutex<Vec<PendingFlush>>,
+        drained_at: Instant,
+        output: &Sender<Outgoing>,
+    ) {
+        lock(flushes).retain(|flush| {
+            if flush.deadline <= drained_at {
+                if let Some(request_id) = flush.request_id {
+                    let _ = output.send(Outgoing::ok_response(request_id));
+                }
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn notify(
+        watches: &mut [Watch],
+        events: Vec<(Backend, DebouncedEvent)>,
+        paused: &AtomicBool,
+        lifecycle: &AtomicBool,
+    ) -> Vec<Outgoing> {
+        if paused.load(Ordering::SeqCst) {
+            return Vec::new();
+        }
+
+        let mut messages: Vec<Outgoing> = watches_by_id(watches)
+            .into_iter()
+            .filter_map(|watch| watch.notify(&events))
+            .collect();
 
         // Emit errors that aren't associated with paths as top-level events
-        for event in &events {
+        for (_, event) in &events {
             if let DebouncedEvent::Error(error, None) = event {
-                emit_json(Outgoing::WatcherError {
-                    description: String::from(error.description()),
+                messages.push(Outgoing::WatcherError {
+                    description: describe_error(error),
                 })
             }
         }
+        if lifecycle.load(Ordering::SeqCst)
+            && events
+                .iter()
+                .any(|(_, event)| matches!(event, DebouncedEvent::Rescan))
+        {
+            messages.push(Outgoing::lifecycle(
+                LifecycleKind::QueueOverflow,
+                None,
+                "rescan",
+            ));
+        }
+
+        messages
+    }
+
+    // Emit the modifications held back by watches with `trailing_quiet_ms` once their paths have
+    // been quiet for long enough, and the events buffered for `coalesce_ms` once their window is
+    // over. While paused, they're kept until emission resumes.
+    fn release_held_modifications(
+        watches: &Mutex<Vec<Watch>>,
+        now: Instant,
+        paused: &AtomicBool,
+        output: &Sender<Outgoing>,
+        batch_emit: bool,
+    ) {
+        if paused.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let mut watches = lock(watches);
+        let messages: Vec<Outgoing> = watches_by_id(&mut watches)
+            .into_iter()
+            .filter_map(|watch| watch.release_held_modifications(now))
+            .collect();
+        Self::send_messages(messages, output, batch_emit);
+    }
+
+    // Emit an `idle` event for the watches with `idle_after_ms` that have been quiet for long
+    // enough. While paused, nothing is reported.
+    fn report_idle_watches(
+        watches: &Mutex<Vec<Watch>>,
+        now: Instant,
+        paused: &AtomicBool,
+        output: &Sender<Outgoing>,
+        batch_emit: bool,
+    ) {
+        if paused.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let mut watches = lock(watches);
+        let messages: Vec<Outgoing> = watches_by_id(&mut watches)
+            .into_iter()
+            .filter_map(|watch| watch.report_idle(now))
+            .collect();
+        Self::send_messages(messages, output, batch_emit);
+    }
+
+    // Emit a `summary` event for the watches with `summary_interval_ms` whose interval is over,
+    // if they counted any changes in it. While paused, the current interval goes on until
+    // emission resumes.
+    fn report_summaries(
+        watches: &Mutex<Vec<Watch>>,
+        now: Instant,
+        paused: &AtomicBool,
+        output: &Sender<Outgoing>,
+        batch_emit: bool,
+    ) {
+        if paused.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let mut watches = lock(watches);
+        let messages: Vec<Outgoing> = watches_by_id(&mut watches)
+            .into_iter()
+            .filter_map(|watch| watch.report_summary(now))
+            .collect();
+        Self::send_messages(messages, output, batch_emit);
+    }
+
+    // Report the native watches whose watchdog finds them silent, moving them to the poll backend
+    // if they have `watchdog_fallback`. While paused, events aren't looked at, so the watchdogs
+    // start over instead.
+    fn check_watchdogs(
+        watches: &Mutex<Vec<Watch>>,
+        watchers: &Mutex<Watchers>,
+        now: Instant,
+        paused: &AtomicBool,
+        output: &Sender<Outgoing>,
+        batch_emit: bool,
+    ) {
+        let mut watches = lock(watches);
+        if paused.load(Ordering::SeqCst) {
+            for watch in watches.iter_mut() {
+                if let Some(watchdog) = &mut watch.watchdog {
+                    watchdog.restart(&watch.root, now);
+                }
+            }
+            return;
+        }
+
+        let mut watchers = lock(watchers);
+        let mut silent: Vec<usize> = (0..watches.len())
+            .filter(|&i| {
+                let watch = &mut watches[i];
+                let grace = watchers.delay(watch.backend) + TICK_INTERVAL;
+                match &mut watch.watchdog {
+                    Some(watchdog) if watch.backend.is_native() => {
+                        watchdog.is_silent(&watch.root, now, grace)
+                    }
+                    _ => false,
+                }
+            })
+            .collect();
+        silent.sort_by_key(|&i| watches[i].id);
+
+        let mut messages = Vec::new();
+        for i in silent {
+            let watchdog_ms = watches[i].options.watchdog_ms.unwrap_or_default();
+            let mut description = format!(
+                "The native backend reported nothing for {}ms although {:?} changed",
+                watchdog_ms, watches[i].root
+            );
+            let mut events = Vec::new();
+            if watches[i].options.watchdog_fallback == Some(true) {
+                let options = &watches[i].options;
+                let poll = Backend::resolve(
+                    Some(BackendKind::Poll),
+                    options.poll_interval_ms,
+                    watchers.default_backend(),
+                );
+                match Self::move_to_backend(&mut watchers, &mut watches, i, poll) {
+                    Ok(_) => {
+                        description.push_str(", so it's polled from now on");
+                        events.extend(watches[i].rescans());
+                    }
+                    Err(error) => {
+                        description.push_str(&format!(
+                            ", and it couldn't be polled instead: {}",
+                            describe_error(&error)
+                        ));
+                    }
+                }
+            }
+            events.insert(
+                0,
+                Event::Error {
+                    path: watches[i].root.clone(),
+                    description,
+                    code: Some(ErrorCode::BackendSilent),
+                    raw: RawPaths::default(),
+                },
+            );
+            messages.extend(watches[i].emit(events));
+        }
+        Self::send_messages(messages, output, batch_emit);
+    }
+
+    // Move watches that have reported the creation of their root from watching its ancestor to
+    // watching the root itself. Events keep flowing through the ancestor's registration until the
+    // root's is in place, so none are missed in between.
+    fn narrow_watches(watches: &mut [Watch], watchers: &Mutex<Watchers>) -> Vec<Outgoing> {
+        let mut messages = Vec::new();
+        for i in 0..watches.len() {
+            let watch = &watches[i];
+            if watch.ancestor.is_none() || !watch.root_created {
+                continue;
+            }
+
+            let mut watchers = lock(watchers);
+            let (backend, root) = (watch.backend, watch.root.clone());
+            if let Err(error) = Self::register(
+                &mut watchers,
+                watches,
+                backend,
+                &[(&root, watch.root_mode())],
+            ) {
+                // Keep watching the ancestor, and try again if the root is created again
+                watches[i].root_created = false;
+                messages.push(Outgoing::WatcherError {
+                    description: format!(
+                        "Error watching {:?} once it was created: {}",
+                        root,
+                        describe_error(&error)
+                    ),
+                });
+                continue;
+            }
+            let ancestor = watches[i].ancestor.take().unwrap();
+            if let Err(description) = Self::release_registration(
+                &mut watchers,
+                watches,
+                backend,
+                &ancestor,
+                RecursiveMode::Recursive,
+            ) {
+                messages.push(Outgoing::WatcherError { description });
+            }
+        }
+        messages
+    }
+
+    // Remove the watches that have emitted as many events as their `limit` allows or outlived their
+    // `duration_ms`, answering the requests that created them a second time to say so. The
+    // responses go through the output thread so that they follow the watches' last events.
+    fn remove_finished_watches(
+        watches: &Mutex<Vec<Watch>>,
+        watchers: &Mutex<Watchers>,
+        now: Instant,
+        paused: &AtomicBool,
+        output: &Sender<Outgoing>,
+        lifecycle: &AtomicBool,
+        batch_emit: bool,
+    ) {
+        let mut watches = lock(watches);
+        let mut finished: Vec<(Watch, RemovalReason)> = Vec::new();
+        let mut i = 0;
+        while i < watches.len() {
+            // A watch that's done while its events are kept stays until they've been written, so
+            // that the second response to its request comes after them
+            let reason = match watches[i].pause_buffer {
+                Some(_) => None,
+                None => watches[i].removal_reason(now),
+            };
+            match reason {
+                Some(reason) => finished.push((watches.remove(i), reason)),
+                None => i += 1,
+            }
+        }
+        if finished.is_empty() {
+            return;
+        }
+        finished.sort_by_key(|(watch, _)| watch.id);
+
+        let mut watchers = lock(watchers);
+        for (mut watch, reason) in finished {
+            // A watch whose time is up gets to emit what it's still holding back, unless emission
+            // is paused
+            if matches!(reason, RemovalReason::DurationElapsed) && !paused.load(Ordering::SeqCst) {
+                let released = watch.release_everything_held();
+                Self::send_messages(released.into_iter().collect(), output, batch_emit);
+            }
+            for (path, mode) in watch.registrations() {
+                if let Err(description) =
+                    Self::release_registration(&mut watchers, &watches, watch.backend, path, mode)
+                {
+                    let _ = output.send(Outgoing::WatcherError { description });
+                }
+            }
+            let _ = output.send(Outgoing::OkResponse {
+                request_id: watch.request_id,
+                unwatched_subdirs: Vec::new(),
+                affected_watches: None,
+                reason: Some(reason),
+                watch_id: Some(watch.id),
+            });
+            if lifecycle.load(Ordering::SeqCst) {
+                let detail = match reason {
+                    RemovalReason::LimitReached => "limit",
+                    RemovalReason::DurationElapsed => "duration",
+                };
+                let _ = output.send(Outgoing::lifecycle(
+                    LifecycleKind::WatchRemoved,
+                    Some(watch.id),
+                    detail,
+                ));
+            }
+        }
+    }
+
+    fn send_messages(messages: Vec<Outgoing>, output: &Sender<Outgoing>, batch_emit: bool) {
+        if batch_emit {
+            if !messages.is_empty() {
+                let _ = output.send(Outgoing::Batch { messages });
+            }
+        } else {
+            for message in messages {
+                let _ = output.send(message);
+            }
+        }
+    }
+
+    // Register the watches listed in a config file, followed by the one from `NOTIFY_WATCH_ROOT`.
+    // Each one is answered as if it were a `watch` request whose id is its index among them, and
+    // failures don't prevent the others from being registered.
+    fn preload(&mut self, specs: Vec<WatchSpec>) {
+        for (request_id, mut spec) in specs.into_iter().enumerate() {
+            if let Some(path_map) = &self.sink.path_map {
+                spec.map_roots(path_map);
+            }
+            self.watch(request_id, spec);
+        }
+    }
+
+    // Handle requests until either a `shutdown` request or the end of stdin, after which the
+    // supervisor has drained its events and is ready to be shut down. Every line is read into the
+    // same buffer rather than into a string of its own, since some clients send hundreds of
+    // requests at once. A line that has only partly arrived stays in the buffer until the rest of
+    // it does.
+    fn handle_requests(&mut self) {
+        let mut stdin = io::stdin().lock();
+        let mut line = Vec::new();
+        let binary = self.sink.protocol == Protocol::Binary;
+        let json_case = self.sink.json_case;
+        let mut next_request = move || -> Option<(Instant, Result<Incoming, Outgoing>)> {
+            // A request's latency starts once it starts arriving, rather than while waiting for it
+            let _ = stdin.fill_buf();
+            let read_at = Instant::now();
+            let request = if binary {
+                read_frame_request(&mut stdin, json_case)
+            } else {
+                read_request(&mut stdin, &mut line, json_case)
+            };
+            Some((read_at, request?))
+        };
+        while let Some((read_at, request)) = next_request() {
+            if let Some(latencies) = &self.sink.latencies {
+                let request_id = match &request {
+                    Ok(request) => Some(request.request_id()),
+                    Err(message) => message.request_id(),
+                };
+                if let Some(request_id) = request_id {
+                    lock(latencies).start(request_id, read_at);
+                }
+            }
+            match request {
+                Ok(request) => self.handle_request(request),
+                Err(message) => emit_json(&self.sink, message),
+            }
+            if self.stopping.load(Ordering::SeqCst) {
+                return;
+            }
+        }
+        self.drain(None, self.drain);
+    }
+
+    fn handle_request(&mut self, mut request: Incoming) {
+        if let Some(path_map) = &self.sink.path_map {
+            request.map_roots(path_map);
+        }
+
+        match request {
+            Incoming::Watch {
+                request_id,
+                mut spec,
+                inherit_from,
+            } => {
+                if let Some(parent_id) = inherit_from {
+                    match self.inherited_filters(request_id, parent_id) {
+                        Some(filters) => spec.options.inherit(filters),
+                        None => return,
+                    }
+                }
+                self.watch(request_id, spec)
+            }
+            Incoming::WatchFiles {
+                request_id,
+                watch_id,
+                files,
+                options,
+            } => self.watch_files(request_id, watch_id, files, options),
+            Incoming::Unwatch {
+                request_id,
+                watch_id,
+            } => self.unwatch(request_id, watch_id),
+            Incoming::UnwatchAll { request_id } => self.unwatch_all(request_id),
+            Incoming::SetWatches {
+                request_id,
+                watches,
+            } => self.set_watches(request_id, watches),
+            Incoming::Flush { request_id } => self.flush(request_id),
+            Incoming::Health { request_id } => self.health(request_id),
+            Incoming::PauseAll { request_id } => self.pause_all(request_id),
+            Incoming::ResumeAll { request_id } => self.resume_all(request_id),
+            Incoming::BufferedPause {
+                request_id,
+                watch_id,
+                max_buffer,
+            } => self.buffered_pause(request_id, watch_id, max_buffer),
+            Incoming::Resume {
+                request_id,
+                watch_id,
+            } => self.resume(request_id, watch_id),
+            Incoming::DebugQueue { request_id } => self.debug_queue(request_id),
+            Incoming::Latencies { request_id } => self.latencies(request_id),
+            Incoming::Time { request_id } => self.time(request_id),
+            Incoming::Version { request_id } => self.version(request_id),
+            Incoming::SystemInfo { request_id } => self.system_info(request_id),
+            Incoming::ExportConfig { request_id } => self.export_config(request_id),
+            Incoming::SubscribeLifecycle { request_id } => self.subscribe_lifecycle(request_id),
+            Incoming::SetDebounce {
+                request_id,
+                debounce_ms,
+            } => self.set_debounce(request_id, Duration::from_millis(debounce_ms)),
+            Incoming::SetWatchDebounce {
+                request_id,
+                watch_id,
+                debounce_ms,
+            } => self.set_watch_debounce(request_id, watch_id, debounce_ms),
+            Incoming::RenameWatch {
+                request_id,
+                watch_id,
+                new_watch_id,
+            } => self.rename_watch(request_id, watch_id, new_watch_id),
+            Incoming::TreeHash {
+                request_id,
+                watch_id,
+            } => self.tree_hash(request_id, watch_id),
+            Incoming::History {
+                request_id,
+                watch_id,
+            } => self.history(request_id, watch_id),
+            Incoming::Snapshot {
+                request_id,
+                watch_id,
+            } => self.snapshot(request_id, watch_id),
+            Incoming::Overlaps {
+                request_id,
+                watch_id,
+            } => self.overlaps(request_id, watch_id),
+            Incoming::EffectiveActions {
+                request_id,
+                watch_id,
+            } => self.effective_actions(request_id, watch_id),
+            Incoming::Shutdown {
+                request_id,
+                drain_ms,
+            } => {
+                let timeout = drain_ms.map_or(self.drain, Duration::from_millis);
+                self.drain(Some(request_id), timeout)
+            }
+            Incoming::IsWatched { request_id, path } => self.is_watched(request_id, path),
+            Incoming::WouldEmit {
+                request_id,
+                watch_id,
+                path,
+                action,
+            } => self.would_emit(request_id, watch_id, path, action),
+            Incoming::Ls {
+                request_id,
+                path,
+                include,
+                ignore,
+                extensions,
+            } => self.ls(
+                request_id,
+                path,
+                FilterProfile::matching(include, ignore, extensions),
+            ),
+            Incoming::Canonicalize { request_id, path } => self.canonicalize(request_id, path),
+            Incoming::AddActions {
+                request_id,
+                watch_id,
+                actions,
+            } => self.update_actions(request_id, watch_id, |current| current.extend(actions)),
+            Incoming::RemoveActions {
+                request_id,
+                watch_id,
+                actions,
+            } => self.update_actions(request_id, watch_id, |current| {
+                current.retain(|action| !actions.contains(action))
+            }),
+            Incoming::SetMaxEntries {
+                request_id,
+                watch_id,
+                max_entries,
+            } => self.set_max_entries(request_id, watch_id, max_entries),
+            Incoming::SetRecursive {
+                request_id,
+                watch_id,
+                recursive,
+            } => self.set_recursive(request_id, watch_id, recursive),
+            Incoming::ReloadRules {
+                request_id,
+                watch_id,
+                rules_file,
+                watch,
+            } => self.reload_rules(request_id, watch_id, rules_file, watch),
+        }
+    }
+
+    fn backend_for(&self, spec: &WatchSpec) -> Backend {
+        Backend::resolve(
+            spec.options.backend,
+            spec.options.poll_interval_ms,
+            lock(&self.watchers).default_backend(),
+        )
+    }
+
+    // When `since_ms` is given, the response is followed by events for the entries under the root
+    // that changed after that time, so that a client can catch up on changes it missed.
+    fn watch(&mut self, request_id: RequestId, spec: WatchSpec) {
+        let active = lock(&self.watches).len();
+        if let Some(error) = self
+            .check_watch_limit(request_id, active, active + 1)
+            .or_else(|| self.check_used_ids(request_id, [spec.watch_id]))
+        {
+            emit_json(&self.sink, error);
+            return;
+        }
+
+        if let Err(description) = spec.options.validate() {
+            emit_json(
+                &self.sink,
+                Outgoing::error_response(request_id, description),
+            );
+            return;
+        }
+
+        // This happens before taking the lock to avoid holding up event delivery. Requests are
+        // handled one at a time and the notify thread only ever removes watches, so the checks
+        // above still hold once it's taken.
+        let unwatched_subdirs = spec.unwatched_subdirs();
+
+        let mut watches = lock(&self.watches);
+        let (watch, changes) =
+            match self.start_watch(&watches, request_id, spec, &unwatched_subdirs) {
+                Ok(started) => started,
+                Err(description) => {
+                    emit_json(
+                        &self.sink,
+                        Outgoing::error_response(request_id, description),
+                    );
+                    return;
+                }
+            };
+        let watch_id = watch.id;
+        watches.push(watch);
+        if let Some(used_ids) = &mut self.used_ids {
+            used_ids.insert(watch_id);
+        }
+        emit_json(
+            &self.sink,
+            Outgoing::OkResponse {
+                request_id,
+                unwatched_subdirs,
+                affected_watches: None,
+                reason: None,
+                watch_id: None,
+            },
+        );
+        self.announce(LifecycleKind::WatchAdded, Some(watch_id), "watch");
+        if let Some(changes) = changes {
+            let _ = self.output.send(changes);
+        }
+    }
+
+    // Create the watch for `spec` and register it alongside `watches`, returning it along with
+    // the events catching up on what changed since its `since_ms`, if any. A root that doesn't
+    // exist yet is stood in for by its closest existing ancestor. Doesn't add the watch to
+    // `watches`, which is left to the caller.
+    fn start_watch(
+        &self,
+        watches: &[Watch],
+        request_id: RequestId,
+        spec: WatchSpec,
+        unwatched_subdirs: &[PathBuf],
+    ) -> Result<(Watch, Option<Outgoing>), String> {
+        let backend = self.backend_for(&spec);
+        let since_ms = spec.since_ms;
+        let mut watch = Watch::new(request_id, spec, backend);
+        watch.ancestor = existing_ancestor(&watch.root);
+        if let Err(error) = Self::register(
+            &mut lock(&self.watchers),
+            watches,
+            backend,
+            &watch.registrations(),
+        ) {
+            // Some backends refuse to watch a tree with unreadable directories at all
+            return Err(if unwatched_subdirs.is_empty() {
+                describe_error(&error)
+            } else {
+                format!(
+                    "{} (unreadable directories: {:?})",
+                    describe_error(&error),
+                    unwatched_subdirs
+                )
+            });
+        }
+
+        watch.start_tree_hash();
+        let changes = since_ms
+            .map(|since_ms| {
+                snapshot::changed_since(&watch.root, UNIX_EPOCH + Duration::from_millis(since_ms))
+            })
+            .and_then(|changes| watch.emit(changes));
+        Ok((watch, changes))
+    }
+
+    // Watch individual files without watching the rest of the directories that they're in.
+    fn watch_files(
+        &mut self,
+        request_id: RequestId,
+        watch_id: WatchId,
+        files: Vec<PathBuf>,
+        options: WatchOptions,
+    ) {
+        let root = match common_ancestor(&files) {
+            Some(root) => root,
+            None => {
+                let description = match files.iter().find(|file| file.parent().is_none()) {
+                    Some(file) => format!("Can't watch {:?} as a file", file),
+                    None => "No files to watch".to_string(),
+                };
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response(request_id, description),
+                );
+                return;
+            }
+        };
+
+        let mut watches = lock(&self.watches);
+
+        if let Some(error) = self
+            .check_watch_limit(request_id, watches.len(), watches.len() + 1)
+            .or_else(|| self.check_used_ids(request_id, [watch_id]))
+        {
+            emit_json(&self.sink, error);
+            return;
+        }
+
+        if let Err(description) = options.validate() {
+            emit_json(
+                &self.sink,
+                Outgoing::error_response(request_id, description),
+            );
+            return;
+        }
+
+        if options.listing_mode == Some(true) {
+            emit_json(
+                &self.sink,
+                Outgoing::error_response(
+                    request_id,
+                    "listingMode only applies to watches of directories".to_string(),
+                ),
+            );
+            return;
+        }
+
+        let spec = WatchSpec {
+            watch_id,
+            root,
+            since_ms: None,
+            options,
+        };
+        let backend = self.backend_for(&spec);
+        let mut watch = Watch::new(request_id, spec, backend);
+        watch.files = Some(files.into_iter().collect());
+        if let Err(error) = Self::register(
+            &mut lock(&self.watchers),
+            &watches,
+            backend,
+            &watch.registrations(),
+        ) {
+            emit_json(
+                &self.sink,
+                Outgoing::error_response(request_id, describe_error(&error)),
+            );
+            return;
+        }
+
+        watch.start_tree_hash();
+        watches.push(watch);
+        if let Some(used_ids) = &mut self.used_ids {
+            used_ids.insert(watch_id);
+        }
+        emit_json(&self.sink, Outgoing::ok_response(request_id));
+        self.announce(LifecycleKind::WatchAdded, Some(watch_id), "watchFiles");
+    }
+
+    fn unwatch(&mut self, request_id: RequestId, watch_id: WatchId) {
+        let mut watches = lock(&self.watches);
+
+        if let Some(i) = self.find_watch_index(&watches, request_id, watch_id) {
+            let removed = watches.remove(i);
+            let mut released = Ok(());
+            for (path, mode) in removed.registrations() {
+                let result = Self::release_registration(
+                    &mut lock(&self.watchers),
+                    &watches,
+                    removed.backend,
+                    path,
+                    mode,
+                );
+                released = released.and(result);
+            }
+            // Answer through the output thread while the watches are still locked, so that the
+            // response follows every event of the watch that the notify thread has queued
+            let response = match released {
+                Ok(()) => Outgoing::ok_response(request_id),
+                Err(description) => Outgoing::error_response(request_id, description),
+            };
+            let _ = self.output.send(response);
+            if self.lifecycle.load(Ordering::SeqCst) {
+                let _ = self.output.send(Outgoing::lifecycle(
+                    LifecycleKind::WatchRemoved,
+                    Some(watch_id),
+                    "unwatch",
+                ));
+            }
+        }
+    }
+
+    fn unwatch_all(&mut self, request_id: RequestId) {
+        let mut watches = lock(&self.watches);
+
+        let mut registrations: Vec<(Backend, PathBuf)> = effective_registrations(&watches)
+            .into_iter()
+            .map(|(backend, path, _)| (backend, path.to_path_buf()))
+            .collect();
+        let removed: Vec<WatchId> = watches.drain(..).map(|watch| watch.id).collect();
+        // Unwatch descendants before their ancestors, since on Linux unwatching a directory also
+        // destroys the watches on its descendants.
+        registrations.sort_by(|(_, a), (_, b)| b.cmp(a));
+        let mut watchers = lock(&self.watchers);
+        let mut response = Outgoing::ok_response(request_id);
+        for (backend, root) in registrations {
+            if let Err(error) = watchers
+                .get(backend)
+                .and_then(|watcher| watcher.unwatch(&root))
+            {
+                response = Outgoing::error_response(
+                    request_id,
+                    format!("Error unwatching {:?}: {:?}", root, error),
+                );
+                break;
+            }
+            watchers.release(backend);
+        }
+
+        // Answer through the output thread while the watches are still locked, so that the
+        // response follows every event that the notify thread has queued, as for `unwatch`
+        let _ = self.output.send(response);
+        if self.lifecycle.load(Ordering::SeqCst) {
+            for watch_id in removed {
+                let _ = self.output.send(Outgoing::lifecycle(
+                    LifecycleKind::WatchRemoved,
+                    Some(watch_id),
+                    "unwatchAll",
+                ));
+            }
+        }
+    }
+
+    // The response is sent from the notify thread once every event that was pending in the
+    // debouncer when the request arrived has been emitted.
+    fn flush(&mut self, request_id: RequestId) {
+        lock(&self.flushes).push(PendingFlush {
+            request_id: Some(request_id),
+            deadline: Instant::now() + lock(&self.watchers).max_delay() + FLUSH_MARGIN,
+        });
+    }
+
+    // Wait for up to `timeout` for the events pending when this was called to be emitted, as with
+    // `flush`, and then stop the notify thread. The response to a `shutdown` request is sent
+    // either way, after whatever events made it out in time.
+    fn drain(&mut self, request_id: Option<RequestId>, timeout: Duration) {
+        let now = Instant::now();
+        let give_up_at = now + timeout;
+        let deadline = give_up_at.min(now + lock(&self.watchers).max_delay() + FLUSH_MARGIN);
+        lock(&self.flushes).push(PendingFlush {
+            request_id,
+            deadline,
+        });
+
+        let is_pending = |flushes: &[PendingFlush]| {
+            flushes
+                .iter()
+                .any(|flush| flush.request_id == request_id && flush.deadline == deadline)
+        };
+        while is_pending(&lock(&self.flushes)) && Instant::now() < give_up_at {
+            thread::sleep(TICK_INTERVAL / 10);
+        }
+
+        // The notify thread may still acknowledge the flush up until it's removed here
+        let mut flushes = lock(&self.flushes);
+        if is_pending(&flushes) {
+            flushes.retain(|flush| flush.request_id != request_id || flush.deadline != deadline);
+            if let Some(request_id) = request_id {
+                let _ = self.output.send(Outgoing::ok_response(request_id));
+            }
+        }
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    // Wait for the notify thread to exit after `drain`, and for the output thread to write
+    // everything that was sent to it.
+    fn shut_down(self) {
+        let Supervisor {
+            notify_thread,
+            output,
+            output_thread,
+            ..
+        } = self;
+        join_within(notify_thread, STALL_THRESHOLD);
+        drop(output);
+        join_within(output_thread, STALL_THRESHOLD);
+        if let Some(event_log) = EVENT_LOG.get() {
+            event_log.close();
+        }
+    }
+
+    // Report whether the notify thread is still ticking, restarting it if it has exited.
+    fn health(&mut self, request_id: RequestId) {
+        let since_last_tick = lock(&self.last_tick).elapsed();
+        let exited = self.notify_thread.is_finished();
+        let notify_thread_alive = !exited && since_last_tick < STALL_THRESHOLD;
+
+        let mut restarted = false;
+        if exited {
+            if let Err(description) = self.restart_notify_thread() {
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response(request_id, description),
+                );
+                return;
+            }
+            restarted = true;
+            self.announce(LifecycleKind::NotifyThreadRestarted, None, "health");
+        }
+
+        emit_json(
+            &self.sink,
+            Outgoing::HealthResponse {
+                request_id,
+                notify_thread_alive,
+                last_tick_ms_ago: since_last_tick.as_millis() as u64,
+                restarted,
+            },
+        );
+    }
+
+    // notify's debouncer doesn't reveal what it's holding on to, so the best we can report is
+    // what it has already let go of: the events still waiting for the notify thread, plus the
+    // modifications held back for `trailing_quiet_ms` and the deletions held for `smart_renames`.
+    fn debug_queue(&mut self, request_id: RequestId) {
+        let held: usize = lock(&self.watches)
+            .iter()
+            .map(|watch| {
+                watch.held_modifications.len()
+                    + watch.held_deletions.len()
+                    + watch.held_for_trigger.len()
+                    + watch.coalescing.len()
+                    + watch
+                        .pause_buffer
+                        .as_ref()
+                        .map_or(0, |buffer| buffer.events.len())
+            })
+            .sum();
+        emit_json(
+            &self.sink,
+            Outgoing::QueueDepthResponse {
+                request_id,
+                pending: lock(&self.watchers).queued_events() + held,
+            },
+        );
+    }
+
+    // Summarize how long the last requests took, from reading each of them until its handling
+    // returned, not counting this one.
+    fn latencies(&mut self, request_id: RequestId) {
+        let response = match &self.sink.latencies {
+            Some(latencies) => {
+                let summary = lock(latencies).summary();
+                Outgoing::LatenciesResponse {
+                    request_id,
+                    p50_ms: summary.p50_ms,
+                    p95_ms: summary.p95_ms,
+                    max_ms: summary.max_ms,
+                    count: summary.count,
+                }
+            }
+            None => Outgoing::error_response(
+                request_id,
+                "Latencies are only measured with --measure-latency".to_string(),
+            ),
+        };
+        emit_json(&self.sink, response);
+    }
+
+    // Report the current time by both of the supervisor's clocks, for clients to compare them with
+    // their own. This is written right away rather than after the events queued before it.
+    fn time(&mut self, request_id: RequestId) {
+        emit_json(
+            &self.sink,
+            Outgoing::TimeResponse {
+                request_id,
+                monotonic_ms: self.started_at.elapsed().as_millis() as u64,
+                wall_ms: UNIX_EPOCH.elapsed().unwrap_or_default().as_millis() as u64,
+            },
+        );
+    }
+
+    // The notify version is embedded by the build script, from the lockfile.
+    fn version(&mut self, request_id: RequestId) {
+        emit_json(
+            &self.sink,
+            Outgoing::VersionResponse {
+                request_id,
+                crate_version: env!("CARGO_PKG_VERSION"),
+                protocol_version: PROTOCOL_VERSION,
+                notify_version: env!("NOTIFY_VERSION"),
+            },
+        );
+    }
+
+    // Report the inotify limits next to what this process uses of them, for capacity monitoring.
+    fn system_info(&mut self, request_id: RequestId) {
+        let capacity = WatchCapacity::read();
+        emit_json(
+            &self.sink,
+            Outgoing::SystemInfoResponse {
+                request_id,
+                max_user_watches: capacity.max_user_watches,
+                max_user_instances: capacity.max_user_instances,
+                current_instance_watches: capacity.current_instance_watches,
+            },
+        );
+    }
+
+    // Answer with the watches in the format of a config file, so that they can be registered again
+    // later with `--config`. Watches of individual files can't be expressed in a config file, so
+    // they're left out. The actions of each watch are the current ones, and a `limit` is whatever
+    // is left of it.
+    fn export_config(&mut self, request_id: RequestId) {
+        let now = Instant::now();
+        let mut watches: Vec<WatchSpec> = lock(&self.watches)
+            .iter()
+            .filter(|watch| watch.files.is_none())
+            .map(|watch| {
+                let mut options = watch.options.clone();
+                options.actions = watch.current_actions();
+                options.limit = options
+                    .limit
+                    .map(|limit| limit.saturating_sub(watch.emitted_events));
+                options.duration_ms = watch
+                    .expires_at
+                    .map(|expires_at| expires_at.saturating_duration_since(now).as_millis() as u64);
+                WatchSpec {
+                    watch_id: watch.id,
+                    root: watch.root.clone(),
+                    since_ms: None,
+                    options,
+                }
+            })
+            .collect();
+        watches.sort_by_key(|spec| spec.watch_id);
+        emit_json(
+            &self.sink,
+            Outgoing::ConfigResponse {
+                request_id,
+                watches,
+            },
+        );
+    }
+
+    // A snapshot of the filters of the watch with `watch_id`, for a new watch to inherit. Later
+    // changes to that watch, such as with `addActions`, don't carry over. Like `find_watch`, the
+    // request is answered with an error if there's no such watch.
+    fn inherited_filters(&self, request_id: RequestId, watch_id: WatchId) -> Option<WatchOptions> {
+        let mut watches = lock(&self.watches);
+        let watch = self.find_watch(&mut watches, request_id, watch_id)?;
+        Some(WatchOptions {
+            ignore_temp_files: watch.options.ignore_temp_files,
+            actions: watch.current_actions(),
+            content_match: watch.options.content_match.clone(),
+            // Already resolved against the watch's root, so that they keep applying to the same
+            // directories under a different one
+            profiles: watch.profiles.clone(),
+            min_size: watch.options.min_size,
+            max_size: watch.options.max_size,
+            filesystems: watch.options.filesystems.clone(),
+            modified_on: watch.options.modified_on.clone(),
+            created_only: watch.options.created_only,
+            ..WatchOptions::default()
+        })
+    }
+
+    // Start reporting changes to the supervisor's own state. Each report follows the response to
+    // the request that caused it.
+    fn subscribe_lifecycle(&mut self, request_id: RequestId) {
+        self.lifecycle.store(true, Ordering::SeqCst);
+        emit_json(&self.sink, Outgoing::ok_response(request_id));
+    }
+
+    fn tree_hash(&mut self, request_id: RequestId, watch_id: WatchId) {
+        let mut watches = lock(&self.watches);
+        let watch = match self.find_watch(&mut watches, request_id, watch_id) {
+            Some(watch) => watch,
+            None => return,
+        };
+        let response = match &watch.tree_hash {
+            Some(tree_hash) => Outgoing::TreeHashResponse {
+                request_id,
+                watch_id,
+                hash: tree_hash.hash(),
+            },
+            None => Outgoing::error_response(
+                request_id,
+                format!("Watch {:?} wasn't created with treeHash", watch_id),
+            ),
+        };
+        emit_json(&self.sink, response);
+    }
+
+    fn history(&mut self, request_id: RequestId, watch_id: WatchId) {
+        let mut watches = lock(&self.watches);
+        let watch = match self.find_watch(&mut watches, request_id, watch_id) {
+            Some(watch) => watch,
+            None => return,
+        };
+        let response = if watch.options.history.is_some() {
+            Outgoing::HistoryResponse {
+                request_id,
+                watch_id,
+                events: watch.history.iter().cloned().collect(),
+            }
+        } else {
+            Outgoing::error_response(
+                request_id,
+                format!("Watch {:?} wasn't created with history", watch_id),
+            )
+        };
+        emit_json(&self.sink, response);
+    }
+
+    // Answer with the other watches that the watch with `watch_id` lies within, and those that lie
+    // within it, judged by their roots and files as they were given.
+    fn overlaps(&mut self, request_id: RequestId, watch_id: WatchId) {
+        let watches = lock(&self.watches);
+        let watch = match self.find_watch_index(&watches, request_id, watch_id) {
+            Some(i) => &watches[i],
+            None => return,
+        };
+        let others = || watches.iter().filter(|other| other.id != watch_id);
+        let mut contains: Vec<WatchId> = others()
+            .filter(|other| watch.covers(other))
+            .map(|other| other.id)
+            .collect();
+        let mut contained_by: Vec<WatchId> = others()
+            .filter(|other| other.covers(watch))
+            .map(|other| other.id)
+            .collect();
+        contains.sort_unstable();
+        contained_by.sort_unstable();
+        emit_json(
+            &self.sink,
+            Outgoing::OverlapsResponse {
+                request_id,
+                watch_id,
+                contains,
+                contained_by,
+            },
+        );
+    }
+
+    // Answer with the current state of every entry the watch would report on.
+    fn snapshot(&mut self, request_id: RequestId, watch_id: WatchId) {
+        let mut watches = lock(&self.watches);
+        if let Some(watch) = self.find_watch(&mut watches, request_id, watch_id) {
+            emit_json(
+                &self.sink,
+                Outgoing::SnapshotResponse {
+                    request_id,
+                    watch_id,
+                    entries: watch.snapshot(),
+                },
+            );
+        }
+    }
+
+    // Answer with the actions the watch's events can currently have, once every option that
+    // filters by action has been applied.
+    fn effective_actions(&mut self, request_id: RequestId, watch_id: WatchId) {
+        let mut watches = lock(&self.watches);
+        if let Some(watch) = self.find_watch(&mut watches, request_id, watch_id) {
+            emit_json(
+                &self.sink,
+                Outgoing::ActionsResponse {
+                    request_id,
+                    actions: watch.effective_actions(),
+                },
+            );
+        }
+    }
+
+    // The index of the watch with `watch_id` among `watches`, or, if there's none, `None` once the
+    // request has been answered with an error.
+    fn find_watch_index(
+        &self,
+        watches: &[Watch],
+        request_id: RequestId,
+        watch_id: WatchId,
+    ) -> Option<usize> {
+        let i = watches.iter().position(|watch| watch.id == watch_id);
+        if i.is_none() {
+            emit_json(
+                &self.sink,
+                Outgoing::error_response(
+                    request_id,
+                    format!("No watch found for id: {:?}", watch_id),
+                ),
+            );
+        }
+        i
+    }
+
+    // The watch with `watch_id`, like `find_watch_index`.
+    fn find_watch<'a>(
+        &self,
+        watches: &'a mut [Watch],
+        request_id: RequestId,
+        watch_id: WatchId,
+    ) -> Option<&'a mut Watch> {
+        let i = self.find_watch_index(watches, request_id, watch_id)?;
+        Some(&mut watches[i])
+    }
+
+    fn announce(&self, kind: LifecycleKind, watch_id: Option<WatchId>, detail: &str) {
+        if self.lifecycle.load(Ordering::SeqCst) {
+            emit_json(&self.sink, Outgoing::lifecycle(kind, watch_id, detail));
+        }
+    }
+
+    // Answer with `path` with its symlinks resolved, the way the backends report paths, or why it
+    // couldn't be resolved. Unlike for `is_watched`, the path has to exist.
+    fn canonicalize(&mut self, request_id: RequestId, path: PathBuf) {
+        let input = path.clone();
+        let (canonical, error) =
+            match within_canonicalize_timeout(self.canonicalize_timeout, move || {
+                fs::canonicalize(path)
+            }) {
+                Some(Ok(canonical)) => (Some(canonical), None),
+                Some(Err(error)) => (None, Some(error.to_string())),
+                None => {
+                    emit_json(
+                        &self.sink,
+                        Outgoing::error_response_with_code(
+                            request_id,
+                            ErrorCode::Timeout,
+                            format!("Timed out resolving {:?}", input),
+                        ),
+                    );
+                    return;
+                }
+            };
+        emit_json(
+            &self.sink,
+            Outgoing::CanonicalResponse {
+                request_id,
+                input,
+                canonical,
+                error,
+            },
+        );
+    }
+
+    // Answer with the watches that would emit events for `path`, which is resolved the way the
+    // backends report paths so that it can be compared with their roots.
+    fn is_watched(&mut self, request_id: RequestId, path: PathBuf) {
+        let path = match resolve_path_in_time(self.canonicalize_timeout, &path) {
+            Some(path) => path,
+            None => {
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response_with_code(
+                        request_id,
+                        ErrorCode::Timeout,
+                        format!("Timed out resolving {:?}", path),
+                    ),
+                );
+                return;
+            }
+        };
+        let mut watch_ids: Vec<WatchId> = lock(&self.watches)
+            .iter()
+            .filter(|watch| watch.would_emit(&path))
+            .map(|watch| watch.id)
+            .collect();
+        watch_ids.sort_unstable();
+        emit_json(
+            &self.sink,
+            Outgoing::CoverageResponse {
+                request_id,
+                path,
+                watch_ids,
+            },
+        );
+    }
+
+    // Answer with everything under `path` that `filter` allows, as a watch with it as its only
+    // profile would see it, without watching anything. `path` is resolved like for `is_watched`.
+    fn ls(&mut self, request_id: RequestId, path: PathBuf, filter: FilterProfile) {
+        let path = match resolve_path_in_time(self.canonicalize_timeout, &path) {
+            Some(path) => path,
+            None => {
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response_with_code(
+                        request_id,
+                        ErrorCode::Timeout,
+                        format!("Timed out resolving {:?}", path),
+                    ),
+                );
+                return;
+            }
+        };
+        let filter = [filter.resolve(&path)];
+        let entries = snapshot::paths(&path, |entry| profiles::allows(&filter, entry));
+        emit_json(
+            &self.sink,
+            Outgoing::LsResponse {
+                request_id,
+                path,
+                entries,
+            },
+        );
+    }
+
+    // Answer whether the watch with `watch_id` would emit an event with `action` for `path`, and if
+    // not, which of its options is in the way. `path` is resolved like for `is_watched`.
+    fn would_emit(
+        &mut self,
+        request_id: RequestId,
+        watch_id: WatchId,
+        path: PathBuf,
+        action: Action,
+    ) {
+        let path = match resolve_path_in_time(self.canonicalize_timeout, &path) {
+            Some(path) => path,
+            None => {
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response_with_code(
+                        request_id,
+                        ErrorCode::Timeout,
+                        format!("Timed out resolving {:?}", path),
+                    ),
+                );
+                return;
+            }
+        };
+        let mut watches = lock(&self.watches);
+        if let Some(watch) = self.find_watch(&mut watches, request_id, watch_id) {
+            let reason = watch.rejected_by(&path, Some(action));
+            emit_json(
+                &self.sink,
+                Outgoing::WouldEmitResponse {
+                    request_id,
+                    emit: reason.is_none(),
+                    reason,
+                },
+            );
+        }
+    }
+
+    // Stop emitting events for every watch while leaving the roots registered, so that nothing
+    // has to be set up again on resuming.
+    fn pause_all(&mut self, request_id: RequestId) {
+        let was_paused = self.paused.swap(true, Ordering::SeqCst);
+        let affected_watches = if was_paused {
+            0
+        } else {
+            lock(&self.watches).len()
+        };
+        emit_json(
+            &self.sink,
+            Outgoing::OkResponse {
+                request_id,
+                unwatched_subdirs: Vec::new(),
+                affected_watches: Some(affected_watches),
+                reason: None,
+                watch_id: None,
+            },
+        );
+    }
+
+    // Start emitting events again after `pause_all`. Everything that happened in the meantime
+    // was dropped, so each watch is told to rescan its root.
+    fn resume_all(&mut self, request_id: RequestId) {
+        let mut watches = lock(&self.watches);
+        let was_paused = self.paused.swap(false, Ordering::SeqCst);
+        let rescans: Vec<Outgoing> = if was_paused {
+            watches_by_id(&mut watches)
+                .into_iter()
+                .filter_map(|watch| {
+                    let rescans = watch.rescans();
+                    watch.emit(rescans)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        emit_json(
+            &self.sink,
+            Outgoing::OkResponse {
+                request_id,
+                unwatched_subdirs: Vec::new(),
+                affected_watches: Some(if was_paused { watches.len() } else { 0 }),
+                reason: None,
+                watch_id: None,
+            },
+        );
+        for rescan in rescans {
+            let _ = self.output.send(rescan);
+        }
+    }
+
+    // Keep the events that a watch would emit, up to `max_buffer` of them, until it's resumed.
+    // The watch goes on filtering events as usual in the meantime. Does nothing to a watch whose
+    // events are already being kept.
+    fn buffered_pause(&mut self, request_id: RequestId, watch_id: WatchId, max_buffer: usize) {
+        let mut watches = lock(&self.watches);
+        if let Some(watch) = self.find_watch(&mut watches, request_id, watch_id) {
+            if watch.pause_buffer.is_none() {
+                watch.pause_buffer = Some(PauseBuffer::new(max_buffer));
+            }
+            // Answer through the output thread while the watches are still locked, so that every
+            // event after the response is one that was kept
+            let _ = self.output.send(Outgoing::ok_response(request_id));
+        }
+    }
+
+    // Start emitting a watch's events again after `buffered_pause`, beginning with the ones it
+    // kept. If it couldn't keep all of them, those that fit are followed by its rescans.
+    fn resume(&mut self, request_id: RequestId, watch_id: WatchId) {
+        let mut watches = lock(&self.watches);
+        let watch = match self.find_watch(&mut watches, request_id, watch_id) {
+            Some(watch) => watch,
+            None => return,
+        };
+        let mut messages = Vec::new();
+        if let Some(buffer) = watch.pause_buffer.take() {
+            if !buffer.events.is_empty() {
+                messages.push(Outgoing::WatchEvents {
+                    watch_id,
+                    events: buffer.events,
+                });
+            }
+            if buffer.overflowed {
+                let rescans = watch.rescans();
+                messages.extend(watch.emit(rescans));
+            }
+        }
+        let _ = self.output.send(Outgoing::ok_response(request_id));
+        Self::send_messages(messages, &self.output, self.batch_emit);
+    }
+
+    // Replace the watchers with ones whose native backend debounces for `debounce`, registering
+    // every root on them before the old ones are dropped. Events that the old debouncers were
+    // still holding on to are lost, so every watch is told to rescan afterwards, unless emission
+    // is paused, in which case resuming does that instead.
+    fn set_debounce(&mut self, request_id: RequestId, debounce: Duration) {
+        let mut watches = lock(&self.watches);
+        let mut watchers = lock(&self.watchers);
+
+        let mut replacement = match watchers.with_native_delay(debounce) {
+            Ok(replacement) => replacement,
+            Err(error) => {
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response(request_id, describe_error(&error)),
+                );
+                return;
+            }
+        };
+        for (backend, path, mode) in effective_registrations(&watches) {
+            if let Err(error) = replacement
+                .get(backend)
+                .and_then(|watcher| watcher.watch(path, mode))
+            {
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response(
+                        request_id,
+                        format!("Error watching {:?}: {}", path, describe_error(&error)),
+                    ),
+                );
+                return;
+            }
+        }
+        *watchers = replacement;
+        drop(watchers);
+
+        let rescans: Vec<Outgoing> = if self.paused.load(Ordering::SeqCst) {
+            Vec::new()
+        } else {
+            watches_by_id(&mut watches)
+                .into_iter()
+                .filter_map(|watch| {
+                    let rescans = watch.rescans();
+                    watch.emit(rescans)
+                })
+                .collect()
+        };
+        emit_json(&self.sink, Outgoing::ok_response(request_id));
+        for rescan in rescans {
+            let _ = self.output.send(rescan);
+        }
+    }
+
+    // Give a watch a different id, keeping everything else about it. The response goes through
+    // the output thread while the watches are locked, so that the events queued before it carry
+    // the old id and everything after it the new one.
+    fn rename_watch(&mut self, request_id: RequestId, watch_id: WatchId, new_watch_id: WatchId) {
+        let mut watches = lock(&self.watches);
+        let response =
+            if new_watch_id != watch_id && watches.iter().any(|watch| watch.id == new_watch_id) {
+                Outgoing::error_response(
+                    request_id,
+                    format!("A watch with id {:?} already exists", new_watch_id),
+                )
+            } else if let Some(error) = self.check_used_ids(
+                request_id,
+                Some(new_watch_id).filter(|new_watch_id| *new_watch_id != watch_id),
+            ) {
+                error
+            } else {
+                match self.find_watch(&mut watches, request_id, watch_id) {
+                    Some(watch) => {
+                        watch.id = new_watch_id;
+                        if let Some(used_ids) = &mut self.used_ids {
+                            used_ids.insert(new_watch_id);
+                        }
+                        Outgoing::ok_response(request_id)
+                    }
+                    None => return,
+                }
+            };
+        let _ = self.output.send(response);
+    }
+
+    // Move a watch to a native watcher of its own that debounces for `debounce_ms`, or back to the
+    // watcher its options ask for when that's `None`. The root is registered with the new watcher
+    // before the old one lets go of it, but events that the old debouncer was still holding on to
+    // are lost, so the watch is told to rescan afterwards unless emission is paused.
+    fn set_watch_debounce(
+        &mut self,
+        request_id: RequestId,
+        watch_id: WatchId,
+        debounce_ms: Option<u64>,
+    ) {
+        let mut watches = lock(&self.watches);
+        let mut watchers = lock(&self.watchers);
+
+        let i = match self.find_watch_index(&watches, request_id, watch_id) {
+            Some(i) => i,
+            None => return,
+        };
+        let options = &watches[i].options;
+        let regular = Backend::resolve(
+            options.backend,
+            options.poll_interval_ms,
+            watchers.default_backend(),
+        );
+        let backend = match debounce_ms {
+            None => regular,
+            Some(_) if !regular.is_native() => {
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response(
+                        request_id,
+                        format!("Watch {:?} is polled, which isn't debounced", watch_id),
+                    ),
+                );
+                return;
+            }
+            Some(debounce_ms) if Duration::from_millis(debounce_ms) == watchers.native_delay() => {
+                Backend::Native
+            }
+            Some(debounce_ms) => Backend::Debounced { debounce_ms },
+        };
+        let previous = watches[i].backend;
+        if backend == previous {
+            emit_json(&self.sink, Outgoing::ok_response(request_id));
+            return;
+        }
+
+        let released = match Self::move_to_backend(&mut watchers, &mut watches, i, backend) {
+            Ok(released) => released,
+            Err(error) => {
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response(request_id, describe_error(&error)),
+                );
+                return;
+            }
+        };
+        drop(watchers);
+
+        let rescans = if self.paused.load(Ordering::SeqCst) {
+            None
+        } else {
+            let rescans = watches[i].rescans();
+            watches[i].emit(rescans)
+        };
+        match released {
+            Ok(()) => emit_json(&self.sink, Outgoing::ok_response(request_id)),
+            Err(description) => emit_json(
+                &self.sink,
+                Outgoing::error_response(request_id, description),
+            ),
+        }
+        if let Some(rescans) = rescans {
+            let _ = self.output.send(rescans);
+        }
+    }
+
+    // Change which actions a watch emits in place, replying with the resulting set.
+    fn update_actions(
+        &mut self,
+        request_id: RequestId,
+        watch_id: WatchId,
+        update: impl FnOnce(&mut BTreeSet<Action>),
+    ) {
+        let mut watches = lock(&self.watches);
+
+        if let Some(watch) = self.find_watch(&mut watches, request_id, watch_id) {
+            update(&mut watch.actions);
+            emit_json(
+                &self.sink,
+                Outgoing::ActionsResponse {
+                    request_id,
+                    actions: watch.actions.iter().cloned().collect(),
+                },
+            );
+        }
+    }
+
+    // Switch a watch of a directory between watching its whole tree and only the root's direct
+    // children, keeping its id and everything else about it. The root is registered with the new
+    // mode before the old registration is released, but releasing a recursive registration takes
+    // the root's own watch down with it on some backends, so events can be lost in between and
+    // the watch is told to rescan afterwards unless emission is paused.
+    fn set_recursive(&mut self, request_id: RequestId, watch_id: WatchId, recursive: bool) {
+        let mut watches = lock(&self.watches);
+        let mut watchers = lock(&self.watchers);
+
+        let i = match self.find_watch_index(&watches, request_id, watch_id) {
+            Some(i) => i,
+            None => return,
+        };
+        if watches[i].files.is_some() || watches[i].options.listing_mode == Some(true) {
+            emit_json(
+                &self.sink,
+                Outgoing::error_response(
+                    request_id,
+                    format!("Watch {:?} doesn't watch a directory tree", watch_id),
+                ),
+            );
+            return;
+        }
+        if watches[i].is_recursive() == recursive {
+            emit_json(&self.sink, Outgoing::ok_response(request_id));
+            return;
+        }
+
+        let owned = |watch: &Watch| -> Vec<(PathBuf, RecursiveMode)> {
+            watch
+                .registrations()
+                .into_iter()
+                .map(|(path, mode)| (path.to_path_buf(), mode))
+                .collect()
+        };
+        let previous = watches[i].options.recursive;
+        let old = owned(&watches[i]);
+        watches[i].options.recursive = Some(recursive);
+        let new = owned(&watches[i]);
+        // Registering is done with the old mode still in place, so that the watch's own
+        // registration doesn't count as covering the new one
+        watches[i].options.recursive = previous;
+        let backend = watches[i].backend;
+        let new_refs: Vec<(&Path, RecursiveMode)> = new
+            .iter()
+            .map(|(path, mode)| (path.as_path(), *mode))
+            .collect();
+        if let Err(error) = Self::register(&mut watchers, &watches, backend, &new_refs) {
+            emit_json(
+                &self.sink,
+                Outgoing::error_response(request_id, describe_error(&error)),
+            );
+            return;
+        }
+        watches[i].options.recursive = Some(recursive);
+        watches[i].start_tree_hash();
+        let mut released = Ok(());
+        for (path, mode) in old
+            .iter()
+            .filter(|registration| !new.contains(registration))
+        {
+            let result = Self::release_registration(&mut watchers, &watches, backend, path, *mode);
+            released = released.and(result);
+        }
+        drop(watchers);
+
+        let rescans = if self.paused.load(Ordering::SeqCst) {
+            None
+        } else {
+            let rescans = watches[i].rescans();
+            watches[i].emit(rescans)
+        };
+        match released {
+            Ok(()) => emit_json(&self.sink, Outgoing::ok_response(request_id)),
+            Err(description) => emit_json(
+                &self.sink,
+                Outgoing::error_response(request_id, description),
+            ),
+        }
+        if let Some(rescans) = rescans {
+            let _ = self.output.send(rescans);
+        }
+    }
+
+    // Replace the patterns the watch ignores with the ones in `rules_file`. With `watch`, the
+    // directory the file is in is registered as well, unless the watch already covers it, and
+    // the rules are read again whenever the file changes.
+    fn reload_rules(
+        &mut self,
+        request_id: RequestId,
+        watch_id: WatchId,
+        rules_file: PathBuf,
+        watch: bool,
+    ) {
+        let mut watches = lock(&self.watches);
+        let mut watchers = lock(&self.watchers);
+
+        let i = match self.find_watch_index(&watches, request_id, watch_id) {
+            Some(i) => i,
+            None => return,
+        };
+        let rules = match watches[i].read_rules(&rules_file) {
+            Ok(rules) => rules,
+            Err(description) => {
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response(request_id, description),
+                );
+                return;
+            }
+        };
+
+        let backend = watches[i].backend;
+        let previous = watches[i].rules_registration();
+        let rules_file = watch.then_some(rules_file);
+        if let Some(dir) = rules_file.as_deref().and_then(Path::parent) {
+            let wanted = [(dir, RecursiveMode::NonRecursive)];
+            if let Err(error) = Self::register(&mut watchers, &watches, backend, &wanted) {
+                emit_json(
+                    &self.sink,
+                    Outgoing::error_response(request_id, describe_error(&error)),
+                );
+                return;
+            }
+        }
+        watches[i].set_rules(rules);
+        watches[i].rules_file = rules_file;
+
+        let released = match previous {
+            Some(dir) if watches[i].rules_registration().as_ref() != Some(&dir) => {
+                let mode = RecursiveMode::NonRecursive;
+                Self::release_registration(&mut watchers, &watches, backend, &dir, mode)
+            }
+            _ => Ok(()),
+        };
+        let response = match released {
+            Ok(()) => Outgoing::ok_response(request_id),
+            Err(description) => Outgoing::error_response(request_id, description),
+        };
+        emit_json(&self.sink, response);
+    }
+
+    // Change the watch's `max_entries`, such as to raise it once it was exceeded. The paths it has
+    // emitted events for so far keep counting against the new limit.
+    fn set_max_entries(
+        &mut self,
+        request_id: RequestId,
+        watch_id: WatchId,
+        max_entries: Option<usize>,
+    ) {
+        let mut watches = lock(&self.watches);
+
+        if let Some(watch) = self.find_watch(&mut watches, request_id, watch_id) {
+            watch.options.max_entries = max_entries;
+            watch.entries_exceeded = false;
+            if max_entries.is_none() {
+                watch.seen_entries.clear();
+            }
+            emit_json(&self.sink, Outgoing::ok_response(request_id));
+        }
+    }
+
+    // Replace the current watches with the given set. Watches whose id, root, backend, and options
+    // are unchanged are left alone, and those that changed are replaced by a new watch under the
+    // same id. New watches are added as `watch` adds them, and their roots are watched before old
+    // ones are released so that no events are lost for roots that appear in both sets.
+    fn set_watches(&mut self, request_id: RequestId, specs: Vec<WatchSpec>) {
+        let mut ids = HashSet::new();
+        if let Some(spec) = specs.iter().find(|spec| !ids.insert(spec.watch_id)) {
+            emit_json(
+                &self.sink,
+                Outgoing::error_response(
+                    request_id,
+                    format!("Duplicate watch id: {:?}", spec.watch_id),
+                ),
+            );
+            return;
+        }
+
+        let mut watches = lock(&self.watches);
+
+        let (kept, removed): (Vec<&Watch>, Vec<&Watch>) = watches.iter().partition(|watch| {
+            specs.iter().any(|spec| {
+                spec.watch_id == watch.id
+                    && watch.is_registered(self.backend_for(spec), &spec.root)
+                    && spec.options == watch.options
+            })
+        });
+        let kept: Vec<WatchId> = kept.into_iter().map(|watch| watch.id).collect();
+        let removed: Vec<WatchId> = removed.into_iter().map(|watch| watch.id).collect();
+        let added: Vec<WatchSpec> = specs
+            .into_iter()
+            .filter(|spec| !kept.contains(&spec.watch_id))
+            .collect();
+        let added_ids: Vec<WatchId> = added.iter().map(|spec| spec.watch_id).collect();
+
+        if let Some(error) = self
+            .check_watch_limit(request_id, watches.len(), kept.len() + added.len())
+            .or_else(|| {
+                // A watch that's replaced keeps its id
+                self.check_used_ids(
+                    request_id,
+                    added_ids.iter().copied().filter(|id| !removed.contains(id)),
+                )
+            })
+        {
+            emit_json(&self.sink, error);
+            return;
+        }
+        if let Some(description) = added.iter().find_map(|spec| spec.options.validate().err()) {
+            emit_json(
+                &self.sink,
+                Outgoing::error_response(request_id, description),
+            );
+            return;
+        }
+
+        // The new watches are registered alongside the current ones, so that the roots they share
+        // are covered throughout
+        let previous = watches.len();
+        let mut unwatched_subdirs = Vec::new();
+        let mut catch_up = Vec::new();
+        for spec in added {
+            let subdirs = spec.unwatched_subdirs();
+            match self.start_watch(&watches, request_id, spec, &subdirs) {
+                Ok((watch, changes)) => {
+                    watches.push(watch);
+                    unwatched_subdirs.extend(subdirs);
+                    catch_up.extend(changes);
+                }
+                Err(description) => {
+                    let undone = watches.split_off(previous);
+                    let mut watchers = lock(&self.watchers);
+                    for watch in undone.iter().rev() {
+                        for (path, mode) in watch.registrations() {
+                            let _ = Self::release_registration(
+                                &mut watchers,
+                                &watches,
+                                watch.backend,
+                                path,
+                                mode,
+                            );
+                        }
+                    }
+                    emit_json(
+                        &self.sink,
+                        Outgoing::error_response(request_id, description),
+                    );
+                    return;
+                }
+            }
+        }
+
+        if let Some(used_ids) = &mut self.used_ids {
+            used_ids.extend(&added_ids);
+        }
+        let started = watches.split_off(previous);
+        let (retained, stale): (Vec<Watch>, Vec<Watch>) = std::mem::take(&mut *watches)
+            .into_iter()
+            .partition(|watch| kept.contains(&watch.id));
+        *watches = retained;
+        watches.extend(started);
+
+        // A failure to release one of the stale roots doesn't undo the change, so it's reported
+        // along with it rather than instead of it
+        let mut errors = Vec::new();
+        let mut watchers = lock(&self.watchers);
+        for watch in &stale {
+            for (path, mode) in watch.registrations() {
+                if let Err(description) =
+                    Self::release_registration(&mut watchers, &watches, watch.backend, path, mode)
+                {
+                    errors.push(description);
+                }
+            }
+        }
+        drop(watchers);
+
+        // Answer through the output thread while the watches are still locked, as `unwatch` does,
+        // so that the response follows every event of the removed watches
+        let _ = self.output.send(Outgoing::DiffResponse {
+            request_id,
+            added: added_ids.clone(),
+            removed: removed.clone(),
+            kept,
+            unwatched_subdirs,
+        });
+        for description in errors {
+            let _ = self.output.send(Outgoing::WatcherError { description });
+        }
+        if self.lifecycle.load(Ordering::SeqCst) {
+            for (kind, watch_ids) in [
+                (LifecycleKind::WatchRemoved, removed),
+                (LifecycleKind::WatchAdded, added_ids),
+            ] {
+                for watch_id in watch_ids {
+                    let _ =
+                        self.output
+                            .send(Outgoing::lifecycle(kind, Some(watch_id), "setWatches"));
+                }
+            }
+        }
+        for changes in catch_up {
+            let _ = self.output.send(changes);
+        }
+    }
+
+    // Produce an error response if going from `active` to `requested` watches would exceed
+    // `--max-watches`.
+    fn check_watch_limit(
+        &self,
+        request_id: RequestId,
+        active: usize,
+        requested: usize,
+    ) -> Option<Outgoing> {
+        match self.max_watches {
+            Some(max_watches) if requested > max_watches => {
+                Some(Outgoing::error_response_with_code(
+                    request_id,
+                    ErrorCode::WatchLimitExceeded,
+                    format!(
+                        "Watch limit exceeded: {} watches are active and the limit is {}",
+                        active, max_watches
+                    ),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    // Produce an error response if any of `watch_ids` was used before, with `--single-use-ids`.
+    fn check_used_ids(
+        &self,
+        request_id: RequestId,
+        watch_ids: impl IntoIterator<Item = WatchId>,
+    ) -> Option<Outgoing> {
+        let used_ids = self.used_ids.as_ref()?;
+        let watch_id = watch_ids.into_iter().find(|id| used_ids.contains(id))?;
+        Some(Outgoing::error_response_with_code(
+            request_id,
+            ErrorCode::IdRetired,
+            format!("The watch id {:?} has already been used", watch_id),
+        ))
+    }
+
+    // Register the roots of `watches[i]` on `backend` before releasing them on its current one.
+    // Fails without changing anything if `backend` can't watch them, and otherwise returns
+    // whether the old registrations could be released.
+    fn move_to_backend(
+        watchers: &mut Watchers,
+        watches: &mut [Watch],
+        i: usize,
+        backend: Backend,
+    ) -> Result<Result<(), String>, notify::Error> {
+        let previous = watches[i].backend;
+        let wanted: Vec<(PathBuf, RecursiveMode)> = watches[i]
+            .registrations()
+            .into_iter()
+            .map(|(path, mode)| (path.to_path_buf(), mode))
+            .collect();
+        let wanted_refs: Vec<(&Path, RecursiveMode)> = wanted
+            .iter()
+            .map(|(path, mode)| (path.as_path(), *mode))
+            .collect();
+        Self::register(watchers, watches, backend, &wanted_refs)?;
+        watches[i].backend = backend;
+        let mut released = Ok(());
+        for &(path, mode) in &wanted_refs {
+            let result = Self::release_registration(watchers, watches, previous, path, mode);
+            released = released.and(result);
+        }
+        Ok(released)
+    }
+
+    // Register whichever of `wanted` aren't already covered by the registrations of `watches`,
+    // undoing them all if any of them fails.
+    fn register(
+        watchers: &mut Watchers,
+        watches: &[Watch],
+        backend: Backend,
+        wanted: &[(&Path, RecursiveMode)],
+    ) -> Result<(), notify::Error> {
+        let mut registered = registrations(watches, backend);
+        let mut newly_registered = Vec::new();
+        for &(path, mode) in wanted {
+            if is_covered(&registered, path, mode) {
+                continue;
+            }
+            if let Err(error) = watchers
+                .get(backend)
+                .and_then(|watcher| watcher.watch(path, mode))
+            {
+                // A recursive watch can fail partway through the tree, after some of its
+                // directories were registered, so the failed path is released as well. That fails
+                // harmlessly if nothing was registered for it.
+                newly_registered.push((path, mode));
+                for (path, mode) in newly_registered.into_iter().rev() {
+                    let _ = Self::release_registration(watchers, watches, backend, path, mode);
+                }
+                return Err(error);
+            }
+            registered.push((path, mode));
+            newly_registered.push((path, mode));
+        }
+        Ok(())
+    }
+
+    // Stop watching `path` with `backend` if none of the remaining `watches` still need it, and
+    // stop the backend's watcher altogether once nothing uses it.
+    fn release_registration(
+        watchers: &mut Watchers,
+        watches: &[Watch],
+        backend: Backend,
+        path: &Path,
+        mode: RecursiveMode,
+    ) -> Result<(), String> {
+        let remaining = registrations(watches, backend);
+        if is_covered(&remaining, path, mode) {
+            return Ok(());
+        }
+
+        let watcher = watchers
+            .get(backend)
+            .map_err(|error| format!("Error unwatching: {:?}", error))?;
+        let unwatch_result = watcher.unwatch(path);
+
+        // On Linux, unwatching a directory seems to destroy all watches on descendant
+        // directories, so we rewatch any descendant directories that are being monitored.
+        // Elsewhere only the directory itself is lost, which leaves nothing watching the
+        // directories beneath it that were only covered by it.
+        let destroys_descendants = cfg!(target_os = "linux") && backend.is_native();
+        let (mut lost, mut intact): (Vec<_>, Vec<_>) =
+            remaining
+                .into_iter()
+                .partition(|(registered, registered_mode)| {
+                    registered.starts_with(path)
+                        && (destroys_descendants || *registered_mode == RecursiveMode::NonRecursive)
+                });
+        lost.sort_by_key(|(_, mode)| *mode);
+        for (registered, registered_mode) in lost {
+            if is_covered(&intact, registered, registered_mode) {
+                continue;
+            }
+            if let Err(error) = watcher.watch(registered, registered_mode) {
+                return Err(format!(
+                    "Error re-watching descendant of unwatched directory: {:?}",
+                    error
+                ));
+            }
+            intact.push((registered, registered_mode));
+        }
+
+        if !watches.iter().any(|watch| watch.backend == backend) {
+            watchers.release(backend);
+        }
+
+        unwatch_result.map_err(|error| format!("Error unwatching: {:?}", error))
+    }
+}
+
+impl Watch {
+    fn new(request_id: RequestId, spec: WatchSpec, backend: Backend) -> Self {
+        let profiles = spec
+            .options
+            .profiles
+            .iter()
+            .map(|profile| profile.resolve(&spec.root))
+            .collect();
+        let watchdog = spec
+            .options
+            .watchdog_ms
+            .map(|ms| Watchdog::new(Duration::from_millis(ms), &spec.root, Instant::now()));
+        let flush_trigger = spec
+            .options
+            .flush_on
+            .as_ref()
+            .map(|flush_on| spec.root.join(flush_on));
+        let summary = spec
+            .options
+            .summary_interval_ms
+            .map(|ms| Summary::new(Duration::from_millis(ms), Instant::now()));
+        let expires_at = spec
+            .options
+            .duration_ms
+            .map(|ms| Instant::now() + Duration::from_millis(ms));
+        let mut watch = Self {
+            id: spec.watch_id,
+            request_id,
+            root: spec.root,
+            files: None,
+            ancestor: None,
+            root_created: false,
+            backend,
+            hash: spec.options.hash_spec().unwrap_or(None),
+            content_match: spec.options.content_pattern().unwrap_or(None),
+            rename_mode: spec.options.rename_mode().unwrap_or(RenameMode::Paired),
+            symlink_filter: spec
+                .options
+                .symlink_filter()
+                .unwrap_or(SymlinkFilter::Include),
+            ignore_syntax: spec.options.ignore_syntax().unwrap_or(IgnoreSyntax::Glob),
+            profiles,
+            actions: match &spec.options.actions {
+                Some(actions) => actions.iter().cloned().collect(),
+                None => Action::ALL.iter().cloned().collect(),
+            },
+            options: spec.options,
+            file_ids: HashMap::new(),
+            attributes: AttributeCache::default(),
+            contents: AttributeCache::default(),
+            sizes: SizeCache::default(),
+            held_modifications: HashMap::new(),
+            held_deletions: Vec::new(),
+            emitted_events: 0,
+            tree_hash: None,
+            filesystem_cache: FilesystemCache::default(),
+            symlink_cache: SymlinkCache::default(),
+            rules: None,
+            gitignore: None,
+            rules_file: None,
+            history: VecDeque::new(),
+            last_emitted_at: None,
+            next_batch_id: 0,
+            last_listing: Vec::new(),
+            seen_entries: HashSet::new(),
+            entries_exceeded: false,
+            next_rename_id: 0,
+            watchdog,
+            summary,
+            flush_trigger,
+            held_for_trigger: Vec::new(),
+            coalescing: Vec::new(),
+            coalescing_since: None,
+            expires_at,
+            pause_buffer: None,
+        };
+        // Deltas start from what the root contains when the watch is created
+        if watch.options.listing_delta == Some(true) {
+            watch.last_listing = watch.entries();
+        }
+        if watch.options.gitignore_files == Some(true) {
+            let mut gitignore = Gitignore::new(&watch.root);
+            gitignore.reload_files();
+            watch.gitignore = Some(gitignore);
+        }
+        watch
+    }
+
+    // Walk the tree to start maintaining its hash, if the watch asked for one. This happens once
+    // everything that decides what the watch reports has been set up.
+    fn start_tree_hash(&mut self) {
+        if self.options.tree_hash == Some(true) {
+            let tree_hash = TreeHash::build(&self.root, &|path| self.reports(path));
+            self.tree_hash = Some(tree_hash);
+        }
+    }
+
+    // Rehash the paths that `batch` refers to. This covers everything the watch reports on, before
+    // any of its options filter the events.
+    fn update_tree_hash(&mut self, batch: &[Event]) {
+        let mut tree_hash = match self.tree_hash.take() {
+            Some(tree_hash) => tree_hash,
+            None => return,
+        };
+        let covers = |path: &Path| self.reports(path);
+        for event in batch {
+            match event {
+                Event::Rescan { path, .. }
+                | Event::Listing { path, .. }
+                | Event::ListingDelta { path, .. } => tree_hash.refresh(path, &covers),
+                Event::Renamed { path, old_path, .. } => {
+                    tree_hash.update(old_path, &covers);
+                    tree_hash.update(path, &covers);
+                }
+                Event::Modified { path, .. }
+                | Event::Created { path, .. }
+                | Event::Deleted { path, .. }
+                | Event::RootCreated { path, .. } => tree_hash.update(path, &covers),
+                Event::Error { .. }
+                | Event::QueueOverflow
+                | Event::Idle
+                | Event::Summary { .. }
+                | Event::BatchBegin { .. }
+                | Event::BatchEnd { .. } => {}
+            }
+        }
+        self.tree_hash = Some(tree_hash);
+    }
+
+    // Keep track of the events that are about to be emitted, for `history` and `idle_after_ms`.
+    fn record_emitted(&mut self, events: &[Event]) {
+        if let Some(capacity) = self.options.history {
+            self.record_history(events, capacity);
+        }
+        if self.options.idle_after_ms.is_some() && !events.is_empty() {
+            self.last_emitted_at = Some(Instant::now());
+        }
+    }
+
+    // Report the watch as idle once `idle_after_ms` has passed since it last emitted an event.
+    // It's only reported once until it emits another.
+    fn report_idle(&mut self, now: Instant) -> Option<Outgoing> {
+        let idle_after = Duration::from_millis(self.options.idle_after_ms?);
+        if now.duration_since(self.last_emitted_at?) < idle_after {
+            return None;
+        }
+        self.last_emitted_at = None;
+        self.deliver(vec![Event::Idle])
+    }
+
+    // Count the creations, modifications, deletions, and renames in `events` towards the next
+    // `summary` event, instead of emitting them, when the watch has `summary_interval_ms`.
+    fn summarize(&mut self, events: &mut Vec<Event>) {
+        let counts = match &mut self.summary {
+            Some(summary) => &mut summary.counts,
+            None => return,
+        };
+        events.retain(|event| {
+            let count = match event {
+                Event::Created { .. } => &mut counts.created,
+                Event::Modified { .. } => &mut counts.modified,
+                Event::Deleted { .. } => &mut counts.deleted,
+                Event::Renamed { .. } => &mut counts.renamed,
+                _ => return true,
+            };
+            *count += 1;
+            false
+        });
+    }
+
+    // Emit a `summary` event once the current `summary_interval_ms` is over, unless nothing was
+    // counted during it.
+    fn report_summary(&mut self, now: Instant) -> Option<Outgoing> {
+        if !self.summary.as_ref()?.is_due(now) {
+            return None;
+        }
+        let events = self.take_summary(now).into_iter().collect();
+        self.emit_released(events)
+    }
+
+    // End the current summary window, returning a `summary` event for it if anything was
+    // counted during it.
+    fn take_summary(&mut self, now: Instant) -> Option<Event> {
+        let (counts, window) = self.summary.as_mut()?.take(now)?;
+        Some(Event::Summary {
+            watch_id: self.id,
+            created: counts.created,
+            modified: counts.modified,
+            deleted: counts.deleted,
+            renamed: counts.renamed,
+            window_ms: window.as_millis() as u64,
+        })
+    }
+
+    // Remember the events in `batch`, forgetting the oldest ones beyond `capacity`.
+    fn record_history(&mut self, batch: &[Event], capacity: usize) {
+        self.history.extend(batch.iter().cloned());
+        let excess = self.history.len().saturating_sub(capacity);
+        self.history.drain(..excess);
+    }
+
+    // Drop the events that would take the number of distinct paths the watch has emitted events
+    // for past `max_entries`, reporting the first of them as an error. Events for paths that were
+    // emitted before still go through, and so do errors.
+    fn guard_entries(&mut self, events: &mut Vec<Event>) {
+        let max_entries = match self.options.max_entries {
+            Some(max_entries) => max_entries,
+            None => return,
+        };
+        let mut guarded = Vec::with_capacity(events.len());
+        for event in events.drain(..) {
+            if matches!(event, Event::Error { .. }) {
+                guarded.push(event);
+                continue;
+            }
+            let new_paths: Vec<PathBuf> = event
+                .paths()
+                .into_iter()
+                .filter(|path| !self.seen_entries.contains(*path))
+                .map(Path::to_path_buf)
+                .collect();
+            if self.seen_entries.len() + new_paths.len() <= max_entries {
+                self.seen_entries.extend(new_paths);
+                guarded.push(event);
+            } else if !self.entries_exceeded {
+                self.entries_exceeded = true;
+                guarded.push(Event::Error {
+                    path: self.root.clone(),
+                    description: format!(
+                        "Events were emitted for {} distinct paths, so events for new ones are \
+                         dropped from now on",
+                        max_entries
+                    ),
+                    code: Some(ErrorCode::TooManyEntries),
+                    raw: RawPaths::default(),
+                });
+            }
+        }
+        *events = guarded;
+    }
+
+    // Drop the events beyond the watch's `limit`, and count the rest towards it.
+    fn apply_limit(&mut self, events: &mut Vec<Event>) {
+        if let Some(limit) = self.options.limit {
+            events.truncate(limit.saturating_sub(self.emitted_events));
+            self.emitted_events += events.len();
+        }
+    }
+
+    fn has_reached_limit(&self) -> bool {
+        self.options
+            .limit
+            .is_some_and(|limit| self.emitted_events >= limit)
+    }
+
+    // Why the watch should be removed as of `now`, if it should
+    fn removal_reason(&self, now: Instant) -> Option<RemovalReason> {
+        if self.has_reached_limit() {
+            Some(RemovalReason::LimitReached)
+        } else if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            Some(RemovalReason::DurationElapsed)
+        } else {
+            None
+        }
+    }
+
+    // Hold back modifications until their path has been quiet for `trailing_quiet_ms`, replacing
+    // any modification that's already held for it. Other events go through, preceded by a held
+    // modification of the same path so that the two are reported in the order they happened.
+    fn hold_modifications(&mut self, batch: Vec<Event>, now: Instant) -> Vec<Event> {
+        let mut emitted = Vec::with_capacity(batch.len());
+        for event in batch {
+            if let Event::Modified { path, .. } = &event {
+                self.held_modifications.insert(path.clone(), (now, event));
+                continue;
+            }
+            for path in event.paths() {
+                if let Some((_, held)) = self.held_modifications.remove(path) {
+                    emitted.push(held);
+                }
+            }
+            emitted.push(event);
+        }
+        emitted
+    }
+
+    // Buffer events for `coalesce_ms`, starting with the first one to arrive, then emit the buffer
+    // as a whole. An event merges into the last buffered event for the same paths if both have the
+    // same action, other than `error`, taking its place. Anything in between is about other paths,
+    // so this keeps the events for each path in order.
+    fn coalesce(&mut self, batch: Vec<Event>, now: Instant) -> Vec<Event> {
+        let window = match self.options.coalesce_ms {
+            Some(coalesce_ms) => Duration::from_millis(coalesce_ms),
+            None => return batch,
+        };
+        let mut emitted = Vec::new();
+        if self
+            .coalescing_since
+            .is_some_and(|since| now.duration_since(since) >= window)
+        {
+            emitted = std::mem::take(&mut self.coalescing);
+            self.coalescing_since = None;
+        }
+        for event in batch {
+            self.coalescing_since.get_or_insert(now);
+            let merged = {
+                let paths = event.paths();
+                self.coalescing
+                    .iter()
+                    .rposition(|held| held.paths().iter().any(|path| paths.contains(path)))
+                    .filter(|&i| {
+                        let held = &self.coalescing[i];
+                        held.action().is_some_and(|action| action != Action::Error)
+                            && held.action() == event.action()
+                            && held.paths() == paths
+                    })
+            };
+            match merged {
+                Some(i) => self.coalescing[i] = event,
+                None => self.coalescing.push(event),
+            }
+        }
+        emitted
+    }
+
+    // Hold back everything until the watch's `flush_on` file is created or modified, then emit
+    // what was held followed by the event for the file.
+    fn hold_until_trigger(&mut self, batch: Vec<Event>) -> Vec<Event> {
+        let trigger = match &self.flush_trigger {
+            Some(trigger) => trigger,
+            None => return batch,
+        };
+        let mut emitted = Vec::new();
+        for event in batch {
+            let fires = match &event {
+                Event::Created { path, .. }
+                | Event::Modified { path, .. }
+                | Event::Renamed { path, .. } => path == trigger,
+                _ => false,
+            };
+            if fires {
+                emitted.append(&mut self.held_for_trigger);
+                emitted.push(event);
+            } else {
+                self.held_for_trigger.push(event);
+            }
+        }
+        emitted
+    }
+
+    // Pair deletions with creations of a file with the same name that follow within
+    // `SMART_RENAME_WINDOW`, which is how backends report a file moving out of the root and back
+    // in. When the inode numbers of both are known, they have to match as well. Deletions are held
+    // back until they're paired or the window has passed, and other events for a held path
+    // release its deletion first.
+    fn pair_renames(&mut self, batch: Vec<Event>, now: Instant) -> Vec<Event> {
+        let mut emitted = Vec::with_capacity(batch.len());
+        for event in batch {
+            match &event {
+                Event::Deleted { .. } => {
+                    self.held_deletions.push((now, event));
+                    continue;
+                }
+                Event::Created { path, info, .. } => {
+                    let paired = self.held_deletions.iter().position(|(_, held)| match held {
+                        Event::Deleted {
+                            path: old_path,
+                            info: old_info,
+                            ..
+                        } => {
+                            old_path.file_name() == path.file_name()
+                                && (old_info.inode.is_none()
+                                    || info.inode.is_none()
+                                    || (old_info.dev, old_info.inode) == (info.dev, info.inode))
+                        }
+                        _ => false,
+                    });
+                    if let Some(i) = paired {
+                        let (_, deleted) = self.held_deletions.remove(i);
+                        let mut renamed = Event::renamed(deleted.paths()[0], path);
+                        if let Event::Renamed {
+                            info: renamed_info, ..
+                        } = &mut renamed
+                        {
+                            renamed_info.ancestors = info.ancestors.clone();
+                        }
+                        if self.options.binary_paths == Some(true) {
+                            renamed.record_raw_paths();
+                        }
+                        emitted.push(renamed);
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            let paths = event.paths();
+            emitted.extend(
+                self.held_deletions
+                    .extract_if(.., |(_, held)| paths.contains(&held.paths()[0]))
+                    .map(|(_, held)| held),
+            );
+            emitted.push(event);
+        }
+        emitted
+    }
+
+    fn release_held_modifications(&mut self, now: Instant) -> Option<Outgoing> {
+        let mut released: Vec<(Instant, Event)> = self
+            .held_deletions
+            .extract_if(.., |(deleted_at, _)| {
+                now.duration_since(*deleted_at) >= SMART_RENAME_WINDOW
+            })
+            .collect();
+        if let Some(quiet_ms) = self.options.trailing_quiet_ms {
+            let quiet = Duration::from_millis(quiet_ms);
+            released.extend(
+                self.held_modifications
+                    .extract_if(|_, (last_seen, _)| now.duration_since(*last_seen) >= quiet)
+                    .map(|(_, held)| held),
+            );
+        }
+        let events = self.coalesce(in_order(released), now);
+        let events = self.hold_until_trigger(events);
+        self.emit_released(events)
+    }
+
+    // Emit everything that's held back, regardless of how long it has been held, for a watch that
+    // is about to be removed.
+    fn release_everything_held(&mut self) -> Option<Outgoing> {
+        let mut released: Vec<(Instant, Event)> = self.held_deletions.drain(..).collect();
+        released.extend(self.held_modifications.drain().map(|(_, held)| held));
+        let mut events = std::mem::take(&mut self.held_for_trigger);
+        events.append(&mut self.coalescing);
+        self.coalescing_since = None;
+        events.extend(in_order(released));
+        // What was counted so far is reported rather than lost
+        self.summarize(&mut events);
+        events.extend(self.take_summary(Instant::now()));
+        self.emit_released(events)
+    }
+
+    fn emit_released(&mut self, mut events: Vec<Event>) -> Option<Outgoing> {
+        if events.is_empty() {
+            return None;
+        }
+
+        self.guard_entries(&mut events);
+        self.summarize(&mut events);
+        self.apply_limit(&mut events);
+        self.rewrite_paths(&mut events);
+        self.record_emitted(&events);
+        self.add_batch_markers(&mut events);
+        self.deliver(events)
+    }
+
+    // Replace each rename in `batch` with the deletion of its old path and the creation of its new
+    // one, which keep its details, or with `RenameMode::Both` follow it with them, correlated by a
+    // shared id.
+    fn split_renames(&mut self, batch: Vec<Event>) -> Vec<Event> {
+        let mut split = Vec::with_capacity(batch.len());
+        for event in batch {
+            let (path, old_path, mut info, raw) = match event {
+                Event::Renamed {
+                    path,
+                    old_path,
+                    info,
+                    raw,
+                } => (path, old_path, info, raw),
+                event => {
+                    split.push(event);
+                    continue;
+                }
+            };
+            if self.rename_mode == RenameMode::Both {
+                info.rename_id = Some(self.next_rename_id);
+                self.next_rename_id += 1;
+                split.push(Event::Renamed {
+                    path: path.clone(),
+                    old_path: old_path.clone(),
+                    info: info.clone(),
+                    raw: raw.clone(),
+                });
+            }
+            let mut deleted = Event::Deleted {
+                path: old_path,
+                info: info.clone(),
+                raw: RawPaths::default(),
+            };
+            let mut created = Event::Created {
+                path,
+                info,
+                raw: RawPaths::default(),
+            };
+            if raw.is_requested() {
+                deleted.record_raw_paths();
+                created.record_raw_paths();
+            }
+            split.push(deleted);
+            split.push(created);
+        }
+        split
+    }
+
+    // Surround a non-empty batch with markers when `batch_markers` is set. They're added after
+    // everything else, so that they don't count towards `limit` or end up in the history.
+    fn add_batch_markers(&mut self, batch: &mut Vec<Event>) {
+        if self.options.batch_markers != Some(true) || batch.is_empty() {
+            return;
+        }
+        let batch_id = self.next_batch_id;
+        self.next_batch_id += 1;
+        batch.insert(0, Event::BatchBegin { batch_id });
+        batch.push(Event::BatchEnd { batch_id });
+    }
+
+    // Apply the `rewrite` option to the paths of events that are about to be emitted.
+    fn rewrite_paths(&self, events: &mut [Event]) {
+        let (from, to) = match &self.options.rewrite {
+            Some((from, to)) => (Path::new(from), Path::new(to)),
+            None => return,
+        };
+        for event in events {
+            if let Event::Created { path, info, .. } | Event::Modified { path, info, .. } = event {
+                if info.pending_hash.is_some() || info.pending_content_match.is_some() {
+                    info.local_path = Some(path.clone());
+                }
+            }
+            event.map_paths(|path| replace_prefix(path, from, to));
+        }
+    }
+
+    // Drop creations and modifications of files outside `min_size` and `max_size`. Each path is
+    // only checked once per batch, however many events it has in it.
+    fn filter_sizes(&self, batch: &mut Vec<Event>) {
+        let mut allowed: HashMap<PathBuf, bool> = HashMap::new();
+        batch.retain(|event| match event {
+            Event::Created { path, .. } | Event::Modified { path, .. } => *allowed
+                .entry(path.clone())
+                .or_insert_with(|| self.options.allows_size(path)),
+            _ => true,
+        });
+    }
+
+    // List the ancestors of each event's path, or of the new path of a rename, up to but not
+    // including the root. This comes after `forward_slashes`, whose separators the ancestors
+    // then share.
+    fn annotate_ancestors(&self, batch: &mut [Event]) {
+        for event in batch {
+            match event {
+                Event::Created { path, info, .. }
+                | Event::Modified { path, info, .. }
+                | Event::Deleted { path, info, .. }
+                | Event::Renamed { path, info, .. } => {
+                    info.ancestors = path
+                        .ancestors()
+                        .skip(1)
+                        .take_while(|ancestor| *ancestor != self.root)
+                        .filter(|ancestor| ancestor.starts_with(&self.root))
+                        .map(Path::to_path_buf)
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn annotate_file_ids(&mut self, batch: &mut [Event]) {
+        for event in batch {
+            match event {
+                Event::Created { path, info, .. } | Event::Modified { path, info, .. } => {
+                    if let Some(file_id) = file_id(path) {
+                        self.file_ids.insert(path.clone(), file_id);
+                        info.dev = Some(file_id.0);
+                        info.inode = Some(file_id.1);
+                    }
+                }
+                Event::Deleted { path, info, .. } => {
+                    if let Some((dev, inode)) = self.file_ids.remove(path.as_path()) {
+                        info.dev = Some(dev);
+                        info.inode = Some(inode);
+                    }
+                }
+                Event::Renamed { path, old_path, .. } => {
+                    if let Some(file_id) = self.file_ids.remove(old_path.as_path()) {
+                        self.file_ids.insert(path.clone(), file_id);
+                    }
+                }
+                Event::Error { .. }
+                | Event::QueueOverflow
+                | Event::Idle
+                | Event::Summary { .. }
+                | Event::BatchBegin { .. }
+                | Event::BatchEnd { .. }
+                | Event::Rescan { .. }
+                | Event::RootCreated { .. }
+                | Event::Listing { .. }
+                | Event::ListingDelta { .. } => {}
+            }
+        }
+    }
+
+    // Compare the size of each modified file with the last one seen, which is remembered from its
+    // creation or previous modification. Directories are left alone.
+    fn annotate_size_deltas(&mut self, batch: &mut [Event]) {
+        for event in batch {
+            match event {
+                Event::Created { path, .. } => {
+                    self.sizes.update(path, file_size(path));
+                }
+                Event::Modified { path, info, .. } => {
+                    let metadata = match fs::metadata(&*path) {
+                        Ok(metadata) if metadata.is_dir() => continue,
+                        metadata => metadata.ok(),
+                    };
+                    let size_after = metadata.map(|metadata| metadata.len());
+                    let size_before = self.sizes.update(path, size_after);
+                    info.size_before = size_before;
+                    info.size_after = size_after;
+                    info.kind = Some(SizeChange::between(size_before, size_after));
+                }
+                Event::Deleted { path, .. } => self.sizes.remove(path),
+                Event::Renamed { path, old_path, .. } => self.sizes.rename(old_path, path),
+                Event::Error { .. }
+                | Event::QueueOverflow
+                | Event::Idle
+                | Event::Summary { .. }
+                | Event::BatchBegin { .. }
+                | Event::BatchEnd { .. }
+                | Event::Rescan { .. }
+                | Event::RootCreated { .. }
+                | Event::Listing { .. }
+                | Event::ListingDelta { .. } => {}
+            }
+        }
+    }
+
+    fn is_registered(&self, backend: Backend, root: &Path) -> bool {
+        self.files.is_none() && self.backend == backend && self.root == root
+    }
+
+    // The directories that the watch registers with its backend. A watch of individual files
+    // registers the directories they're in instead of the files themselves, so that a file is
+    // still noticed after being replaced or if it doesn't exist yet.
+    // The directory of a watched rules file is registered along with them.
+    fn registrations(&self) -> Vec<(&Path, RecursiveMode)> {
+        let mut registrations = match &self.files {
+            None => match &self.ancestor {
+                Some(ancestor) => vec![(ancestor.as_path(), RecursiveMode::Recursive)],
+                None => vec![(self.root.as_path(), self.root_mode())],
+            },
+            Some(files) => {
+                let mut dirs: Vec<&Path> = files.iter().filter_map(|file| file.parent()).collect();
+                dirs.sort();
+                dirs.dedup();
+                dirs.into_iter()
+                    .map(|dir| (dir, RecursiveMode::NonRecursive))
+                    .collect()
+            }
+        };
+        if let Some(dir) = self.rules_file.as_deref().and_then(Path::parent) {
+            if !registrations.contains(&(dir, RecursiveMode::NonRecursive)) {
+                registrations.push((dir, RecursiveMode::NonRecursive));
+            }
+        }
+        registrations
+    }
+
+    // The directory registered for the watched rules file, if there is one.
+    fn rules_registration(&self) -> Option<PathBuf> {
+        self.rules_file
+            .as_deref()
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+    }
+
+    // Read the watched rules file again after it changed. The previous rules stay in place if it
+    // can't be read, and the error is reported for the root.
+    fn reload_watched_rules(&mut self, batch: &mut Vec<Event>) {
+        let Some(rules_file) = &self.rules_file else {
+            return;
+        };
+        match self.read_rules(rules_file) {
+            Ok(rules) => self.set_rules(rules),
+            Err(description) => batch.push(Event::Error {
+                path: self.root.clone(),
+                description,
+                code: None,
+                raw: RawPaths::default(),
+            }),
+        }
+    }
+
+    // Read the patterns in `rules_file` according to the watch's `ignore_syntax`.
+    fn read_rules(&self, rules_file: &Path) -> Result<IgnoreRules, String> {
+        let rules = match self.ignore_syntax {
+            IgnoreSyntax::Glob => {
+                FilterProfile::ignoring_rules_in(rules_file).map(IgnoreRules::Glob)
+            }
+            IgnoreSyntax::Gitignore => {
+                gitignore::read_rules(rules_file).map(IgnoreRules::Gitignore)
+            }
+        };
+        rules.map_err(|error| format!("Error reading rules file {:?}: {}", rules_file, error))
+    }
+
+    fn set_rules(&mut self, rules: IgnoreRules) {
+        match rules {
+            IgnoreRules::Glob(profile) => self.rules = Some(profile.resolve(&self.root)),
+            IgnoreRules::Gitignore(rules) => {
+                let root = &self.root;
+                self.gitignore
+                    .get_or_insert_with(|| Gitignore::new(root))
+                    .set_rules(rules)
+            }
+        }
+    }
+
+    // The actions the watch emits as the `actions` option would give them, which is `None` for all
+    // of them.
+    fn current_actions(&self) -> Option<Vec<Action>> {
+        if self.actions.len() < Action::ALL.len() {
+            Some(self.actions.iter().cloned().collect())
+        } else {
+            None
+        }
+    }
+
+    // Whether events for `path` would make it through the watch's filters. The contents of files
+    // are only checked against `content_match` as they are right now.
+    fn would_emit(&self, path: &Path) -> bool {
+        self.rejected_by(path, None).is_none()
+    }
+
+    // The name of the first option that keeps an event with `action` for `path` from being
+    // emitted, with `None` standing for any action the watch emits. Options that depend on how
+    // the file changed, like `modifiedOn`, aren't considered.
+    fn rejected_by(&self, path: &Path, action: Option<Action>) -> Option<&'static str> {
+        if !self.reports(path) {
+            return Some(if self.files.is_some() {
+                "files"
+            } else if self.options.listing_mode == Some(true) {
+                "listingMode"
+            } else if path.starts_with(&self.root) {
+                "recursive"
+            } else {
+                "root"
+            });
+        }
+        if self.options.ignore_temp_files == Some(true) && is_temp_file(path) {
+            return Some("ignoreTempFiles");
+        }
+        if self.options.include_root_events == Some(false) && path == self.root {
+            return Some("includeRootEvents");
+        }
+        if !self.profiles.is_empty() && !profiles::allows(&self.profiles, path) {
+            return Some("profiles");
+        }
+        if let Some(rules) = &self.rules {
+            if !profiles::allows(std::slice::from_ref(rules), path) {
+                return Some("rules");
+            }
+        }
+        match self
+            .gitignore
+            .as_ref()
+            .and_then(|gitignore| gitignore.ignored_by(path))
+        {
+            Some(gitignore::Source::RulesFile) => return Some("rules"),
+            Some(gitignore::Source::Tree) => return Some("gitignoreFiles"),
+            None => {}
+        }
+        if !self.options.filesystems.is_empty()
+            && !filesystems::allows(path, &self.options.filesystems)
+        {
+            return Some("filesystems");
+        }
+        if self.symlink_filter != SymlinkFilter::Include
+            && !symlinks::allows(path, self.symlink_filter)
+        {
+            return Some("symlinks");
+        }
+        match action {
+            Some(action) if !self.actions.contains(&action) => return Some("actions"),
+            None if self.actions.is_empty() => return Some("actions"),
+            Some(Action::Modified) if self.options.created_only == Some(true) => {
+                return Some("createdOnly")
+            }
+            _ => {}
+        }
+
+        // Only creations and modifications are filtered by what's in the file
+        if action.is_some_and(|action| action != Action::Created && action != Action::Modified) {
+            return None;
+        }
+        if self.options.has_size_bounds() && !self.options.allows_size(path) {
+            let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+            return Some(
+                if self
+                    .options
+                    .min_size
+                    .is_some_and(|min_size| size < min_size)
+                {
+                    "minSize"
+                } else {
+                    "maxSize"
+                },
+            );
+        }
+        if self.content_match.as_ref().is_some_and(|pattern| {
+            !content_match::first_line_matches(path, pattern).unwrap_or(true)
+        }) {
+            return Some("contentMatch");
+        }
+        None
+    }
+
+    // The actions left by `actions` as it currently is, minus modifications with `created_only`.
+    // Renames paired up by `smart_renames` are only emitted when `actions` includes them, so they
+    // don't add anything, while `emit_rename_as` turns renames into deletions and creations.
+    fn effective_actions(&self) -> Vec<Action> {
+        let mut actions: BTreeSet<Action> = self
+            .actions
+            .iter()
+            .filter(|&&action| {
+                action != Action::Modified || self.options.created_only != Some(true)
+            })
+            .cloned()
+            .collect();
+        if self.rename_mode != RenameMode::Paired && actions.contains(&Action::Renamed) {
+            actions.insert(Action::Deleted);
+            actions.insert(Action::Created);
+            if self.rename_mode == RenameMode::Split {
+                actions.remove(&Action::Renamed);
+            }
+        }
+        actions.into_iter().collect()
+    }
+
+    // Describe the entries under the root that events would be emitted for, with their paths
+    // spelled the way the watch's events spell them.
+    fn snapshot(&self) -> Vec<snapshot::Entry> {
+        let mut entries = snapshot::entries(&self.root, |path| self.would_emit(path));
+        for entry in &mut entries {
+            if self.options.forward_slashes == Some(true) {
+                entry.path = with_forward_slashes(&entry.path);
+            }
+            if let Some((from, to)) = &self.options.rewrite {
+                entry.path = replace_prefix(&entry.path, Path::new(from), Path::new(to));
+            }
+        }
+        entries
+    }
+
+    // Whether every path that `other` reports on is one that this watch of a directory reports on
+    // as well, going by their roots and files rather than their other options.
+    fn covers(&self, other: &Watch) -> bool {
+        if self.files.is_some() {
+            return false;
+        }
+        match &other.files {
+            Some(files) => files.iter().all(|file| self.reports(file)),
+            None if self.is_recursive() => other.root.starts_with(&self.root),
+            // The direct children of a root are only all covered by a watch of the same root
+            None => !other.is_recursive() && other.root == self.root,
+        }
+    }
+
+    // Whether the watch covers the whole tree under its root rather than only its direct children.
+    fn is_recursive(&self) -> bool {
+        self.options.is_recursive()
+    }
+
+    // How the root is registered once it exists.
+    fn root_mode(&self) -> RecursiveMode {
+        if self.is_recursive() {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        }
+    }
+
+    fn reports(&self, path: &Path) -> bool {
+        match &self.files {
+            Some(files) => files.contains(path),
+            None if !self.is_recursive() => path == self.root || path.parent() == Some(&self.root),
+            None => path.starts_with(&self.root),
+        }
+    }
+
+    // The root's current contents, sorted by path. A root that can't be read, for example
+    // because it was deleted, is listed as empty.
+    fn entries(&self) -> Vec<PathBuf> {
+        let mut entries: Vec<PathBuf> = fs::read_dir(&self.root)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        entries.sort();
+        entries
+    }
+
+    fn listing(&self) -> Event {
+        Event::Listing {
+            path: self.root.clone(),
+            entries: self.entries(),
+            raw: RawPaths::default(),
+        }
+    }
+
+    // Sum up a change in the root as its full listing, or with `listing_delta` as what was added
+    // and removed since the last listing, if anything was.
+    fn listing_change(&mut self) -> Option<Event> {
+        if self.options.listing_delta != Some(true) {
+            return Some(self.listing());
+        }
+        let entries = self.entries();
+        let previous = std::mem::replace(&mut self.last_listing, entries);
+        let added: Vec<PathBuf> = self
+            .last_listing
+            .iter()
+            .filter(|entry| previous.binary_search(entry).is_err())
+            .cloned()
+            .collect();
+        let removed: Vec<PathBuf> = previous
+            .into_iter()
+            .filter(|entry| self.last_listing.binary_search(entry).is_err())
+            .collect();
+        if added.is_empty() && removed.is_empty() {
+            return None;
+        }
+        Some(Event::ListingDelta {
+            path: self.root.clone(),
+            added,
+            removed,
+            raw: RawPaths::default(),
+        })
+    }
+
+    // Events telling the client to rescan everything the watch reports on. In listing mode, the
+    // listing itself is all there is to rescan.
+    fn rescans(&self) -> Vec<Event> {
+        if self.options.listing_mode == Some(true) {
+            return vec![self.listing()];
+        }
+        let mut paths: Vec<&Path> = match &self.files {
+            Some(files) => files.iter().map(PathBuf::as_path).collect(),
+            None => vec![&self.root],
+        };
+        paths.sort();
+        paths
+            .into_iter()
+            .map(|path| Event::Rescan {
+                path: path.to_path_buf(),
+                raw: RawPaths::default(),
+            })
+            .collect()
+    }
+
+    fn notify(&mut self, events: &[(Backend, DebouncedEvent)]) -> Option<Outgoing> {
+        let mut batch = Vec::new();
+
+        // Another backend watching an overlapping root reports the same changes
+        let own_backend = self.backend;
+        let events = events
+            .iter()
+            .filter(|(backend, _)| *backend == own_backend)
+            .map(|(_, event)| event);
+
+        // The rules are reloaded before anything else, so that they apply to the events they
+        // arrived with
+        if let Some(rules_file) = &self.rules_file {
+            let touched = events.clone().any(|event| match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Remove(path) => path == rules_file,
+                DebouncedEvent::Rename(old_path, new_path) => {
+                    old_path == rules_file || new_path == rules_file
+                }
+                _ => false,
+            });
+            if touched {
+                self.reload_watched_rules(&mut batch);
+            }
+        }
+        // Adding, changing, or removing a `.gitignore` file can change which directories are
+        // ignored, and so which other `.gitignore` files there are to read
+        if let (Some(gitignore), Some(true)) = (&mut self.gitignore, self.options.gitignore_files) {
+            let is_gitignore = |path: &PathBuf| path.file_name() == Some(GITIGNORE.as_ref());
+            let touched = events.clone().any(|event| match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Remove(path) => is_gitignore(path),
+                DebouncedEvent::Rename(old_path, new_path) => {
+                    is_gitignore(old_path) || is_gitignore(new_path)
+                }
+                _ => false,
+            });
+            if touched {
+                gitignore.reload_files();
+            }
+        }
+
+        for event in events {
+            // The root may appear along with its parents, before the backend has had a chance
+            // to watch them, so its creation is reported as soon as any of them appears. The
+            // backend's own events for the root may still follow, and are dropped.
+            if let DebouncedEvent::Create(path) | DebouncedEvent::Rename(_, path) = event {
+                if (self.ancestor.is_some() || self.root_created) && self.root.starts_with(path) {
+                    if !self.root_created && self.root.exists() {
+                        batch.push(if self.options.root_created_event == Some(true) {
+                            Event::RootCreated {
+                                path: self.root.clone(),
+                                raw: RawPaths::default(),
+                            }
+                        } else {
+                            Event::created(&self.root)
+                        });
+                        self.root_created = true;
+                    }
+                    continue;
+                }
+            }
+            match event {
+                DebouncedEvent::Create(path) => {
+                    if self.reports(path) {
+                        batch.push(Event::created(path));
+                    }
+                }
+                DebouncedEvent::Write(path) => {
+                    if self.reports(path) {
+                        batch.push(Event::modified(path));
+                    }
+                }
+                DebouncedEvent::Remove(path) => {
+                    if self.reports(path) {
+                        batch.push(Event::deleted(path));
+                    }
+                }
+                DebouncedEvent::Rename(old_path, new_path) => {
+                    match (self.reports(old_path), self.reports(new_path)) {
+                        (true, true) => batch.push(Event::renamed(old_path, new_path)),
+                        (true, false) => batch.push(Event::deleted(old_path)),
+                        (false, true) => batch.push(Event::created(new_path)),
+                        (false, false) => {}
+                    }
+                }
+                DebouncedEvent::Error(error, path) => {
+                    if let Some(path) = path {
+                        if self.reports(path) {
+                            batch.push(Event::error(path, error));
+                        }
+                    }
+                }
+                DebouncedEvent::NoticeWrite(_path) => {}
+                DebouncedEvent::NoticeRemove(_path) => {}
+                DebouncedEvent::Chmod(_path) => {}
+                // notify reports queue overflows this way, and doesn't say which paths were
+                // affected, so every watch on the backend has to start over
+                DebouncedEvent::Rescan => {
+                    if !batch
+                        .iter()
+                        .any(|event| matches!(event, Event::QueueOverflow))
+                    {
+                        batch.push(Event::QueueOverflow);
+                        batch.extend(self.rescans());
+                    }
+                }
+            }
+        }
+
+        if let Some(watchdog) = &mut self.watchdog {
+            if !batch.is_empty() {
+                watchdog.received_events();
+            }
+        }
+
+        // In listing mode, everything that happened in the batch is summed up by a single listing,
+        // apart from the creation of the root and what has to reach the client regardless: errors,
+        // and the requests to rescan, which already come with a full listing of their own
+        if self.options.listing_mode == Some(true) && !batch.is_empty() {
+            let root = &self.root;
+            batch.retain(|event| match event {
+                Event::RootCreated { .. }
+                | Event::QueueOverflow
+                | Event::Rescan { .. }
+                | Event::Listing { .. }
+                | Event::Error { .. } => true,
+                Event::Created { path, .. } => path == root,
+                _ => false,
+            });
+            if !batch
+                .iter()
+                .any(|event| matches!(event, Event::Listing { .. }))
+            {
+                batch.extend(self.listing_change());
+            }
+        }
+
+        self.emit(batch)
+    }
+
+    // Apply the watch's options to a batch of events and wrap what remains in a message.
+    fn emit(&mut self, mut batch: Vec<Event>) -> Option<Outgoing> {
+        self.update_tree_hash(&batch);
+
+        // Full listings, as sent for rescans, are what later deltas build on
+        if self.options.listing_delta == Some(true) {
+            for event in &batch {
+                if let Event::Listing { entries, .. } = event {
+                    self.last_listing = entries.clone();
+                }
+            }
+        }
+
+        if self.options.ignore_temp_files == Some(true) {
+            batch = batch
+                .into_iter()
+                .filter_map(|event| retain_paths(event, |path| !is_temp_file(path)))
+                .collect();
+        }
+
+        if self.options.include_root_events == Some(false) {
+            let root = &self.root;
+            batch = batch
+                .into_iter()
+                .filter_map(|event| retain_paths(event, |path| path != root))
+                .collect();
+        }
+
+        if !self.profiles.is_empty() {
+            batch = batch
+                .into_iter()
+                .filter_map(|event| {
+                    retain_paths(event, |path| profiles::allows(&self.profiles, path))
+                })
+                .collect();
+        }
+
+        if let Some(rules) = &self.rules {
+            let rules = std::slice::from_ref(rules);
+            batch = batch
+                .into_iter()
+                .filter_map(|event| retain_paths(event, |path| profiles::allows(rules, path)))
+                .collect();
+        }
+
+        if let Some(gitignore) = &self.gitignore {
+            batch = batch
+                .into_iter()
+                .filter_map(|event| {
+                    retain_paths(event, |path| gitignore.ignored_by(path).is_none())
+                })
+                .collect();
+        }
+
+        if !self.options.filesystems.is_empty() {
+            let allowed = &self.options.filesystems;
+            let cache = &mut self.filesystem_cache;
+            batch = batch
+                .into_iter()
+                .filter_map(|event| retain_paths(event, |path| cache.allows(path, allowed)))
+                .collect();
+        }
+
+        if self.symlink_filter != SymlinkFilter::Include {
+            let filter = self.symlink_filter;
+            let cache = &mut self.symlink_cache;
+            batch = batch
+                .into_iter()
+                .filter_map(|event| retain_paths(event, |path| cache.allows(path, filter)))
+                .collect();
+        }
+
+        batch.retain(|event| match event.action() {
+            Some(action) => self.actions.contains(&action),
+            None => true,
+        });
+
+        // Whether a file was modified on its own or right after it was created, it has been
+        // reported once already
+        if self.options.created_only == Some(true) {
+            batch.retain(|event| !matches!(event, Event::Modified { .. }));
+        }
+
+        if self.options.has_size_bounds() {
+            self.filter_sizes(&mut batch);
+        }
+
+        if !self.options.modified_on.is_empty() {
+            filter_modifications(&mut self.attributes, &self.options.modified_on, &mut batch);
+        }
+
+        if self.options.suppress_nop_changes == Some(true) {
+            filter_modifications(
+                &mut self.contents,
+                &[ModifiedAttribute::Content],
+                &mut batch,
+            );
+        }
+
+        if self.options.include_inode == Some(true) {
+            self.annotate_file_ids(&mut batch);
+        }
+
+        if self.options.track_size_delta == Some(true) {
+            self.annotate_size_deltas(&mut batch);
+        }
+
+        if self.options.detect_hardlinks == Some(true) {
+            annotate_link_counts(&mut batch);
+        }
+
+        if let Some(hash) = self.hash {
+            for event in &mut batch {
+                if let Event::Created { info, .. } | Event::Modified { info, .. } = event {
+                    info.pending_hash = Some(hash);
+                }
+            }
+        }
+
+        if let Some(content_match) = &self.content_match {
+            for event in &mut batch {
+                if let Event::Created { info, .. } | Event::Modified { info, .. } = event {
+                    info.pending_content_match = Some(content_match.clone());
+                }
+            }
+        }
+
+        if self.options.forward_slashes == Some(true) {
+            for event in &mut batch {
+                event.map_paths(with_forward_slashes);
+            }
+        }
+
+        if self.options.binary_paths == Some(true) {
+            for event in &mut batch {
+                event.record_raw_paths();
+            }
+        }
+
+        if self.options.include_ancestors == Some(true) {
+            self.annotate_ancestors(&mut batch);
+        }
+
+        if self.options.trailing_quiet_ms.is_some() {
+            batch = self.hold_modifications(batch, Instant::now());
+        }
+
+        // Only pair up what the watch would otherwise emit, as long as it emits renames at all
+        if self.options.smart_renames == Some(true) && self.actions.contains(&Action::Renamed) {
+            batch = self.pair_renames(batch, Instant::now());
+        }
+
+        if self.rename_mode != RenameMode::Paired {
+            batch = self.split_renames(batch);
+        }
+
+        batch = self.coalesce(batch, Instant::now());
+        batch = self.hold_until_trigger(batch);
+
+        self.guard_entries(&mut batch);
+        self.summarize(&mut batch);
+        self.apply_limit(&mut batch);
+        self.rewrite_paths(&mut batch);
+        self.record_emitted(&batch);
+        self.add_batch_markers(&mut batch);
+        self.deliver(batch)
+    }
+
+    // Wrap the events that are ready to be emitted in a message, unless they're kept for after a
+    // `bufferedPause` instead.
+    fn deliver(&mut self, events: Vec<Event>) -> Option<Outgoing> {
+        if events.is_empty() {
+            return None;
+        }
+        if let Some(buffer) = &mut self.pause_buffer {
+            buffer.store(events);
+            return None;
+        }
+        Some(Outgoing::WatchEvents {
+            watch_id: self.id,
+            events,
+        })
+    }
+}
+
+impl Event {
+    fn modified(path: &Path) -> Self {
+        Event::Modified {
+            path: path.into(),
+            info: FileInfo::default(),
+            raw: RawPaths::default(),
+        }
+    }
+    fn created(path: &Path) -> Self {
+        Event::Created {
+            path: path.into(),
+            info: FileInfo::default(),
+            raw: RawPaths::default(),
+        }
+    }
+    fn deleted(path: &Path) -> Self {
+        Event::Deleted {
+            path: path.into(),
+            info: FileInfo::default(),
+            raw: RawPaths::default(),
+        }
+    }
+    fn renamed(old_path: &Path, new_path: &Path) -> Self {
+        Event::Renamed {
+            path: new_path.into(),
+            old_path: old_path.into(),
+            info: FileInfo::default(),
+            raw: RawPaths::default(),
+        }
+    }
+    fn error(path: &Path, error: &notify::Error) -> Self {
+        Event::Error {
+            path: path.into(),
+            description: describe_error(error),
+            code: None,
+            raw: RawPaths::default(),
+        }
+    }
+
+    fn map_paths(&mut self, f: impl Fn(&Path) -> PathBuf) {
+        match self {
+            Event::Modified { path, info, raw }
+            | Event::Created { path, info, raw }
+            | Event::Deleted { path, info, raw } => {
+                *path = f(path);
+                for ancestor in &mut info.ancestors {
+                    *ancestor = f(ancestor);
+                }
+                if raw.is_requested() {
+                    *raw = RawPaths::new(path, None);
+                }
+            }
+            Event::Error { path, raw, .. }
+            | Event::Rescan { path, raw }
+            | Event::RootCreated { path, raw } => {
+                *path = f(path);
+                if raw.is_requested() {
+                    *raw = RawPaths::new(path, None);
+                }
+            }
+            Event::Renamed {
+                path,
+                old_path,
+                info,
+                raw,
+            } => {
+                *path = f(path);
+                *old_path = f(old_path);
+                for ancestor in &mut info.ancestors {
+                    *ancestor = f(ancestor);
+                }
+                if raw.is_requested() {
+                    *raw = RawPaths::new(path, Some(old_path));
+                }
+            }
+            Event::ListingDelta {
+                path,
+                added,
+                removed,
+                raw,
+            } => {
+                *path = f(path);
+                for entry in added.iter_mut().chain(removed) {
+                    *entry = f(entry);
+                }
+                if raw.is_requested() {
+                    *raw = RawPaths::new(path, None);
+                }
+            }
+            Event::Listing { path, entries, raw } => {
+                *path = f(path);
+                for entry in entries {
+                    *entry = f(entry);
+                }
+                if raw.is_requested() {
+                    *raw = RawPaths::new(path, None);
+                }
+            }
+            Event::QueueOverflow
+            | Event::Idle
+            | Event::Summary { .. }
+            | Event::BatchBegin { .. }
+            | Event::BatchEnd { .. } => {}
+        }
+    }
+
+    // The paths that the event refers to.
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            Event::Modified { path, .. }
+            | Event::Created { path, .. }
+            | Event::Deleted { path, .. }
+            | Event::Error { path, .. }
+            | Event::Rescan { path, .. }
+            | Event::RootCreated { path, .. } => vec![path],
+            Event::Renamed { path, old_path, .. } => vec![old_path, path],
+            Event::Listing { path, entries, .. } => {
+                let mut paths = vec![path.as_path()];
+                paths.extend(entries.iter().map(PathBuf::as_path));
+                paths
+            }
+            Event::ListingDelta {
+                path,
+                added,
+                removed,
+                ..
+            } => {
+                let mut paths = vec![path.as_path()];
+                paths.extend(added.iter().chain(removed).map(PathBuf::as_path));
+                paths
+            }
+            Event::QueueOverflow
+            | Event::Idle
+            | Event::Summary { .. }
+            | Event::BatchBegin { .. }
+            | Event::BatchEnd { .. } => Vec::new(),
+        }
+    }
+
+    // Describe the encoding of the paths for watches with `binary_paths`. Once recorded, this is
+    // kept up to date by `map_paths`.
+    fn record_raw_paths(&mut self) {
+        match self {
+            Event::Modified { path, raw, .. }
+            | Event::Created { path, raw, .. }
+            | Event::Deleted { path, raw, .. }
+            | Event::Error { path, raw, .. }
+            | Event::Rescan { path, raw }
+            | Event::RootCreated { path, raw }
+            | Event::Listing { path, raw, .. }
+            | Event::ListingDelta { path, raw, .. } => *raw = RawPaths::new(path, None),
+            Event::Renamed {
+                path,
+                old_path,
+                raw,
+                ..
+            } => *raw = RawPaths::new(path, Some(old_path)),
+            Event::QueueOverflow
+            | Event::Idle
+            | Event::Summary { .. }
+            | Event::BatchBegin { .. }
+            | Event::BatchEnd { .. } => {}
+        }
+    }
+
+    // `None` for the events that report something other than a change to a path, such as lost
+    // events, which can't be filtered out.
+    fn action(&self) -> Option<Action> {
+        match self {
+            Event::Modified { .. } => Some(Action::Modified),
+            Event::Created { .. } => Some(Action::Created),
+            Event::Deleted { .. } => Some(Action::Deleted),
+            Event::Renamed { .. } => Some(Action::Renamed),
+            Event::Error { .. } => Some(Action::Error),
+            Event::QueueOverflow
+            | Event::Idle
+            | Event::Summary { .. }
+            | Event::BatchBegin { .. }
+            | Event::BatchEnd { .. }
+            | Event::Rescan { .. }
+            | Event::RootCreated { .. }
+            | Event::Listing { .. }
+            | Event::ListingDelta { .. } => None,
+        }
+    }
+
+    fn matches_content(&mut self) -> bool {
+        if let Event::Created { path, info, .. } | Event::Modified { path, info, .. } = self {
+            if let Some(pattern) = info.pending_content_match.take() {
+                let path = info.local_path.as_deref().unwrap_or(path);
+                return content_match::first_line_matches(path, &pattern).unwrap_or(true);
+            }
+        }
+        true
+    }
+
+    // Append the event to a `WATCH_EVENTS` frame, unless it has fields without a binary layout.
+    fn encode(&self, frame: binary::Frame) -> Option<binary::Frame> {
+        let path = |frame: binary::Frame, path: &Path| frame.bytes(&binary::path_bytes(path));
+        Some(match self {
+            Event::Modified { path: p, info, raw } if info.is_empty() && !raw.is_requested() => {
+                path(frame.u8(binary::MODIFIED), p)
+            }
+            Event::Created { path: p, info, raw } if info.is_empty() && !raw.is_requested() => {
+                path(frame.u8(binary::CREATED), p)
+            }
+            Event::Deleted { path: p, info, raw } if info.is_empty() && !raw.is_requested() => {
+                path(frame.u8(binary::DELETED), p)
+            }
+            Event::Renamed {
+                path: p,
+                old_path,
+                info,
+                raw,
+            } if info.is_empty() && !raw.is_requested() => {
+                path(path(frame.u8(binary::RENAMED), old_path), p)
+            }
+            Event::Error {
+                path: p,
+                description,
+                code: None,
+                raw,
+            } if !raw.is_requested() => {
+                path(frame.u8(binary::ERROR), p).bytes(description.as_bytes())
+            }
+            Event::QueueOverflow => frame.u8(binary::QUEUE_OVERFLOW),
+            Event::Idle => frame.u8(binary::IDLE),
+            Event::Rescan { path: p, raw } if !raw.is_requested() => {
+                path(frame.u8(binary::RESCAN), p)
+            }
+            Event::RootCreated { path: p, raw } if !raw.is_requested() => {
+                path(frame.u8(binary::ROOT_CREATED), p)
+            }
+            _ => return None,
+        })
+    }
+
+    fn compute_hash(&mut self) {
+        if let Event::Created { path, info, .. } | Event::Modified { path, info, .. } = self {
+            if let Some(hash) = info.pending_hash.take() {
+                let path = info.local_path.as_deref().unwrap_or(path);
+                info.hash = Some(hash.hash_file(path));
+            }
+        }
+    }
+}
+
+// The size of the file at `path`, unless it's a directory or can't be read.
+fn file_size(path: &Path) -> Option<u64> {
+    fs::metadata(path)
+        .ok()
+        .filter(|metadata| !metadata.is_dir())
+        .map(|metadata| metadata.len())
+}
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::symlink_metadata(path)
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+// Replace `from` at the start of `path` with `to`, leaving paths that don't start with it alone.
+// Prefixes are compared component by component, so `/srv/www` isn't a prefix of `/srv/www2`.
+fn replace_prefix(path: &Path, from: &Path, to: &Path) -> PathBuf {
+    match path.strip_prefix(from) {
+        Ok(rest) if rest.as_os_str().is_empty() => to.to_path_buf(),
+        Ok(rest) => to.join(rest),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+// Drop the modifications in `batch` that didn't change any of `watched` according to `cache`,
+// keeping the cache up to date with the other events.
+fn filter_modifications(
+    cache: &mut AttributeCache,
+    watched: &[ModifiedAttribute],
+    batch: &mut Vec<Event>,
+) {
+    batch.retain(|event| match event {
+        Event::Created { path, .. } => {
+            cache.update(path, watched);
+            true
+        }
+        Event::Modified { path, .. } => cache.update(path, watched),
+        Event::Deleted { path, .. } => {
+            cache.remove(path);
+            true
+        }
+        Event::Renamed { path, old_path, .. } => {
+            cache.rename(old_path, path);
+            true
+        }
+        Event::Error { .. }
+        | Event::QueueOverflow
+        | Event::Idle
+        | Event::Summary { .. }
+        | Event::BatchBegin { .. }
+        | Event::BatchEnd { .. }
+        | Event::Rescan { .. }
+        | Event::RootCreated { .. }
+        | Event::Listing { .. }
+        | Event::ListingDelta { .. } => true,
+    });
+}
+
+// Mark the files created or modified in `batch` that have other hard links to them. Directories
+// always have several links, so they're left alone.
+fn annotate_link_counts(batch: &mut [Event]) {
+    for event in batch {
+        if let Event::Created { path, info, .. } | Event::Modified { path, info, .. } = event {
+            if let Some(nlink) = link_count(path).filter(|&nlink| nlink > 1) {
+                info.hardlink = Some(true);
+                info.nlink = Some(nlink);
+            }
+        }
     }
+}
 
-    fn handle_requests(&mut self) {
-        let stdin = io::stdin();
-        for line in stdin.lock().lines() {
-            let request = serde_json::from_str(&line.unwrap()).unwrap();
-            self.handle_request(request);
+#[cfg(unix)]
+fn link_count(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::symlink_metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.nlink())
+}
+
+#[cfg(not(unix))]
+fn link_count(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(windows)]
+fn with_forward_slashes(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+
+    // Operate on the raw UTF-16 units so that nothing but the separators is altered.
+    let wide: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .map(|unit| {
+            if unit == u16::from(b'\\') {
+                u16::from(b'/')
+            } else {
+                unit
+            }
+        })
+        .collect();
+    PathBuf::from(OsString::from_wide(&wide))
+}
+
+#[cfg(not(windows))]
+fn with_forward_slashes(path: &Path) -> PathBuf {
+    path.into()
+}
+
+// The events held back by a watch, in the order they were held.
+fn in_order(mut held: Vec<(Instant, Event)>) -> Vec<Event> {
+    held.sort_by_key(|(held_at, _)| *held_at);
+    held.into_iter().map(|(_, event)| event).collect()
+}
+
+// Drop events for paths that `keep` rejects. A rename between a rejected path and a kept one, as
+// editors do when saving atomically through a temporary file, is reported as the creation or
+// deletion of the kept one.
+fn retain_paths(event: Event, mut keep: impl FnMut(&Path) -> bool) -> Option<Event> {
+    match event {
+        Event::Renamed {
+            ref path,
+            ref old_path,
+            ..
+        } => match (keep(old_path), keep(path)) {
+            (true, true) => Some(event),
+            (false, true) => Some(Event::created(path)),
+            (true, false) => Some(Event::deleted(old_path)),
+            (false, false) => None,
+        },
+        Event::Created { ref path, .. }
+        | Event::Modified { ref path, .. }
+        | Event::Deleted { ref path, .. }
+        | Event::Error { ref path, .. } => {
+            if keep(path) {
+                Some(event)
+            } else {
+                None
+            }
         }
+        Event::QueueOverflow
+        | Event::Idle
+        | Event::Summary { .. }
+        | Event::BatchBegin { .. }
+        | Event::BatchEnd { .. }
+        | Event::Rescan { .. }
+        | Event::RootCreated { .. }
+        | Event::Listing { .. }
+        | Event::ListingDelta { .. } => Some(event),
     }
+}
 
-    fn handle_request(&mut self, request: Incoming) {
-        match request {
-            Incoming::Watch {
-                request_id,
-                watch_id,
-                root,
-            } => self.watch(request_id, watch_id, root),
-            Incoming::Unwatch {
-                request_id,
-                watch_id,
-            } => self.unwatch(request_id, watch_id),
-            Incoming::UnwatchAll { request_id } => self.unwatch_all(request_id),
+// notify's `Display` impl relies on the deprecated `Error::description` for I/O errors, which no
+// longer yields anything useful, so we format the underlying `io::Error` ourselves, such as
+// "Permission denied (os error 13)". notify's own message is fine for the other variants.
+fn describe_error(error: &notify::Error) -> String {
+    match error {
+        notify::Error::Io(io_error) => io_error.to_string(),
+        _ => error.to_string(),
+    }
+}
+
+// The watches in ascending id order, to fan out to so that messages concerning several watches are
+// always emitted in the same order, regardless of the order in which the watches were added.
+fn watches_by_id(watches: &mut [Watch]) -> Vec<&mut Watch> {
+    let mut ordered: Vec<&mut Watch> = watches.iter_mut().collect();
+    ordered.sort_by_key(|watch| watch.id);
+    ordered
+}
+
+// Lock a mutex even if a panicking thread poisoned it, so that a panic on the notify thread
+// doesn't bring down the request loop along with it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Every registration that `watches` make with `backend`.
+fn registrations(watches: &[Watch], backend: Backend) -> Vec<(&Path, RecursiveMode)> {
+    watches
+        .iter()
+        .filter(|watch| watch.backend == backend)
+        .flat_map(Watch::registrations)
+        .collect()
+}
+
+// Whether registering `path` with `mode` would be redundant next to `registrations`. Recursive
+// registrations cover the non-recursive ones beneath them, which on Linux would otherwise stop
+// notify from watching directories that get created there, but nested roots are still
+// registered separately.
+fn is_covered(registrations: &[(&Path, RecursiveMode)], path: &Path, mode: RecursiveMode) -> bool {
+    registrations.iter().any(
+        |(registered, registered_mode)| match (registered_mode, mode) {
+            (RecursiveMode::Recursive, RecursiveMode::NonRecursive) => path.starts_with(registered),
+            (RecursiveMode::NonRecursive, RecursiveMode::Recursive) => false,
+            _ => *registered == path,
+        },
+    )
+}
+
+// The registrations that `watches` make with their backends that aren't covered by another one,
+// recursive ones first.
+fn effective_registrations(watches: &[Watch]) -> Vec<(Backend, &Path, RecursiveMode)> {
+    let mut all: Vec<(Backend, &Path, RecursiveMode)> = watches
+        .iter()
+        .flat_map(|watch| {
+            let backend = watch.backend;
+            watch
+                .registrations()
+                .into_iter()
+                .map(move |(path, mode)| (backend, path, mode))
+        })
+        .collect();
+    all.sort_by_key(|(_, _, mode)| *mode);
+
+    let mut effective: Vec<(Backend, &Path, RecursiveMode)> = Vec::new();
+    for (backend, path, mode) in all {
+        let registered: Vec<(&Path, RecursiveMode)> = effective
+            .iter()
+            .filter(|(registered_backend, _, _)| *registered_backend == backend)
+            .map(|(_, path, mode)| (*path, *mode))
+            .collect();
+        if !is_covered(&registered, path, mode) {
+            effective.push((backend, path, mode));
         }
     }
+    effective
+}
 
-    fn watch(&mut self, request_id: RequestId, watch_id: WatchId, root: PathBuf) {
-        let mut watches = self.watches.lock().unwrap();
+// Resolve symlinks in `path`. A path that doesn't exist has its parent resolved instead, and one
+// whose parent doesn't exist either is left as it is.
+fn resolve_path(path: &Path) -> PathBuf {
+    if let Ok(resolved) = fs::canonicalize(path) {
+        return resolved;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match fs::canonicalize(parent) {
+            Ok(parent) => parent.join(name),
+            Err(_) => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    }
+}
 
-        if let Some(watch) = watches.iter_mut().find(|watch| watch.root == root) {
-            watch.ids.push(watch_id);
-            emit_json(Outgoing::OkResponse { request_id });
-        } else {
-            if let Err(error) = self.watcher.watch(&root, RecursiveMode::Recursive) {
-                emit_json(Outgoing::ErrorResponse {
-                    request_id,
-                    description: error.description().to_string(),
-                });
-            } else {
-                watches.push(Watch {
-                    root,
-                    ids: vec![watch_id],
-                });
-                emit_json(Outgoing::OkResponse { request_id });
-            }
+// Resolve `path` as with `resolve_path` on a helper thread, giving up once
+// `--canonicalize-timeout-ms` has passed. The helper is left behind in that case, to finish
+// whenever the file system responds.
+fn resolve_path_in_time(timeout: Option<Duration>, path: &Path) -> Option<PathBuf> {
+    let path = path.to_path_buf();
+    within_canonicalize_timeout(timeout, move || resolve_path(&path))
+}
+
+// Run `resolve`, on a helper thread if there's a `timeout`, in which case `None` is returned once
+// the timeout has passed.
+fn within_canonicalize_timeout<T: Send + 'static>(
+    timeout: Option<Duration>,
+    resolve: impl FnOnce() -> T + Send + 'static,
+) -> Option<T> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Some(resolve()),
+    };
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(resolve());
+    });
+    rx.recv_timeout(timeout).ok()
+}
+
+// The closest ancestor of `path` that exists, if `path` itself doesn't.
+fn existing_ancestor(path: &Path) -> Option<PathBuf> {
+    if path.exists() {
+        return None;
+    }
+    path.ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.exists())
+        .map(Path::to_path_buf)
+}
+
+// The closest directory that contains all of `files`, if there are any and each of them is in a
+// directory.
+fn common_ancestor(files: &[PathBuf]) -> Option<PathBuf> {
+    let mut ancestor = files.first()?.parent()?;
+    for file in files {
+        file.parent()?;
+        while !file.starts_with(ancestor) {
+            ancestor = ancestor.parent()?;
         }
     }
+    Some(ancestor.to_path_buf())
+}
 
-    fn unwatch(&mut self, request_id: RequestId, watch_id: WatchId) {
-        let mut watches = self.watches.lock().unwrap();
-
-        let mut found_id = false;
-        let mut unwatch_error = None;
-        let mut index_to_remove = None;
-
-        for (i, watch) in watches.iter_mut().enumerate() {
-            if let Some(j) = watch.ids.iter().position(|id| *id == watch_id) {
-                found_id = true;
-                watch.ids.remove(j);
-                if watch.ids.is_empty() {
-                    index_to_remove = Some(i);
-                    if let Err(error) = self.watcher.unwatch(&watch.root) {
-                        unwatch_error = Some(error);
-                    }
-                }
+// Start the thread that emits the messages produced from file system events. Hashing file
+// contents happens here rather than on the notify thread so that slow reads don't hold up the
+// delivery of events.
+fn spawn_output_thread(sink: Arc<MessageSink>) -> (Sender<Outgoing>, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel::<Outgoing>();
+    let output_thread = thread::spawn(move || {
+        for mut message in rx {
+            if !message.retain_content_matches() {
+                continue;
             }
+            message.compute_hashes();
+            emit_json(&sink, message);
         }
+    });
+    (tx, output_thread)
+}
 
-        if let Some(i) = index_to_remove {
-            let removed = watches.remove(i);
+// Join `thread` unless it's still running after `timeout`, in which case it's left behind.
+fn join_within(thread: JoinHandle<()>, timeout: Duration) {
+    let give_up_at = Instant::now() + timeout;
+    while !thread.is_finished() && Instant::now() < give_up_at {
+        thread::sleep(TICK_INTERVAL / 10);
+    }
+    if thread.is_finished() {
+        let _ = thread.join();
+    }
+}
 
-            // On Linux, unwatching a directory seems to destroy all watches on descendant
-            // directories, so we rewatch any descendant directories that are being monitored.
-            if cfg!(target_os = "linux") {
-                for watch in watches.iter() {
-                    if watch.root.starts_with(&removed.root) {
-                        if let Err(error) =
-                            self.watcher.watch(&watch.root, RecursiveMode::Recursive)
-                        {
-                            emit_json(Outgoing::ErrorResponse {
-                                request_id,
-                                description: format!(
-                                    "Error re-watching descendant of unwatched directory: {:?}",
-                                    error
-                                ),
-                            });
-                            return;
-                        }
-                    }
-                }
+// Write a keepalive message whenever the sink has been idle for `interval`, so that transports
+// that drop idle connections keep ours open.
+fn spawn_keepalive_thread(sink: Arc<MessageSink>, interval: Duration) {
+    thread::spawn(move || loop {
+        let idle_for = sink.sinks.idle_for();
+        if idle_for < interval {
+            thread::sleep(interval - idle_for);
+            continue;
+        }
+
+        let timestamp_ms = UNIX_EPOCH.elapsed().unwrap_or_default().as_millis() as u64;
+        emit_json(&sink, Outgoing::Keepalive { timestamp_ms });
+    });
+}
+
+fn emit_json(sink: &MessageSink, mut message: Outgoing) {
+    if let Some(path_map) = &sink.path_map {
+        message.map_paths(&|path: &Path| path_map.to_host(path));
+    }
+
+    let event_log = EVENT_LOG.get().filter(|_| {
+        matches!(
+            message,
+            Outgoing::WatchEvents { .. } | Outgoing::WatcherError { .. } | Outgoing::Batch { .. }
+        )
+    });
+
+    // Messages with a binary layout of their own are only serialized as JSON for the event log
+    if sink.protocol == Protocol::Binary {
+        if let Some(frame) = encode_frame(sink, &message) {
+            sink.sinks.write_bytes(&frame);
+            sink.answered(&message);
+            if let Some(event_log) = event_log {
+                event_log.append(&to_json_line(sink, &message));
             }
+            return;
         }
+    }
 
-        if let Some(error) = unwatch_error {
-            emit_json(Outgoing::ErrorResponse {
-                request_id,
-                description: format!("Error unwatching: {:?}", error),
+    let line = to_json_line(sink, &message);
+    match (sink.protocol, sink.format) {
+        (Protocol::Binary, _) => sink.sinks.write_bytes(
+            &binary::Frame::new(binary::JSON)
+                .rest(line.as_bytes())
+                .finish(),
+        ),
+        (_, OutputFormat::Csv) => emit_csv(&sink.sinks, &message),
+        _ => match &sink.event_template {
+            Some(template) => emit_templated(sink, &message, template),
+            None => sink.sinks.write_line(&line),
+        },
+    }
+    sink.answered(&message);
+
+    if let Some(event_log) = event_log {
+        event_log.append(&line);
+    }
+}
+
+// Serialize `message` for writing. Should that fail, the failure is reported on stderr, and the
+// line is replaced by a `watcherError` built by hand, so that clients still get a valid line that
+// tells them something was lost, and the process carries on. The whole message is replaced, so a
+// `watchEvents` message with a single event that fails loses the others along with it. Event paths
+// are serialized lossily, which keeps that from happening to them in particular.
+fn to_json_line(sink: &MessageSink, message: &Outgoing) -> String {
+    serialize_json(sink, message).unwrap_or_else(|error| {
+        let description = format!("Couldn't serialize a message: {}", error);
+        eprintln!("{}", description);
+        serde_json::json!({ "type": "watcherError", "description": description }).to_string()
+    })
+}
+
+fn serialize_json(sink: &MessageSink, message: &Outgoing) -> serde_json::Result<String> {
+    let json_case = sink.json_case;
+    let global_seq = sink.global_seq.as_ref();
+    let event_ids = sink.event_ids.as_ref();
+    if json_case != JsonCase::Camel || global_seq.is_some() || event_ids.is_some() {
+        let mut value = serde_json::to_value(message)?;
+        if let Some(global_seq) = global_seq {
+            for_each_event(&mut value, &mut |event| {
+                event["globalSeq"] = global_seq.fetch_add(1, Ordering::SeqCst).into();
             });
-        } else if found_id {
-            emit_json(Outgoing::OkResponse { request_id });
-        } else {
-            emit_json(Outgoing::ErrorResponse {
-                request_id,
-                description: format!("No watch found for id: {:?}", watch_id),
+        }
+        if let Some(event_ids) = event_ids {
+            for_each_event(&mut value, &mut |event| {
+                event["eventId"] = event_ids.next().into();
             });
         }
+        serde_json::to_string(&json_case.outgoing(value))
+    } else {
+        serde_json::to_string(message)
     }
+}
 
-    fn unwatch_all(&mut self, request_id: RequestId) {
-        let mut watches = self.watches.lock().unwrap();
+// Read the next line of requests into `line`, which is reused from one line to the next since some
+// clients send hundreds of requests at once. An input that can't be read any further ends just as
+// it would at its end.
+fn read_request(
+    reader: &mut impl BufRead,
+    line: &mut Vec<u8>,
+    json_case: JsonCase,
+) -> Option<Result<Incoming, Outgoing>> {
+    line.clear();
+    match reader.read_until(b'\n', line) {
+        Ok(0) => return None,
+        Ok(_) => {}
+        Err(error) => {
+            eprintln!("Error reading requests: {:?}", error);
+            return None;
+        }
+    }
+    while let Some(b'\n' | b'\r') = line.last() {
+        line.pop();
+    }
+    Some(match std::str::from_utf8(line) {
+        Ok(text) => {
+            parse_json(text, json_case).map_err(|error| invalid_request(text, error, json_case))
+        }
+        Err(error) => Err(invalid_encoding(line, error, json_case)),
+    })
+}
 
-        for watch in watches.drain(..) {
-            if let Err(error) = self.watcher.unwatch(&watch.root) {
-                emit_json(Outgoing::ErrorResponse {
-                    request_id,
-                    description: format!("Error unwatching {:?}: {:?}", watch.root, error),
-                });
-                return;
-            }
+// Read the next frame of requests with `--protocol binary`. A frame cut short by the end of the
+// input ends it just the same.
+fn read_frame_request(
+    reader: &mut impl BufRead,
+    json_case: JsonCase,
+) -> Option<Result<Incoming, Outgoing>> {
+    let (opcode, payload) = binary::read_frame(reader).ok().flatten()?;
+    let payload = match payload {
+        Ok(payload) => payload,
+        Err(too_long) => {
+            let description = too_long.to_string();
+            return Some(Err(invalid_frame(
+                opcode,
+                &too_long.head,
+                description,
+                json_case,
+            )));
+        }
+    };
+    if opcode == binary::JSON {
+        if let Err(error) = std::str::from_utf8(&payload) {
+            return Some(Err(invalid_encoding(&payload, error, json_case)));
         }
+    }
+    Some(
+        decode_frame(opcode, &payload, json_case)
+            .map_err(|description| invalid_frame(opcode, &payload, description, json_case)),
+    )
+}
 
-        emit_json(Outgoing::OkResponse { request_id });
+// Decode a request framed with `--protocol binary`.
+fn decode_frame(opcode: u8, payload: &[u8], json_case: JsonCase) -> Result<Incoming, String> {
+    if opcode == binary::JSON {
+        let text = std::str::from_utf8(payload).map_err(|error| error.to_string())?;
+        return parse_json(text, json_case).map_err(|error| error.to_string());
     }
+
+    let mut payload = binary::Payload::new(payload);
+    let request_id = payload.u64()? as RequestId;
+    let request = match opcode {
+        binary::WATCH => {
+            let watch_id = payload.u64()? as WatchId;
+            let root = binary::path_from_bytes(payload.rest());
+            return Ok(Incoming::Watch {
+                request_id,
+                spec: WatchSpec {
+                    watch_id,
+                    root,
+                    since_ms: None,
+                    options: WatchOptions::default(),
+                },
+                inherit_from: None,
+            });
+        }
+        binary::UNWATCH => Incoming::Unwatch {
+            request_id,
+            watch_id: payload.u64()? as WatchId,
+        },
+        binary::UNWATCH_ALL => Incoming::UnwatchAll { request_id },
+        binary::FLUSH => Incoming::Flush { request_id },
+        binary::PAUSE_ALL => Incoming::PauseAll { request_id },
+        binary::RESUME_ALL => Incoming::ResumeAll { request_id },
+        _ => return Err(format!("Unknown opcode {}", opcode)),
+    };
+    payload.finish()?;
+    Ok(request)
 }
 
-impl Watch {
-    fn notify(&self, events: &[DebouncedEvent]) {
-        let mut batch = Vec::new();
+impl MessageSink {
+    // Written as plain JSON lines
+    fn new(sinks: SharedSink) -> Self {
+        Self {
+            sinks,
+            protocol: Protocol::Json,
+            format: OutputFormat::Json,
+            event_template: None,
+            path_map: None,
+            json_case: JsonCase::Camel,
+            global_seq: None,
+            event_ids: None,
+            latencies: None,
+        }
+    }
 
-        for event in events {
-            match event {
-                DebouncedEvent::Create(path) => {
-                    if path.starts_with(&self.root) {
-                        batch.push(Event::created(path));
-                    }
-                }
-                DebouncedEvent::Write(path) => {
-                    if path.starts_with(&self.root) {
-                        batch.push(Event::modified(path));
-                    }
-                }
-                DebouncedEvent::Remove(path) => {
-                    if path.starts_with(&self.root) {
-                        batch.push(Event::deleted(path));
-                    }
-                }
-                DebouncedEvent::Rename(old_path, new_path) => {
-                    match (
-                        old_path.starts_with(&self.root),
-                        new_path.starts_with(&self.root),
-                    ) {
-                        (true, true) => batch.push(Event::renamed(old_path, new_path)),
-                        (true, false) => batch.push(Event::deleted(old_path)),
-                        (false, true) => batch.push(Event::created(new_path)),
-                        (false, false) => {}
+    // Finish measuring the latency of the requests that `message` is the first response to.
+    fn answered(&self, message: &Outgoing) {
+        if let Some(latencies) = &self.latencies {
+            let messages = match message {
+                Outgoing::Batch { messages } => messages.as_slice(),
+                _ => std::slice::from_ref(message),
+            };
+            for request_id in messages.iter().filter_map(Outgoing::request_id) {
+                lock(latencies).finish(request_id);
+            }
+        }
+    }
+}
+
+// Encode `message` for `--protocol binary`, unless it has details that only JSON can hold. Events
+// get the extra fields of `--global-seq` and `--event-ids` in JSON, so they're left to it then.
+fn encode_frame(sink: &MessageSink, message: &Outgoing) -> Option<Vec<u8>> {
+    match message {
+        Outgoing::OkResponse {
+            request_id,
+            unwatched_subdirs,
+            affected_watches: None,
+            reason: None,
+            watch_id: None,
+        } if unwatched_subdirs.is_empty() => Some(
+            binary::Frame::new(binary::OK_RESPONSE)
+                .u64(*request_id as u64)
+                .finish(),
+        ),
+        Outgoing::ErrorResponse {
+            request_id,
+            description,
+            code: None,
+        } => Some(
+            binary::Frame::new(binary::ERROR_RESPONSE)
+                .u64(*request_id as u64)
+                .rest(description.as_bytes())
+                .finish(),
+        ),
+        Outgoing::WatchEvents { watch_id, events }
+            if sink.global_seq.is_none() && sink.event_ids.is_none() =>
+        {
+            let frame = binary::Frame::new(binary::WATCH_EVENTS)
+                .u64(*watch_id as u64)
+                .u32(events.len() as u32);
+            events
+                .iter()
+                .try_fold(frame, |frame, event| event.encode(frame))
+                .map(binary::Frame::finish)
+        }
+        _ => None,
+    }
+}
+
+// Write a row for each event in `message`, for `--format csv`. Errors go to stderr instead, and
+// everything else, including the responses to requests, is left out.
+fn emit_csv(sink: &SharedSink, message: &Outgoing) {
+    match message {
+        Outgoing::WatchEvents { watch_id, events } => {
+            let watch_id = watch_id.to_string();
+            for event in events {
+                let event = match serde_json::to_value(event) {
+                    Ok(event) => event,
+                    Err(error) => {
+                        eprintln!("Couldn't serialize an event: {}", error);
+                        continue;
                     }
-                }
-                DebouncedEvent::Error(error, path) => {
-                    if let Some(path) = path {
-                        if path.starts_with(&self.root) {
-                            batch.push(Event::error(path, error));
-                        }
+                };
+                let field = |name: &str| event[name].as_str().unwrap_or_default().to_string();
+                sink.write_line(&csv::row(&[
+                    &field("action"),
+                    &watch_id,
+                    &field("path"),
+                    &field("oldPath"),
+                ]));
+            }
+        }
+        Outgoing::Batch { messages } => {
+            for message in messages {
+                emit_csv(sink, message);
+            }
+        }
+        Outgoing::WatcherError { description } | Outgoing::ErrorResponse { description, .. } => {
+            eprintln!("{}", description)
+        }
+        _ => {}
+    }
+}
+
+// Write a line made from `template` for each event in `message`, for `--event-template`, and
+// everything else as JSON. The events in a batch are written one by one as well.
+fn emit_templated(sink: &MessageSink, message: &Outgoing, template: &EventTemplate) {
+    match message {
+        Outgoing::WatchEvents { watch_id, events } => {
+            let timestamp_ms = UNIX_EPOCH.elapsed().unwrap_or_default().as_millis() as u64;
+            for event in events {
+                match serde_json::to_value(event) {
+                    Ok(event) => {
+                        sink.sinks
+                            .write_line(&template.render(&event, *watch_id, timestamp_ms))
                     }
+                    Err(error) => eprintln!("Couldn't serialize an event: {}", error),
                 }
-                DebouncedEvent::NoticeWrite(_path) => {}
-                DebouncedEvent::NoticeRemove(_path) => {}
-                DebouncedEvent::Chmod(_path) => {}
-                DebouncedEvent::Rescan => {}
             }
         }
-
-        if !batch.is_empty() {
-            for id in &self.ids {
-                emit_json(Outgoing::WatchEvents {
-                    watch_id: *id,
-                    events: batch.clone(),
-                });
+        Outgoing::Batch { messages } => {
+            for message in messages {
+                emit_templated(sink, message, template);
             }
         }
+        _ => sink.sinks.write_line(&to_json_line(sink, message)),
     }
 }
 
-impl Event {
-    fn modified(path: &Path) -> Self {
-        Event::Modified { path: path.into() }
+// The watch asked for with `NOTIFY_WATCH_ROOT`, ignoring the comma-separated file name patterns in
+// `NOTIFY_WATCH_IGNORE`. It's registered after the watches in the config file, with the next
+// watch id that they leave free.
+fn env_watch(config_watches: &[WatchSpec]) -> Option<WatchSpec> {
+    let root = env::var_os("NOTIFY_WATCH_ROOT").filter(|root| !root.is_empty())?;
+    let ignore: Vec<String> = env::var("NOTIFY_WATCH_IGNORE")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(String::from)
+        .collect();
+
+    let mut options = WatchOptions::default();
+    if !ignore.is_empty() {
+        options.profiles.push(FilterProfile::ignoring(ignore));
     }
-    fn created(path: &Path) -> Self {
-        Event::Created { path: path.into() }
+    Some(WatchSpec {
+        watch_id: config_watches
+            .iter()
+            .map(|spec| spec.watch_id + 1)
+            .max()
+            .unwrap_or(0),
+        root: resolve_path(Path::new(&root)),
+        since_ms: None,
+        options,
+    })
+}
+
+// Call `f` with each event in a serialized message, such as to add a `globalSeq` to it. Events
+// are only ever emitted by the output thread, so they're visited in the order they're written.
+fn for_each_event(message: &mut serde_json::Value, f: &mut impl FnMut(&mut serde_json::Value)) {
+    match message["type"].as_str() {
+        Some("watchEvents") => {
+            if let Some(events) = message["events"].as_array_mut() {
+                events.iter_mut().for_each(&mut *f);
+            }
+        }
+        Some("batch") => {
+            if let Some(messages) = message["messages"].as_array_mut() {
+                for message in messages {
+                    for_each_event(message, f);
+                }
+            }
+        }
+        _ => {}
     }
-    fn deleted(path: &Path) -> Self {
-        Event::Deleted { path: path.into() }
+}
+
+fn load_config(path: &Path, json_case: JsonCase) -> Result<Config, String> {
+    let contents = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    parse_json(&contents, json_case).map_err(|error| error.to_string())
+}
+
+// Just the id of a request, which is what's left to make out of one that can't be handled.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RequestHeader {
+    request_id: RequestId,
+}
+
+// The message for a request line that isn't valid UTF-8. The line is still decoded lossily in
+// the hope of finding its request id, so that its sender gets an error response, and otherwise
+// there's nobody to respond to, so the error is reported for the whole watcher instead.
+fn invalid_encoding(line: &[u8], error: std::str::Utf8Error, json_case: JsonCase) -> Outgoing {
+    let description = format!(
+        "Request isn't valid UTF-8: invalid byte at offset {}",
+        error.valid_up_to()
+    );
+    match parse_json::<RequestHeader>(&String::from_utf8_lossy(line), json_case) {
+        Ok(RequestHeader { request_id }) => {
+            Outgoing::error_response_with_code(request_id, ErrorCode::InvalidEncoding, description)
+        }
+        Err(_) => Outgoing::WatcherError { description },
     }
-    fn renamed(old_path: &Path, new_path: &Path) -> Self {
-        Event::Renamed {
-            path: new_path.into(),
-            old_path: old_path.into(),
+}
+
+// The message for a request line that isn't a request, which is answered like one that isn't
+// valid UTF-8.
+fn invalid_request(text: &str, error: serde_json::Error, json_case: JsonCase) -> Outgoing {
+    let description = format!("Invalid request: {}", error);
+    match parse_json::<RequestHeader>(text, json_case) {
+        Ok(RequestHeader { request_id }) => {
+            Outgoing::error_response_with_code(request_id, ErrorCode::InvalidRequest, description)
         }
+        Err(_) => Outgoing::WatcherError { description },
     }
-    fn error(path: &Path, error: &notify::Error) -> Self {
-        Event::Error {
-            path: path.into(),
-            description: String::from(error.description()),
+}
+
+// The reply to a frame that couldn't be decoded: an `errorResponse` if its request id can still be
+// made out, or else a `watcherError`.
+fn invalid_frame(opcode: u8, payload: &[u8], description: String, json_case: JsonCase) -> Outgoing {
+    let description = format!("Invalid frame: {}", description);
+    let request_id = if opcode == binary::JSON {
+        parse_json::<RequestHeader>(&String::from_utf8_lossy(payload), json_case)
+            .ok()
+            .map(|request| request.request_id)
+    } else {
+        binary::Payload::new(payload)
+            .u64()
+            .ok()
+            .map(|request_id| request_id as RequestId)
+    };
+    match request_id {
+        Some(request_id) => {
+            Outgoing::error_response_with_code(request_id, ErrorCode::InvalidFrame, description)
         }
+        None => Outgoing::WatcherError { description },
     }
 }
 
-fn emit_json(message: Outgoing) {
-    println!("{}", &serde_json::to_string(&message).unwrap());
+// Parse a request or config file, accepting the field names selected with `--json-case`.
+fn parse_json<T: DeserializeOwned>(text: &str, json_case: JsonCase) -> serde_json::Result<T> {
+    if json_case == JsonCase::Camel {
+        return serde_json::from_str(text);
+    }
+    let value = serde_json::from_str(text)?;
+    serde_json::from_value(json_case.incoming(value))
 }
 
 fn main() {
     let opt = Opt::from_args();
 
-    if let Some(poll_interval) = opt.poll_interval {
-        match Supervisor::<PollWatcher>::new(Duration::from_millis(poll_interval)) {
-            Ok(mut supervisor) => supervisor.handle_requests(),
-            Err(error) => {
-                emit_json(Outgoing::WatcherError {
-                    description: String::from(error.description()),
-                });
-                eprintln!("Error creating notify watcher: {:?}", error);
+    if let Some(path) = &opt.event_log {
+        match EventLog::open(path.clone(), opt.event_log_max_bytes) {
+            Ok(event_log) => {
+                let _ = EVENT_LOG.set(event_log);
             }
+            Err(error) => eprintln!("Error opening event log {:?}: {:?}", path, error),
         }
-    } else {
-        match Supervisor::<RecommendedWatcher>::new(Duration::from_millis(100)) {
-            Ok(mut supervisor) => supervisor.handle_requests(),
+    }
+
+    if opt.format == OutputFormat::Csv && opt.protocol == Protocol::Binary {
+        eprintln!("--format csv can't be combined with --protocol binary");
+        process::exit(1);
+    }
+    if opt.event_template.is_some()
+        && (opt.format == OutputFormat::Csv || opt.protocol == Protocol::Binary)
+    {
+        eprintln!("--event-template can't be combined with --format csv or --protocol binary");
+        process::exit(1);
+    }
+
+    let mut initial_watches = match &opt.config {
+        Some(path) => match load_config(path, opt.json_case) {
+            Ok(config) => config.watches,
             Err(error) => {
-                emit_json(Outgoing::WatcherError {
-                    description: String::from(error.description()),
-                });
-                eprintln!("Error creating notify watcher: {:?}", error);
+                eprintln!("Error reading config file {:?}: {}", path, error);
+                process::exit(1);
             }
-        }
+        },
+        None => Vec::new(),
+    };
+    if let Some(spec) = env_watch(&initial_watches) {
+        initial_watches.push(spec);
+    }
+
+    let default_backend = match opt.poll_interval {
+        Some(interval_ms) => Backend::Poll { interval_ms },
+        None => Backend::Native,
     };
+
+    let mut builder = Supervisor::builder()
+        .backend(default_backend)
+        .debounce(Duration::from_millis(opt.debounce_ms))
+        .batch_emit(opt.batch_emit)
+        .single_use_ids(opt.single_use_ids)
+        .measure_latency(opt.measure_latency)
+        .drain(Duration::from_millis(opt.drain_ms))
+        .protocol(opt.protocol)
+        .format(opt.format)
+        .json_case(opt.json_case)
+        .global_seq(opt.global_seq)
+        .event_ids(opt.event_ids)
+        .sink(Box::new(StdoutSink));
+    if let Some(template) = opt.event_template {
+        builder = builder.event_template(template);
+    }
+    if !opt.path_map.is_empty() {
+        builder = builder.path_map(PathMap::new(opt.path_map.clone()));
+    }
+    if let Some(timeout_ms) = opt.canonicalize_timeout_ms {
+        builder = builder.canonicalize_timeout(Duration::from_millis(timeout_ms));
+    }
+    if let Some(max_watches) = opt.max_watches {
+        builder = builder.max_watches(max_watches);
+    }
+    if let Some(keepalive_ms) = opt.keepalive_ms.filter(|keepalive_ms| *keepalive_ms > 0) {
+        builder = builder.keepalive(Duration::from_millis(keepalive_ms));
+    }
+    if let Some(path) = &opt.tee {
+        match FileSink::open(path.clone()) {
+            Ok(sink) => builder = builder.add_sink(Box::new(sink)),
+            Err(error) => eprintln!("Error opening {:?}: {:?}", path, error),
+        }
+    }
+
+    match builder.build() {
+        Ok(mut supervisor) => {
+            // Written through the sinks, so that the file written with `--tee` starts with it too
+            if opt.format == OutputFormat::Csv {
+                supervisor.sink.sinks.write_line(csv::HEADER);
+            }
+            supervisor.preload(initial_watches);
+            supervisor.handle_requests();
+            supervisor.shut_down();
+        }
+        Err(error) => {
+            let stdout = MessageSink {
+                protocol: opt.protocol,
+                format: opt.format,
+                json_case: opt.json_case,
+                ..MessageSink::new(SharedSink::new(vec![Box::new(StdoutSink)]))
+            };
+            emit_json(
+                &stdout,
+                Outgoing::WatcherError {
+                    description: describe_error(&error),
+                },
+            );
+            eprintln!("Error creating notify watcher: {:?}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::io::Cursor;
+    use std::sync::atomic::AtomicUsize;
+
+    // Counts allocations for `reads_requests_into_a_reused_buffer`
+    struct CountingAllocator;
+
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn count_allocations(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.load(Ordering::SeqCst);
+        f();
+        ALLOCATIONS.load(Ordering::SeqCst) - before
+    }
+
+    // Other tests allocate at the same time, so this is only meaningful on its own:
+    // `cargo test -- --ignored --test-threads=1`
+    #[test]
+    #[ignore]
+    fn reads_requests_into_a_reused_buffer() {
+        let input: String = (0..1000)
+            .map(|request_id| format!("{{\"type\":\"version\",\"requestId\":{}}}\n", request_id))
+            .collect();
+
+        let reused = count_allocations(|| {
+            let mut reader = Cursor::new(input.as_bytes());
+            let mut line = Vec::new();
+            while let Some(request) = read_request(&mut reader, &mut line, JsonCase::Camel) {
+                assert!(request.is_ok());
+            }
+        });
+        let fresh = count_allocations(|| {
+            for line in Cursor::new(input.as_bytes()).lines() {
+                assert!(parse_json::<Incoming>(&line.unwrap(), JsonCase::Camel).is_ok());
+            }
+        });
+
+        eprintln!(
+            "{} allocations with a reused buffer, {} with a line each",
+            reused, fresh
+        );
+        assert!(reused + 900 < fresh);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaces_a_message_that_cant_be_serialized_with_a_watcher_error() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let message = Outgoing::OkResponse {
+            request_id: 1,
+            unwatched_subdirs: vec![PathBuf::from(OsStr::from_bytes(b"\xff"))],
+            affected_watches: None,
+            reason: None,
+            watch_id: None,
+        };
+        let sink = MessageSink::new(SharedSink::new(Vec::new()));
+        let line: serde_json::Value = serde_json::from_str(&to_json_line(&sink, &message)).unwrap();
+        assert_eq!(line["type"], "watcherError");
+        assert!(line["description"].as_str().unwrap().contains("serialize"));
+    }
 }
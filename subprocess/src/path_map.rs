@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A pair of prefixes under which the same files are visible to the client (the host) and to us
+/// (the container), as given to `--path-map <host-prefix>:<container-prefix>`.
+#[derive(Clone, Debug)]
+pub struct PathMapping {
+    host: PathBuf,
+    container: PathBuf,
+}
+
+/// Translates paths between the client's view of the file system and ours. Paths that aren't
+/// under any of the mapped prefixes are passed through unchanged.
+#[derive(Debug, Default)]
+pub struct PathMap {
+    mappings: Vec<PathMapping>,
+}
+
+impl FromStr for PathMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Split on the last colon so that Windows host paths like `C:\src` can be mapped
+        match s.rsplit_once(':') {
+            Some((host, container)) if !host.is_empty() && !container.is_empty() => Ok(Self {
+                host: PathBuf::from(host),
+                container: PathBuf::from(container),
+            }),
+            _ => Err(format!(
+                "Expected <host-prefix>:<container-prefix>, got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+impl PathMap {
+    pub fn new(mappings: Vec<PathMapping>) -> Self {
+        Self { mappings }
+    }
+
+    pub fn to_container(&self, path: &Path) -> PathBuf {
+        self.translate(path, |mapping| (&mapping.host, &mapping.container))
+    }
+
+    pub fn to_host(&self, path: &Path) -> PathBuf {
+        self.translate(path, |mapping| (&mapping.container, &mapping.host))
+    }
+
+    // Replace the longest matching prefix
+    fn translate(
+        &self,
+        path: &Path,
+        prefixes: impl Fn(&PathMapping) -> (&PathBuf, &PathBuf),
+    ) -> PathBuf {
+        self.mappings
+            .iter()
+            .map(&prefixes)
+            .filter_map(|(from, to)| {
+                path.strip_prefix(from)
+                    .ok()
+                    .map(|rest| (from.components().count(), to, rest))
+            })
+            .max_by_key(|(depth, _, _)| *depth)
+            .map(|(_, to, rest)| {
+                if rest.as_os_str().is_empty() {
+                    to.clone()
+                } else {
+                    to.join(rest)
+                }
+            })
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+}
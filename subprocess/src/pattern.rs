@@ -0,0 +1,213 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A regular expression supporting a commonly used subset of the usual syntax: literals, `.`,
+/// bracketed classes like `[a-z]` and `[^0-9]`, the `\d`, `\w`, and `\s` classes and their
+/// negations, the `*`, `+`, and `?` quantifiers, the `^` and `$` anchors, groups, and `|`.
+/// Matching backtracks, so it's only suitable for short inputs.
+#[derive(Debug)]
+pub struct Pattern {
+    alternatives: Vec<Vec<Node>>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+impl Pattern {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut chars = source.chars().peekable();
+        let alternatives = parse_alternatives(&mut chars)
+            .map_err(|error| format!("Invalid pattern {:?}: {}", source, error))?;
+        match chars.next() {
+            None => Ok(Self { alternatives }),
+            Some(_) => Err(format!("Invalid pattern {:?}: unmatched ')'", source)),
+        }
+    }
+
+    /// Whether the pattern matches anywhere in `text`.
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        (0..=text.len()).any(|start| {
+            self.alternatives
+                .iter()
+                .any(|sequence| match_sequence(sequence, &text, start, &|_| true))
+        })
+    }
+}
+
+fn parse_alternatives(chars: &mut Peekable<Chars>) -> Result<Vec<Vec<Node>>, String> {
+    let mut alternatives = vec![parse_sequence(chars)?];
+    while chars.peek() == Some(&'|') {
+        chars.next();
+        alternatives.push(parse_sequence(chars)?);
+    }
+    Ok(alternatives)
+}
+
+fn parse_sequence(chars: &mut Peekable<Chars>) -> Result<Vec<Node>, String> {
+    let mut sequence = Vec::new();
+    while let Some(&c) = chars.peek() {
+        if c == '|' || c == ')' {
+            break;
+        }
+        chars.next();
+        let node = match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                let alternatives = parse_alternatives(chars)?;
+                if chars.next() != Some(')') {
+                    return Err("unmatched '('".into());
+                }
+                Node::Group(alternatives)
+            }
+            '[' => parse_class(chars)?,
+            '\\' => parse_escape(chars)?,
+            '*' | '+' | '?' => return Err(format!("nothing to repeat before '{}'", c)),
+            c => Node::Char(c),
+        };
+        let node = match chars.peek() {
+            Some('*') => repeat(chars, node, 0, None),
+            Some('+') => repeat(chars, node, 1, None),
+            Some('?') => repeat(chars, node, 0, Some(1)),
+            _ => node,
+        };
+        sequence.push(node);
+    }
+    Ok(sequence)
+}
+
+fn repeat(chars: &mut Peekable<Chars>, node: Node, min: usize, max: Option<usize>) -> Node {
+    chars.next();
+    Node::Repeat {
+        node: Box::new(node),
+        min,
+        max,
+    }
+}
+
+fn parse_escape(chars: &mut Peekable<Chars>) -> Result<Node, String> {
+    let class = |ranges: &[(char, char)], negated| Node::Class {
+        ranges: ranges.to_vec(),
+        negated,
+    };
+    const DIGIT: &[(char, char)] = &[('0', '9')];
+    const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+    const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+    match chars.next() {
+        Some('d') => Ok(class(DIGIT, false)),
+        Some('D') => Ok(class(DIGIT, true)),
+        Some('w') => Ok(class(WORD, false)),
+        Some('W') => Ok(class(WORD, true)),
+        Some('s') => Ok(class(SPACE, false)),
+        Some('S') => Ok(class(SPACE, true)),
+        Some('t') => Ok(Node::Char('\t')),
+        Some(c) if !c.is_ascii_alphanumeric() => Ok(Node::Char(c)),
+        Some(c) => Err(format!("unsupported escape '\\{}'", c)),
+        None => Err("trailing '\\'".into()),
+    }
+}
+
+fn parse_class(chars: &mut Peekable<Chars>) -> Result<Node, String> {
+    let negated = chars.peek() == Some(&'^');
+    if negated {
+        chars.next();
+    }
+
+    let mut ranges = Vec::new();
+    loop {
+        let start = match chars.next() {
+            // A leading `]` is taken literally
+            Some(']') if !ranges.is_empty() => break,
+            Some('\\') => chars.next().ok_or("trailing '\\'")?,
+            Some(c) => c,
+            None => return Err("unmatched '['".into()),
+        };
+        let mut lookahead = chars.clone();
+        if lookahead.next() == Some('-') && !matches!(lookahead.peek(), Some(']') | None) {
+            chars.next();
+            let end = chars.next().unwrap();
+            if end < start {
+                return Err(format!("invalid range '{}-{}'", start, end));
+            }
+            ranges.push((start, end));
+        } else {
+            ranges.push((start, start));
+        }
+    }
+    Ok(Node::Class { ranges, negated })
+}
+
+// Match `sequence` against `text` starting at `pos`, calling `rest` with the position after each
+// way of matching until it accepts one.
+fn match_sequence(
+    sequence: &[Node],
+    text: &[char],
+    pos: usize,
+    rest: &dyn Fn(usize) -> bool,
+) -> bool {
+    let (node, remaining) = match sequence.split_first() {
+        Some(split) => split,
+        None => return rest(pos),
+    };
+    let next = |pos| match_sequence(remaining, text, pos, rest);
+
+    match node {
+        Node::Start => pos == 0 && next(pos),
+        Node::End => pos == text.len() && next(pos),
+        Node::Group(alternatives) => alternatives
+            .iter()
+            .any(|alternative| match_sequence(alternative, text, pos, &next)),
+        Node::Repeat { node, min, max } => match_repeat(node, *min, *max, 0, text, pos, &next),
+        node => pos < text.len() && matches_char(node, text[pos]) && next(pos + 1),
+    }
+}
+
+// Greedily match as many repetitions of `node` as possible, backing off until `rest` accepts.
+fn match_repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    count: usize,
+    text: &[char],
+    pos: usize,
+    rest: &dyn Fn(usize) -> bool,
+) -> bool {
+    let more = max.is_none_or(|max| count < max)
+        && match_sequence(std::slice::from_ref(node), text, pos, &|after| {
+            // Repetitions that don't consume anything could go on forever
+            after != pos && match_repeat(node, min, max, count + 1, text, after, rest)
+        });
+    more || (count >= min && rest(pos))
+}
+
+fn matches_char(node: &Node, c: char) -> bool {
+    match node {
+        Node::Char(expected) => c == *expected,
+        Node::Any => true,
+        Node::Class { ranges, negated } => {
+            ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&c))
+                != *negated
+        }
+        _ => false,
+    }
+}
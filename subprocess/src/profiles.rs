@@ -0,0 +1,103 @@
+use crate::temp_files;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Filtering rules for the part of a watch's tree under `prefix`. A watch with profiles emits
+/// events for a path according to the first profile whose prefix it's under, and drops the
+/// events for paths under none of them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterProfile {
+    /// A directory relative to the watch's root, or an absolute one
+    pub prefix: PathBuf,
+    /// Only emit paths whose file name matches one of these patterns
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include: Option<Vec<String>>,
+    /// Drop paths whose file name matches one of these patterns
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ignore: Vec<String>,
+    /// Only emit paths with one of these extensions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extensions: Option<Vec<String>>,
+}
+
+impl FilterProfile {
+    /// A profile for the whole tree that only drops paths matching `patterns`.
+    pub fn ignoring(patterns: Vec<String>) -> Self {
+        Self {
+            prefix: PathBuf::new(),
+            include: None,
+            ignore: patterns,
+            extensions: None,
+        }
+    }
+
+    /// A profile for the whole tree that drops paths matching the patterns in the rules file at
+    /// `path`, one per line. Blank lines and lines starting with `#` are skipped.
+    pub fn ignoring_rules_in(path: &Path) -> io::Result<Self> {
+        let patterns = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+        Ok(Self::ignoring(patterns))
+    }
+
+    /// A profile for the whole tree with the given rules.
+    pub fn matching(
+        include: Option<Vec<String>>,
+        ignore: Vec<String>,
+        extensions: Option<Vec<String>>,
+    ) -> Self {
+        Self {
+            prefix: PathBuf::new(),
+            include,
+            ignore,
+            extensions,
+        }
+    }
+
+    /// The profile with a relative `prefix` resolved against `root`.
+    pub fn resolve(&self, root: &Path) -> Self {
+        Self {
+            prefix: root.join(&self.prefix),
+            ..self.clone()
+        }
+    }
+
+    fn allows(&self, path: &Path) -> bool {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy())
+            .unwrap_or_default();
+
+        self.include.as_ref().is_none_or(|patterns| {
+            patterns
+                .iter()
+                .any(|pattern| temp_files::matches(pattern, &name))
+        }) && !self
+            .ignore
+            .iter()
+            .any(|pattern| temp_files::matches(pattern, &name))
+            && self.extensions.as_ref().is_none_or(|extensions| {
+                extensions
+                    .iter()
+                    .any(|expected| expected.trim_start_matches('.') == extension)
+            })
+    }
+}
+
+/// Whether `profiles` allow events for `path`. The profiles should have been resolved already.
+pub fn allows(profiles: &[FilterProfile], path: &Path) -> bool {
+    profiles
+        .iter()
+        .find(|profile| path.starts_with(&profile.prefix))
+        .is_some_and(|profile| profile.allows(path))
+}
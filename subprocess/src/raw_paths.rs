@@ -0,0 +1,108 @@
+use serde::{Serialize, Serializer};
+use std::path::{Path, PathBuf};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Whether an event's paths could be represented as JSON strings.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PathEncoding {
+    Utf8,
+    Bytes,
+}
+
+/// The exact bytes of an event's paths, for watches that ask for them with `binaryPaths`. Left
+/// empty otherwise, in which case nothing is serialized.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RawPaths {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path_encoding: Option<PathEncoding>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path_bytes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_path_bytes: Option<String>,
+}
+
+impl RawPaths {
+    /// Describe `path` and, for renames, `old_path`. If either of them isn't valid UTF-8, the
+    /// encoding is `bytes` and both are included in base64.
+    pub fn new(path: &Path, old_path: Option<&Path>) -> Self {
+        let is_utf8 = path.to_str().is_some() && old_path.is_none_or(|old| old.to_str().is_some());
+        if is_utf8 {
+            Self {
+                path_encoding: Some(PathEncoding::Utf8),
+                path_bytes: None,
+                old_path_bytes: None,
+            }
+        } else {
+            Self {
+                path_encoding: Some(PathEncoding::Bytes),
+                path_bytes: Some(base64(&path_bytes(path))),
+                old_path_bytes: old_path.map(|old| base64(&path_bytes(old))),
+            }
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.path_encoding.is_some()
+    }
+}
+
+/// Serialize a path as a string, replacing anything that isn't valid UTF-8 with U+FFFD rather
+/// than failing. Watches with `binaryPaths` get the exact bytes alongside.
+pub fn serialize_lossy<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+/// Serialize a list of paths with `serialize_lossy`.
+pub fn serialize_all_lossy<S: Serializer>(
+    paths: &[PathBuf],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(paths.iter().map(|path| path.to_string_lossy()))
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().to_vec()
+}
+
+// Windows paths are sequences of UTF-16 units that needn't be valid UTF-16, so pass those on as
+// little-endian pairs of bytes.
+#[cfg(windows)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+
+    path.as_os_str()
+        .encode_wide()
+        .flat_map(|unit| unit.to_le_bytes().to_vec())
+        .collect()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().as_bytes().to_vec()
+}
+
+// Standard base64 with padding, as described in RFC 4648.
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(char::from(BASE64_ALPHABET[index as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,112 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// A destination for outgoing messages, each of which is handed over as a single line of JSON
+/// without its trailing newline, or as a whole frame with `--protocol binary`.
+pub trait EventSink: Send {
+    fn write_line(&mut self, line: &str);
+    fn write_bytes(&mut self, bytes: &[u8]);
+}
+
+/// The sinks that several threads write to. Each line is written to every sink in turn while
+/// holding a lock, so lines from different threads are never interleaved. Sinks deal with their
+/// own errors, so one that fails doesn't keep the others from being written to.
+pub struct SharedSink {
+    inner: Mutex<(Vec<Box<dyn EventSink>>, Instant)>,
+}
+
+/// Writes to stdout, where the client reads messages from.
+pub struct StdoutSink;
+
+/// Appends to a file, such as to keep a record of everything sent to the client. Once writing
+/// fails, the error is reported on stderr and nothing more is written.
+pub struct FileSink {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+// Write each message on its own line. A client that went away isn't an error worth reporting,
+// since there's nobody left to report it to.
+impl<W: Write + Send> EventSink for W {
+    fn write_line(&mut self, line: &str) {
+        let _ = writeln!(self, "{}", line);
+    }
+
+    // Without a newline to flush line-buffered output, frames have to be flushed explicitly
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let _ = self.write_all(bytes).and_then(|()| self.flush());
+    }
+}
+
+impl EventSink for StdoutSink {
+    fn write_line(&mut self, line: &str) {
+        io::stdout().write_line(line);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        io::stdout().write_bytes(bytes);
+    }
+}
+
+impl FileSink {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file: Some(file),
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if let Some(file) = &mut self.file {
+            if let Err(error) = file.write_all(bytes) {
+                eprintln!("Error writing {:?}: {:?}", self.path, error);
+                self.file = None;
+            }
+        }
+    }
+}
+
+impl EventSink for FileSink {
+    // Written at once, so that a line is never torn apart by a failure halfway through
+    fn write_line(&mut self, line: &str) {
+        self.write(format!("{}\n", line).as_bytes());
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write(bytes);
+    }
+}
+
+impl SharedSink {
+    pub fn new(sinks: Vec<Box<dyn EventSink>>) -> Self {
+        Self {
+            inner: Mutex::new((sinks, Instant::now())),
+        }
+    }
+
+    pub fn write_line(&self, line: &str) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        for sink in &mut inner.0 {
+            sink.write_line(line);
+        }
+        inner.1 = Instant::now();
+    }
+
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        for sink in &mut inner.0 {
+            sink.write_bytes(bytes);
+        }
+        inner.1 = Instant::now();
+    }
+
+    /// How long it's been since anything was written.
+    pub fn idle_for(&self) -> Duration {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.1.elapsed()
+    }
+}
@@ -0,0 +1,64 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The number of paths whose size is remembered per watch. Once this many are cached, the cache
+/// is cleared and starts over, so the next modification of each path is of an unknown kind.
+const MAX_CACHED_PATHS: usize = 10_000;
+
+/// How a modification changed the size of a file, which tells appending to a file apart from
+/// truncating it, such as when a log is rotated.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SizeChange {
+    /// The file grew
+    Append,
+    /// The file shrank
+    Truncate,
+    /// The file kept its size, so its contents were presumably overwritten
+    Rewrite,
+    /// The size before or after the modification isn't known
+    Unknown,
+}
+
+impl SizeChange {
+    pub fn between(before: Option<u64>, after: Option<u64>) -> Self {
+        match (before, after) {
+            (Some(before), Some(after)) if after > before => SizeChange::Append,
+            (Some(before), Some(after)) if after < before => SizeChange::Truncate,
+            (Some(_), Some(_)) => SizeChange::Rewrite,
+            _ => SizeChange::Unknown,
+        }
+    }
+}
+
+/// Remembers the last observed size of each file, to compare the size after a modification with.
+#[derive(Default)]
+pub struct SizeCache {
+    entries: HashMap<PathBuf, u64>,
+}
+
+impl SizeCache {
+    /// Record `size` as the current size of `path`, returning the size it had before if that's
+    /// known. A size of `None`, for a file that couldn't be read, forgets the path instead.
+    pub fn update(&mut self, path: &Path, size: Option<u64>) -> Option<u64> {
+        let size = match size {
+            Some(size) => size,
+            None => return self.entries.remove(path),
+        };
+        if self.entries.len() >= MAX_CACHED_PATHS && !self.entries.contains_key(path) {
+            self.entries.clear();
+        }
+        self.entries.insert(path.to_path_buf(), size)
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.entries.remove(path);
+    }
+
+    pub fn rename(&mut self, old_path: &Path, new_path: &Path) {
+        if let Some(size) = self.entries.remove(old_path) {
+            self.entries.insert(new_path.to_path_buf(), size);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::raw_paths::{self, RawPaths};
+use crate::Event;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::{DirEntry, WalkDir};
+
+/// An entry of the tree as it was when a snapshot was taken.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    #[serde(serialize_with = "raw_paths::serialize_lossy")]
+    pub path: PathBuf,
+    /// Milliseconds since the Unix epoch, where the file system records modification times
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime_ms: Option<u64>,
+    /// Only set for entries that aren't directories
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+/// Walk the tree under `root`, following symlinks as the backends do when watching recursively.
+/// walkdir recognizes a directory that's already being walked by its handle (the device and inode
+/// number on Unix), so a symlink back to one of its own ancestors isn't followed again. Such
+/// cycles are reported as error events, one for each symlink that closes one.
+pub fn walk(root: &Path) -> impl Iterator<Item = Result<DirEntry, walkdir::Error>> {
+    WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(true)
+        .into_iter()
+}
+
+fn cycle_error(error: &walkdir::Error) -> Option<Event> {
+    let ancestor = error.loop_ancestor()?;
+    let path = error.path()?;
+    Some(Event::Error {
+        path: path.to_path_buf(),
+        description: format!(
+            "Symlink cycle: {:?} leads back to {:?}, which isn't walked again",
+            path, ancestor
+        ),
+        code: None,
+        raw: RawPaths::default(),
+    })
+}
+
+/// Walk `root` and produce an event for every entry that changed after `since`, judged by its
+/// modification time. Entries whose creation time is also after `since` are reported as created,
+/// and everything else as modified. Directories are only reported when they were created, since
+/// their modification time changes whenever one of their children does.
+pub fn changed_since(root: &Path, since: SystemTime) -> Vec<Event> {
+    let mut events = Vec::new();
+
+    for entry in walk(root) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                events.extend(cycle_error(&error));
+                continue;
+            }
+        };
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if !matches!(metadata.modified(), Ok(modified) if modified > since) {
+            continue;
+        }
+
+        // Not every platform and file system records creation times, in which case the best we
+        // can say is that the entry was modified
+        if matches!(metadata.created(), Ok(created) if created > since) {
+            events.push(Event::created(entry.path()));
+        } else if !metadata.is_dir() {
+            events.push(Event::modified(entry.path()));
+        }
+    }
+
+    events
+}
+
+/// Find the directories under `root` whose contents can't be listed because of their
+/// permissions. Watching `root` recursively silently skips them. Dangling symlinks and entries
+/// that disappear during the walk aren't among them. A `root` that doesn't exist has none.
+pub fn inaccessible_dirs(root: &Path) -> Vec<PathBuf> {
+    walk(root)
+        .filter_map(|entry| entry.err())
+        .filter(|error| {
+            error.depth() > 0
+                && error.io_error().map(io::Error::kind) == Some(io::ErrorKind::PermissionDenied)
+        })
+        .filter_map(|error| error.path().map(Path::to_path_buf))
+        .filter(|path| fs::metadata(path).is_ok_and(|metadata| metadata.is_dir()))
+        .collect()
+}
+
+/// Walk `root` and list the paths of the entries that `keep` accepts, in order. As for
+/// `entries`, what can't be read is left out.
+pub fn paths(root: &Path, mut keep: impl FnMut(&Path) -> bool) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = walk(root)
+        .filter_map(Result::ok)
+        .filter(|entry| keep(entry.path()))
+        .map(DirEntry::into_path)
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Walk `root` and describe every entry that `keep` accepts, ordered by path. Entries that vanish
+/// or can't be read during the walk are left out, as are symlink cycles.
+pub fn entries(root: &Path, mut keep: impl FnMut(&Path) -> bool) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = walk(root)
+        .filter_map(Result::ok)
+        .filter(|entry| keep(entry.path()))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some(Entry {
+                path: entry.into_path(),
+                mtime_ms: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|since_epoch| since_epoch.as_millis() as u64),
+                size: if metadata.is_dir() {
+                    None
+                } else {
+                    Some(metadata.len())
+                },
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries
+}
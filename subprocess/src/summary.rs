@@ -0,0 +1,43 @@
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// How many changes of each kind a watch counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChangeCounts {
+    pub created: u64,
+    pub modified: u64,
+    pub deleted: u64,
+    pub renamed: u64,
+}
+
+/// The changes counted by a watch with `summary_interval_ms` instead of emitting them, over
+/// windows of at least the interval each. A window starts as soon as the previous one ended,
+/// whether or not anything was counted in it.
+pub struct Summary {
+    interval: Duration,
+    since: Instant,
+    pub counts: ChangeCounts,
+}
+
+impl Summary {
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            since: now,
+            counts: ChangeCounts::default(),
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now.duration_since(self.since) >= self.interval
+    }
+
+    /// Start a new window, returning the counts of the one that ended and how long it lasted,
+    /// unless nothing was counted in it.
+    pub fn take(&mut self, now: Instant) -> Option<(ChangeCounts, Duration)> {
+        let window = now.duration_since(self.since);
+        self.since = now;
+        let counts = mem::take(&mut self.counts);
+        (counts != ChangeCounts::default()).then_some((counts, window))
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The number of symlinks remembered per watch. Once this many are cached, the cache is cleared
+/// and starts over, so deletions of the symlinks it forgot aren't recognized as such.
+const MAX_CACHED_SYMLINKS: usize = 10_000;
+
+/// Which paths a watch emits events for by whether they're symlinks, as chosen with `symlinks`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymlinkFilter {
+    /// Symlinks and everything else alike
+    Include,
+    /// Everything but symlinks
+    Exclude,
+    /// Only symlinks
+    Only,
+}
+
+impl SymlinkFilter {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "include" => Ok(SymlinkFilter::Include),
+            "exclude" => Ok(SymlinkFilter::Exclude),
+            "only" => Ok(SymlinkFilter::Only),
+            _ => Err(format!(
+                "Unsupported symlinks {:?}, expected \"include\", \"exclude\" or \"only\"",
+                name
+            )),
+        }
+    }
+
+    fn allows(self, is_symlink: bool) -> bool {
+        match self {
+            SymlinkFilter::Include => true,
+            SymlinkFilter::Exclude => !is_symlink,
+            SymlinkFilter::Only => is_symlink,
+        }
+    }
+}
+
+/// Remembers the paths that were symlinks when they were last checked, since a path that was
+/// deleted can't be checked anymore.
+#[derive(Default)]
+pub struct SymlinkCache {
+    symlinks: HashSet<PathBuf>,
+}
+
+impl SymlinkCache {
+    /// Whether `filter` allows `path`, checked with a single `lstat` call. A path that no longer
+    /// exists counts as a symlink if it was one when it was last checked, and is forgotten.
+    pub fn allows(&mut self, path: &Path, filter: SymlinkFilter) -> bool {
+        let is_symlink = match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                if self.symlinks.len() >= MAX_CACHED_SYMLINKS && !self.symlinks.contains(path) {
+                    self.symlinks.clear();
+                }
+                self.symlinks.insert(path.to_path_buf());
+                true
+            }
+            Ok(_) => {
+                self.symlinks.remove(path);
+                false
+            }
+            Err(_) => self.symlinks.remove(path),
+        };
+        filter.allows(is_symlink)
+    }
+}
+
+/// Like `SymlinkCache::allows`, for a one-off check of the path as it is now.
+pub fn allows(path: &Path, filter: SymlinkFilter) -> bool {
+    let is_symlink =
+        fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink());
+    filter.allows(is_symlink)
+}
@@ -0,0 +1,63 @@
+#[cfg(target_os = "linux")]
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::path::Path;
+
+/// How much of the operating system's capacity for watches is in use, as far as it can be told.
+/// Every field is `None` where it isn't known, which is always the case off Linux.
+pub struct WatchCapacity {
+    pub max_user_watches: Option<u64>,
+    pub max_user_instances: Option<u64>,
+    pub current_instance_watches: Option<u64>,
+}
+
+impl WatchCapacity {
+    #[cfg(target_os = "linux")]
+    pub fn read() -> Self {
+        Self {
+            max_user_watches: read_number("/proc/sys/fs/inotify/max_user_watches"),
+            max_user_instances: read_number("/proc/sys/fs/inotify/max_user_instances"),
+            current_instance_watches: count_inotify_watches(),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn read() -> Self {
+        Self {
+            max_user_watches: None,
+            max_user_instances: None,
+            current_instance_watches: None,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_number(path: impl AsRef<Path>) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+// The number of watch descriptors held by this process's inotify instances, which the kernel
+// lists as one `inotify wd:` line each in the instances' fdinfo. Only this process is counted,
+// while `max_user_watches` applies to every process of the same user together.
+#[cfg(target_os = "linux")]
+fn count_inotify_watches() -> Option<u64> {
+    let mut count = 0;
+    for entry in fs::read_dir("/proc/self/fd").ok()? {
+        let fd = entry.ok()?.file_name();
+        let target = match fs::read_link(Path::new("/proc/self/fd").join(&fd)) {
+            Ok(target) => target,
+            // The descriptor was closed in the meantime, such as the one listing the directory
+            Err(_) => continue,
+        };
+        if target != Path::new("anon_inode:inotify") {
+            continue;
+        }
+        if let Ok(info) = fs::read_to_string(Path::new("/proc/self/fdinfo").join(&fd)) {
+            count += info
+                .lines()
+                .filter(|line| line.starts_with("inotify wd:"))
+                .count() as u64;
+        }
+    }
+    Some(count)
+}
@@ -0,0 +1,54 @@
+use std::path::Path;
+
+/// File name patterns of the transient files that common editors create while saving. `*`
+/// matches any run of characters, and patterns are matched against the last path component only.
+pub const TEMP_FILE_PATTERNS: &[&str] = &[
+    // Vim swap and backup files, and the file it creates to probe whether a directory is writable
+    "*.swp",
+    "*.swo",
+    "*.swx",
+    "*~",
+    "4913",
+    // Emacs lock and auto-save files
+    ".#*",
+    "#*#",
+    // JetBrains IDEs save through a temporary copy and keep the previous contents alongside it
+    "*___jb_tmp___",
+    "*___jb_old___",
+    // VS Code and other tools that write to a temporary file before renaming it into place
+    "*.tmp",
+];
+
+/// Whether `path` looks like one of the files matched by `TEMP_FILE_PATTERNS`.
+pub fn is_temp_file(path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => TEMP_FILE_PATTERNS
+            .iter()
+            .any(|pattern| matches(pattern, name)),
+        None => false,
+    }
+}
+
+/// Whether the file name `name` matches `pattern`, in which `*` matches any run of characters.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // There was no `*`, so the pattern must match the whole name
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
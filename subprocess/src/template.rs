@@ -0,0 +1,106 @@
+use serde_json::Value;
+use std::mem;
+use std::str::FromStr;
+
+/// What every event is written as with `--event-template`, instead of its JSON. Placeholders in
+/// braces are replaced by the event's fields, and `{{` and `}}` stand for literal braces.
+#[derive(Debug)]
+pub struct EventTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Placeholder {
+    Action,
+    WatchId,
+    Path,
+    OldPath,
+    Timestamp,
+}
+
+impl Placeholder {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "action" => Some(Placeholder::Action),
+            "watchId" => Some(Placeholder::WatchId),
+            "path" => Some(Placeholder::Path),
+            "oldPath" => Some(Placeholder::OldPath),
+            "timestamp" => Some(Placeholder::Timestamp),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for EventTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+            match c {
+                '{' | '}' if rest.starts_with(c) => {
+                    literal.push(c);
+                    rest = &rest[1..];
+                }
+                '{' => {
+                    let end = rest.find('}').ok_or_else(|| {
+                        "Unclosed placeholder, write a literal { as {{".to_string()
+                    })?;
+                    let name = &rest[..end];
+                    let placeholder = Placeholder::parse(name).ok_or_else(|| {
+                        format!(
+                            "Unknown placeholder {{{}}}, expected {{action}}, {{watchId}}, \
+                             {{path}}, {{oldPath}}, or {{timestamp}}",
+                            name
+                        )
+                    })?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(placeholder));
+                    rest = &rest[end + 1..];
+                }
+                '}' => return Err("Unmatched }, write a literal } as }}".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl EventTemplate {
+    /// Fill in the template for `event`, as serialized to JSON, of the watch `watch_id`. Each
+    /// placeholder is replaced by its value as JSON, so strings are quoted and escaped, and
+    /// fields the event doesn't have are `null`.
+    pub fn render(&self, event: &Value, watch_id: usize, timestamp_ms: u64) -> String {
+        let mut line = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => line.push_str(text),
+                Part::Placeholder(placeholder) => {
+                    let value = match placeholder {
+                        Placeholder::Action => event["action"].clone(),
+                        Placeholder::WatchId => Value::from(watch_id),
+                        Placeholder::Path => event["path"].clone(),
+                        Placeholder::OldPath => event["oldPath"].clone(),
+                        Placeholder::Timestamp => Value::from(timestamp_ms),
+                    };
+                    line.push_str(&value.to_string());
+                }
+            }
+        }
+        line
+    }
+}
@@ -0,0 +1,107 @@
+use crate::snapshot;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// A hash of the paths in a tree along with the sizes and modification times of its files, kept
+/// up to date as events arrive. Each entry is hashed on its own and the tree's hash is their
+/// wrapping sum, so changing one entry only requires subtracting its old hash and adding the new
+/// one.
+pub struct TreeHash {
+    root: PathBuf,
+    entries: HashMap<PathBuf, u64>,
+    combined: u64,
+}
+
+impl TreeHash {
+    /// Walk the tree under `root` to hash every path for which `covers` is true.
+    pub fn build(root: &Path, covers: &impl Fn(&Path) -> bool) -> Self {
+        let mut tree = Self {
+            root: root.to_path_buf(),
+            entries: HashMap::new(),
+            combined: 0,
+        };
+        tree.refresh(root, covers);
+        tree
+    }
+
+    /// The current hash as a lowercase hex string.
+    pub fn hash(&self) -> String {
+        format!("{:016x}", self.combined)
+    }
+
+    /// Rehash `path` after an event for it. A file only needs to be read again itself, while
+    /// everything under a directory or a path that no longer exists has to be hashed again.
+    pub fn update(&mut self, path: &Path, covers: &impl Fn(&Path) -> bool) {
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                if covers(path) {
+                    self.insert(path, &metadata);
+                }
+            }
+            _ => self.refresh(path, covers),
+        }
+    }
+
+    /// Hash everything under `path` again, including `path` itself.
+    pub fn refresh(&mut self, path: &Path, covers: &impl Fn(&Path) -> bool) {
+        let stale: Vec<PathBuf> = self
+            .entries
+            .keys()
+            .filter(|entry| entry.starts_with(path))
+            .cloned()
+            .collect();
+        for entry in stale {
+            self.remove(&entry);
+        }
+
+        if let Ok(metadata) = fs::metadata(path) {
+            if covers(path) {
+                self.insert(path, &metadata);
+            }
+            for entry in snapshot::walk(path).filter_map(Result::ok) {
+                if let Ok(metadata) = entry.metadata() {
+                    if covers(entry.path()) {
+                        self.insert(entry.path(), &metadata);
+                    }
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, path: &Path, metadata: &Metadata) {
+        self.remove(path);
+        let hash = self.entry_hash(path, metadata);
+        self.combined = self.combined.wrapping_add(hash);
+        self.entries.insert(path.to_path_buf(), hash);
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(hash) = self.entries.remove(path) {
+            self.combined = self.combined.wrapping_sub(hash);
+        }
+    }
+
+    // Paths are hashed relative to the root, so that identical trees have the same hash. A
+    // directory's size and modification time change along with its children, which are hashed
+    // themselves, so only its path counts.
+    fn entry_hash(&self, path: &Path, metadata: &Metadata) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .hash(&mut hasher);
+        metadata.is_dir().hash(&mut hasher);
+        if !metadata.is_dir() {
+            metadata.len().hash(&mut hasher);
+            metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+}
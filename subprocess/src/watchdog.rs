@@ -0,0 +1,64 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Notices a native backend that has gone silent, as they're known to do on some network mounts,
+/// where registering a watch succeeds but no events ever arrive. Once per interval, the
+/// modification time of the root is compared with the one at the previous check. If it changed,
+/// something was added to, removed from, or renamed in the root, so the backend should have
+/// reported at least one event in between.
+pub struct Watchdog {
+    interval: Duration,
+    checked_at: Instant,
+    root_mtime: Option<SystemTime>,
+    received_events: bool,
+}
+
+impl Watchdog {
+    pub fn new(interval: Duration, root: &Path, now: Instant) -> Self {
+        Self {
+            interval,
+            checked_at: now,
+            root_mtime: mtime(root),
+            received_events: false,
+        }
+    }
+
+    /// Start a new interval, forgetting what happened during the current one.
+    pub fn restart(&mut self, root: &Path, now: Instant) {
+        *self = Self::new(self.interval, root, now);
+    }
+
+    pub fn received_events(&mut self) {
+        self.received_events = true;
+    }
+
+    /// Whether the backend stayed silent for a whole interval although the root changed. Events
+    /// for a change can take up to `grace` to arrive, so a change more recent than that is left
+    /// to the next check.
+    pub fn is_silent(&mut self, root: &Path, now: Instant, grace: Duration) -> bool {
+        if now.duration_since(self.checked_at) < self.interval {
+            return false;
+        }
+        let root_mtime = mtime(root);
+        let changed = root_mtime != self.root_mtime;
+        let settled = root_mtime
+            .and_then(|mtime| mtime.elapsed().ok())
+            .is_none_or(|elapsed| elapsed >= grace);
+        if changed && !settled {
+            return false;
+        }
+
+        let silent = changed && !self.received_events;
+        self.checked_at = now;
+        self.root_mtime = root_mtime;
+        self.received_events = false;
+        silent
+    }
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
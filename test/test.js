@@ -1,6 +1,7 @@
 const assert = require("assert");
 const path = require("path");
 const fs = require("fs");
+const { execFileSync, spawn } = require("child_process");
 const temp = require("temp");
 const Watcher = require("..");
 
@@ -92,12 +93,2619 @@ for (const pollInterval of [0, 1]) {
       await sub2.dispose();
     });
 
-    it("rejects when watching a path that does not exist", async () => {
+    it("emits paths with forward slashes when forwardSlashes is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, { forwardSlashes: true }, e =>
+        events.push(...e)
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+
+      await condition(() => events.length === 1);
+
+      // Separators are only rewritten on Windows, e.g. `C:/Users/me/a`
+      const expectedPath =
+        process.platform === "win32"
+          ? `${tempDirPath.replace(/\\/g, "/")}/a`
+          : path.join(tempDirPath, "a");
+      assert.deepStrictEqual(events, [
+        { action: "created", path: expectedPath }
+      ]);
+      if (process.platform === "win32") {
+        assert(/^[A-Za-z]:\//.test(events[0].path));
+      }
+    });
+
+    it("emits events under overlapping roots in ascending watch id order", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const subdirPath = path.join(tempDirPath, "subdir");
+      fs.mkdirSync(subdirPath);
+
+      const order = [];
+      await watcher.watchPath(tempDirPath, () => order.push("outer"));
+      await watcher.watchPath(subdirPath, () => order.push("inner"));
+
+      fs.writeFileSync(path.join(subdirPath, "a"), "");
+
+      await condition(() => order.length === 2);
+      assert.deepStrictEqual(order, ["outer", "inner"]);
+    });
+
+    it("delivers pending events before flush resolves", async () => {
+      const events = [];
+      await watcher.watchPath(tempDirPath, e => events.push(...e));
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      if (pollInterval) await new Promise(resolve => setTimeout(resolve, 50));
+      await watcher.flush();
+
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(tempDirPath, "a") }
+      ]);
+    });
+
+    it("reports the notify thread as healthy", async () => {
+      const health = await watcher.health();
+      assert.strictEqual(health.notifyThreadAlive, true);
+      assert.strictEqual(health.restarted, false);
+      assert(health.lastTickMsAgo < 1000);
+    });
+
+    it("watches NOTIFY_WATCH_ROOT on startup without any requests", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const args = pollInterval ? ["--poll-interval", pollInterval] : [];
+      const child = spawn(require("../lib/bin-path"), args, {
+        env: Object.assign({}, process.env, {
+          NOTIFY_WATCH_ROOT: tempDirPath,
+          NOTIFY_WATCH_IGNORE: "*.log, *.tmp"
+        })
+      });
+      const messages = [];
+      let buffer = "";
+      child.stdout.on("data", chunk => {
+        const lines = (buffer + chunk).split("\n");
+        buffer = lines.pop();
+        messages.push(...lines.map(line => JSON.parse(line)));
+      });
+
+      try {
+        await condition(() => messages.length === 1);
+        assert.deepStrictEqual(messages[0], { type: "okResponse", requestId: 0 });
+
+        fs.writeFileSync(path.join(tempDirPath, "a.log"), "");
+        fs.writeFileSync(path.join(tempDirPath, "b"), "");
+        await condition(() => messages.length === 2);
+        assert.deepStrictEqual(messages[1], {
+          type: "watchEvents",
+          watchId: 0,
+          events: [{ action: "created", path: path.join(tempDirPath, "b") }]
+        });
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("writes events as rows of CSV with --format csv", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const args = ["--format", "csv"];
+      if (pollInterval) args.push("--poll-interval", pollInterval);
+      const child = spawn(require("../lib/bin-path"), args, {
+        env: Object.assign({}, process.env, { NOTIFY_WATCH_ROOT: tempDirPath })
+      });
+      let output = "";
+      child.stdout.on("data", chunk => (output += chunk));
+
+      try {
+        await condition(() => output.length > 0);
+        assert.strictEqual(output, "action,watch_id,path,old_path\n");
+
+        fs.writeFileSync(path.join(tempDirPath, 'a,"b"'), "");
+        await condition(() => output.split("\n").length === 3);
+        assert.strictEqual(
+          output.split("\n")[1],
+          `created,0,"${path.join(tempDirPath, 'a,""b""')}",`
+        );
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("writes events filled into the template given with --event-template", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const template =
+        '{{"kind": {action}, "file": {path}, "from": {oldPath}, "watch": {watchId}}}';
+      const args = ["--event-template", template];
+      if (pollInterval) args.push("--poll-interval", pollInterval);
+      const child = spawn(require("../lib/bin-path"), args, {
+        env: Object.assign({}, process.env, { NOTIFY_WATCH_ROOT: tempDirPath })
+      });
+      let output = "";
+      child.stdout.on("data", chunk => (output += chunk));
+
+      try {
+        // Make sure the watch is in place first
+        child.stdin.write(JSON.stringify({ type: "flush", requestId: 0 }) + "\n");
+        await condition(() => output.includes('"requestId":0'));
+        const filePath = path.join(tempDirPath, 'a "b"');
+        fs.writeFileSync(filePath, "");
+        await condition(() => output.split("\n").length === 3);
+        assert.deepStrictEqual(JSON.parse(output.split("\n")[1]), {
+          kind: "created",
+          file: filePath,
+          from: null,
+          watch: 0
+        });
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("rejects event templates with unknown placeholders at startup", async function() {
+      const child = spawn(require("../lib/bin-path"), [
+        "--event-template",
+        "{action} {size}"
+      ]);
+      let stderr = "";
+      child.stderr.on("data", chunk => (stderr += chunk));
+      const code = await new Promise(resolve => child.on("close", resolve));
+      assert.notStrictEqual(code, 0);
+      assert(stderr.includes("Unknown placeholder {size}"), stderr);
+    });
+
+    it("also writes every message to the file given with --tee", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      // Outside the watched directory, so that writing it doesn't produce events
+      const teePath = path.join(fs.realpathSync(temp.mkdirSync()), "tee.log");
+      const args = ["--tee", teePath];
+      if (pollInterval) args.push("--poll-interval", pollInterval);
+      const child = spawn(require("../lib/bin-path"), args);
+      let output = "";
+      child.stdout.on("data", chunk => (output += chunk));
+      const send = request => child.stdin.write(JSON.stringify(request) + "\n");
+
+      try {
+        send({ type: "watch", requestId: 0, watchId: 0, root: tempDirPath });
+        await condition(() => output.includes('"requestId":0'));
+        fs.writeFileSync(path.join(tempDirPath, "a"), "");
+        await condition(() => output.includes("watchEvents"));
+        send({ type: "flush", requestId: 1 });
+        await condition(() => output.includes('"requestId":1'));
+
+        assert.strictEqual(fs.readFileSync(teePath, "utf8"), output);
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("emits no events for a watch after the response to unwatching it", async function() {
+      // Unwatching while the poll backend is reporting a burst can hang inside notify
+      if (pollInterval) this.skip();
+
+      const child = spawn(require("../lib/bin-path"));
+      const messages = [];
+      let buffer = "";
+      child.stdout.on("data", chunk => {
+        const lines = (buffer + chunk).split("\n");
+        buffer = lines.pop();
+        messages.push(...lines.map(line => JSON.parse(line)));
+      });
+      const send = request => child.stdin.write(JSON.stringify(request) + "\n");
+
+      try {
+        send({ type: "watch", requestId: 0, watchId: 0, root: tempDirPath });
+        await condition(() => messages.length === 1);
+
+        // Unwatch as soon as the first of the burst's events arrive, while the rest are still
+        // being emitted
+        for (let i = 0; i < 100; i++) {
+          fs.writeFileSync(path.join(tempDirPath, `${i}`), "");
+        }
+        await condition(() => messages.some(m => m.type === "watchEvents"));
+        send({ type: "unwatch", requestId: 1, watchId: 0 });
+        send({ type: "flush", requestId: 2 });
+        await condition(() => messages.some(m => m.requestId === 2));
+
+        const unwatched = messages.findIndex(m => m.requestId === 1);
+        assert.strictEqual(messages[unwatched].type, "okResponse");
+        assert(
+          !messages.slice(unwatched).some(m => m.type === "watchEvents"),
+          "Events were emitted after the watch was unwatched"
+        );
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("rejects reusing a watch id with --single-use-ids", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const args = ["--single-use-ids"];
+      if (pollInterval) args.push("--poll-interval", pollInterval);
+      const child = spawn(require("../lib/bin-path"), args);
+      const messages = [];
+      let buffer = "";
+      child.stdout.on("data", chunk => {
+        const lines = (buffer + chunk).split("\n");
+        buffer = lines.pop();
+        messages.push(...lines.map(line => JSON.parse(line)));
+      });
+      // Wait for each response in turn, since those that go through the output
+      // thread can be overtaken by others
+      const send = async request => {
+        child.stdin.write(JSON.stringify(request) + "\n");
+        await condition(() =>
+          messages.some(m => m.requestId === request.requestId)
+        );
+      };
+      const root = tempDirPath;
+
+      try {
+        await send({ type: "watch", requestId: 0, watchId: 0, root });
+        await send({ type: "unwatch", requestId: 1, watchId: 0 });
+        await send({ type: "watch", requestId: 2, watchId: 0, root });
+        await send({ type: "watch", requestId: 3, watchId: 1, root });
+
+        assert.deepStrictEqual(messages, [
+          { type: "okResponse", requestId: 0 },
+          { type: "okResponse", requestId: 1 },
+          {
+            type: "errorResponse",
+            requestId: 2,
+            code: "idRetired",
+            description: "The watch id 0 has already been used"
+          },
+          { type: "okResponse", requestId: 3 }
+        ]);
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("replaces watches whose options changed with setWatches", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const args = pollInterval ? ["--poll-interval", pollInterval] : [];
+      const child = spawn(require("../lib/bin-path"), args);
+      const messages = [];
+      let buffer = "";
+      child.stdout.on("data", chunk => {
+        const lines = (buffer + chunk).split("\n");
+        buffer = lines.pop();
+        messages.push(...lines.map(line => JSON.parse(line)));
+      });
+      const send = async request => {
+        child.stdin.write(JSON.stringify(request) + "\n");
+        await condition(() =>
+          messages.some(m => m.requestId === request.requestId)
+        );
+        return messages.find(m => m.requestId === request.requestId);
+      };
+      const root = tempDirPath;
+
+      try {
+        await send({
+          type: "setWatches",
+          requestId: 0,
+          watches: [{ watchId: 0, root }, { watchId: 1, root }]
+        });
+        assert.deepStrictEqual(
+          await send({
+            type: "setWatches",
+            requestId: 1,
+            watches: [
+              { watchId: 0, root },
+              { watchId: 1, root, actions: ["deleted"] }
+            ]
+          }),
+          {
+            type: "diffResponse",
+            requestId: 1,
+            added: [1],
+            removed: [1],
+            kept: [0]
+          }
+        );
+        assert.deepStrictEqual(
+          await send({
+            type: "setWatches",
+            requestId: 2,
+            watches: [{ watchId: 2, root }, { watchId: 2, root }]
+          }),
+          {
+            type: "errorResponse",
+            requestId: 2,
+            description: "Duplicate watch id: 2"
+          }
+        );
+
+        // Only the watch without the new actions filter reports the creation
+        fs.writeFileSync(path.join(root, "a"), "");
+        await condition(() => messages.some(m => m.type === "watchEvents"));
+        await send({ type: "flush", requestId: 3 });
+        assert.deepStrictEqual(
+          messages.filter(m => m.type === "watchEvents").map(m => m.watchId),
+          [0]
+        );
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("moves a watch to a new id with renameWatch", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const args = pollInterval ? ["--poll-interval", pollInterval] : [];
+      const child = spawn(require("../lib/bin-path"), args);
+      const messages = [];
+      let buffer = "";
+      child.stdout.on("data", chunk => {
+        const lines = (buffer + chunk).split("\n");
+        buffer = lines.pop();
+        messages.push(...lines.map(line => JSON.parse(line)));
+      });
+      const send = request => child.stdin.write(JSON.stringify(request) + "\n");
+      const response = requestId =>
+        messages.find(message => message.requestId === requestId);
+
+      try {
+        send({ type: "watch", requestId: 0, watchId: 0, root: tempDirPath });
+        send({ type: "watch", requestId: 1, watchId: 1, root: tempDirPath });
+        send({ type: "renameWatch", requestId: 2, watchId: 0, newWatchId: 5 });
+        send({ type: "renameWatch", requestId: 3, watchId: 5, newWatchId: 1 });
+        send({ type: "renameWatch", requestId: 4, watchId: 0, newWatchId: 6 });
+        await condition(() => response(4));
+        assert.strictEqual(response(2).type, "okResponse");
+        assert.deepStrictEqual(response(3), {
+          type: "errorResponse",
+          requestId: 3,
+          description: "A watch with id 1 already exists"
+        });
+        assert.deepStrictEqual(response(4), {
+          type: "errorResponse",
+          requestId: 4,
+          description: "No watch found for id: 0"
+        });
+
+        fs.writeFileSync(path.join(tempDirPath, "a"), "");
+        await condition(() =>
+          messages.some(m => m.type === "watchEvents" && m.watchId === 5)
+        );
+        assert(!messages.some(m => m.type === "watchEvents" && m.watchId === 0));
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("skips request lines that aren't valid UTF-8", async function() {
+      const args = pollInterval ? ["--poll-interval", pollInterval] : [];
+      const child = spawn(require("../lib/bin-path"), args);
+      const messages = [];
+      let buffer = "";
+      child.stdout.on("data", chunk => {
+        const lines = (buffer + chunk).split("\n");
+        buffer = lines.pop();
+        messages.push(...lines.map(line => JSON.parse(line)));
+      });
+
+      try {
+        child.stdin.write(
+          Buffer.concat([
+            Buffer.from('{"type":"watch","requestId":0,"watchId":0,"root":"'),
+            Buffer.from([0xff, 0xfe]),
+            Buffer.from('"}\n')
+          ])
+        );
+        child.stdin.write(Buffer.from([0xc3, 0x28, 0x0a]));
+        child.stdin.write(JSON.stringify({ type: "health", requestId: 1 }) + "\n");
+        await condition(() => messages.length === 3);
+
+        assert.deepStrictEqual(messages[0], {
+          type: "errorResponse",
+          requestId: 0,
+          description: "Request isn't valid UTF-8: invalid byte at offset 50",
+          code: "invalidEncoding"
+        });
+        assert.deepStrictEqual(messages[1], {
+          type: "watcherError",
+          description: "Request isn't valid UTF-8: invalid byte at offset 0"
+        });
+        assert.strictEqual(messages[2].type, "healthResponse");
+        assert.strictEqual(messages[2].requestId, 1);
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("skips request lines that aren't valid requests", async function() {
+      const args = pollInterval ? ["--poll-interval", pollInterval] : [];
+      const child = spawn(require("../lib/bin-path"), args);
+      const messages = [];
+      let buffer = "";
+      child.stdout.on("data", chunk => {
+        const lines = (buffer + chunk).split("\n");
+        buffer = lines.pop();
+        messages.push(...lines.map(line => JSON.parse(line)));
+      });
+
+      try {
+        child.stdin.write('{"type":"nonsense","requestId":0}\n');
+        child.stdin.write('{"type":"health",\n');
+        child.stdin.write(JSON.stringify({ type: "health", requestId: 1 }) + "\n");
+        await condition(() => messages.length === 3);
+
+        assert.strictEqual(messages[0].type, "errorResponse");
+        assert.strictEqual(messages[0].requestId, 0);
+        assert.strictEqual(messages[0].code, "invalidRequest");
+        assert.strictEqual(messages[1].type, "watcherError");
+        assert(messages[1].description.startsWith("Invalid request: "));
+        assert.strictEqual(messages[2].type, "healthResponse");
+        assert.strictEqual(messages[2].requestId, 1);
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("round-trips every opcode with --protocol binary", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const args = ["--protocol", "binary"];
+      if (pollInterval) args.push("--poll-interval", pollInterval);
+      const child = spawn(require("../lib/bin-path"), args);
+      const frames = [];
+      let buffer = Buffer.alloc(0);
+      child.stdout.on("data", chunk => {
+        buffer = Buffer.concat([buffer, chunk]);
+        while (buffer.length >= 5 && buffer.length >= 5 + buffer.readUInt32LE(1)) {
+          const end = 5 + buffer.readUInt32LE(1);
+          frames.push({ opcode: buffer[0], payload: buffer.subarray(5, end) });
+          buffer = buffer.subarray(end);
+        }
+      });
+      const u64 = value => {
+        const field = Buffer.alloc(8);
+        field.writeBigUInt64LE(BigInt(value));
+        return field;
+      };
+      const send = (opcode, ...fields) => {
+        const payload = Buffer.concat(fields);
+        const header = Buffer.alloc(5);
+        header[0] = opcode;
+        header.writeUInt32LE(payload.length, 1);
+        child.stdin.write(Buffer.concat([header, payload]));
+      };
+      // Polling can report the root as modified later on, which is skipped
+      // once the event frame has been checked
+      let skipEvents = false;
+      const next = async () => {
+        await condition(() => {
+          while (skipEvents && frames.length && frames[0].opcode === 3) {
+            frames.shift();
+          }
+          return frames.length > 0;
+        });
+        return frames.shift();
+      };
+      const ok = requestId => ({ opcode: 1, payload: u64(requestId) });
+
+      try {
+        send(1, u64(1), u64(7), Buffer.from(tempDirPath));
+        assert.deepStrictEqual(await next(), ok(1));
+
+        const aPath = path.join(tempDirPath, "a");
+        fs.writeFileSync(aPath, "");
+        const { opcode, payload } = await next();
+        assert.strictEqual(opcode, 3);
+        assert.strictEqual(payload.readBigUInt64LE(0), 7n);
+        assert.strictEqual(payload.readUInt32LE(8), 1);
+        assert.strictEqual(payload[12], 2);
+        const pathLength = payload.readUInt32LE(13);
+        assert.strictEqual(payload.toString("utf8", 17, 17 + pathLength), aPath);
+        skipEvents = true;
+
+        send(4, u64(2));
+        assert.deepStrictEqual(await next(), ok(2));
+        send(2, u64(3), u64(7));
+        assert.deepStrictEqual(await next(), ok(3));
+        send(2, u64(4), u64(7));
+        const error = await next();
+        assert.strictEqual(error.opcode, 2);
+        assert.strictEqual(error.payload.readBigUInt64LE(0), 4n);
+        assert.match(error.payload.toString("utf8", 8), /No watch found/);
+        send(3, u64(5));
+        assert.deepStrictEqual(await next(), ok(5));
+
+        // Messages without a layout of their own are framed as JSON
+        send(5, u64(6));
+        const paused = await next();
+        assert.strictEqual(paused.opcode, 0);
+        assert.deepStrictEqual(JSON.parse(paused.payload), {
+          type: "okResponse",
+          requestId: 6,
+          affectedWatches: 0
+        });
+        send(6, u64(7));
+        assert.strictEqual((await next()).opcode, 0);
+        send(0, Buffer.from(JSON.stringify({ type: "version", requestId: 8 })));
+        const version = await next();
+        assert.strictEqual(version.opcode, 0);
+        assert.strictEqual(JSON.parse(version.payload).type, "versionResponse");
+      } finally {
+        child.kill();
+      }
+    });
+
+    it("reports the versions of the subprocess", async () => {
+      const version = await watcher.version();
+      assert.strictEqual(version.protocolVersion, 1);
+      assert(/^\d+\.\d+\.\d+/.test(version.crateVersion));
+      assert(/^4\.\d+\.\d+/.test(version.notifyVersion));
+    });
+
+    it("resolves symlinks in isWatched on a helper thread when canonicalizeTimeoutMs is set", async function() {
+      if (process.platform === "win32") this.skip();
+
+      await watcher.kill();
+      watcher = new Watcher({ pollInterval, canonicalizeTimeoutMs: 5000 });
+      const linkPath = path.join(tempDirPath, "link");
+      fs.mkdirSync(path.join(tempDirPath, "target"));
+      fs.symlinkSync(path.join(tempDirPath, "target"), linkPath);
+      await watcher.watchPath(path.join(tempDirPath, "target"), () => {});
+
+      assert.deepStrictEqual(
+        await watcher.isWatched(path.join(linkPath, "a")),
+        [0]
+      );
+    });
+
+    it("marks files with several hard links when detectHardlinks is set", async function() {
+      if (process.platform === "win32") this.skip();
+
+      const aPath = path.join(tempDirPath, "a");
+      const bPath = path.join(tempDirPath, "b");
+      const cPath = path.join(tempDirPath, "c");
+      fs.writeFileSync(aPath, "a");
+      const events = [];
+      await watcher.watchPath(tempDirPath, { detectHardlinks: true }, e =>
+        events.push(...e)
+      );
+
+      fs.linkSync(aPath, bPath);
+      fs.writeFileSync(cPath, "c");
+      await condition(
+        () =>
+          events.some(event => event.path === bPath) &&
+          events.some(event => event.path === cPath)
+      );
+      assert.deepStrictEqual(events.find(event => event.path === bPath), {
+        action: "created",
+        path: bPath,
+        hardlink: true,
+        nlink: 2
+      });
+      assert.deepStrictEqual(events.find(event => event.path === cPath), {
+        action: "created",
+        path: cPath
+      });
+    });
+
+    it("resolves symlinks in a path with canonicalize", async function() {
+      // Creating symlinks requires extra privileges on Windows
+      if (process.platform === "win32") this.skip();
+
+      const targetPath = path.join(tempDirPath, "target");
+      const linkPath = path.join(tempDirPath, "link");
+      fs.mkdirSync(targetPath);
+      fs.symlinkSync(targetPath, linkPath);
+
+      assert.deepStrictEqual(await watcher.canonicalize(linkPath), {
+        input: linkPath,
+        canonical: targetPath,
+        error: null
+      });
+      const missing = await watcher.canonicalize(path.join(linkPath, "missing"));
+      assert.strictEqual(missing.canonical, null);
+      assert(/No such file/.test(missing.error));
+    });
+
+    it("reports which watches cover a path, respecting their options", async () => {
+      fs.mkdirSync(path.join(tempDirPath, "subdir"));
+      await watcher.watchPath(tempDirPath, { ignoreTempFiles: true }, () => {});
+      await watcher.watchPath(path.join(tempDirPath, "subdir"), () => {});
+      await watcher.watchFiles([path.join(tempDirPath, "a")], () => {});
+
+      assert.deepStrictEqual(
+        await watcher.isWatched(path.join(tempDirPath, "a")),
+        [0, 2]
+      );
+      assert.deepStrictEqual(
+        await watcher.isWatched(path.join(tempDirPath, "subdir", "b")),
+        [0, 1]
+      );
+      assert.deepStrictEqual(
+        await watcher.isWatched(path.join(tempDirPath, "subdir", "b.swp")),
+        [1]
+      );
+      assert.deepStrictEqual(
+        await watcher.isWatched(path.join(tempDirPath, "..", "c")),
+        []
+      );
+    });
+
+    it("lists a directory through a filter without watching it", async () => {
+      fs.mkdirSync(path.join(tempDirPath, "subdir"));
+      fs.writeFileSync(path.join(tempDirPath, "a.js"), "");
+      fs.writeFileSync(path.join(tempDirPath, "b.txt"), "");
+      fs.writeFileSync(path.join(tempDirPath, "subdir", "c.js"), "");
+      fs.writeFileSync(path.join(tempDirPath, "subdir", "d.min.js"), "");
+
+      assert.deepStrictEqual(await watcher.ls(tempDirPath), [
+        path.join(tempDirPath, "a.js"),
+        path.join(tempDirPath, "b.txt"),
+        path.join(tempDirPath, "subdir"),
+        path.join(tempDirPath, "subdir", "c.js"),
+        path.join(tempDirPath, "subdir", "d.min.js")
+      ]);
+      assert.deepStrictEqual(
+        await watcher.ls(tempDirPath, {
+          include: ["*.js", "subdir"],
+          ignore: ["*.min.js"]
+        }),
+        [
+          path.join(tempDirPath, "a.js"),
+          path.join(tempDirPath, "subdir"),
+          path.join(tempDirPath, "subdir", "c.js")
+        ]
+      );
+      assert.deepStrictEqual(await watcher.isWatched(tempDirPath), []);
+    });
+
+    it("reports which watches contain one another with overlaps", async () => {
+      const subPath = path.join(tempDirPath, "sub");
+      fs.mkdirSync(subPath);
+      const outer = await watcher.watchPath(tempDirPath, () => {});
+      const inner = await watcher.watchPath(subPath, () => {});
+      const files = await watcher.watchFiles([path.join(subPath, "a")], () => {});
+      const shallow = await watcher.watchPath(
+        tempDirPath,
+        { recursive: false },
+        () => {}
+      );
+
+      assert.deepStrictEqual(await outer.overlaps(), {
+        contains: [1, 2, 3],
+        containedBy: []
+      });
+      assert.deepStrictEqual(await inner.overlaps(), {
+        contains: [2],
+        containedBy: [0]
+      });
+      assert.deepStrictEqual(await files.overlaps(), {
+        contains: [],
+        containedBy: [0, 1]
+      });
+      assert.deepStrictEqual(await shallow.overlaps(), {
+        contains: [],
+        containedBy: [0]
+      });
+    });
+
+    it("exports the current watches in the format of a config file", async function() {
+      fs.mkdirSync(path.join(tempDirPath, "sub"));
+      await watcher.watchPath(tempDirPath, { ignoreTempFiles: true }, () => {});
+      const sub = await watcher.watchPath(
+        path.join(tempDirPath, "sub"),
+        { profiles: [{ prefix: "src", extensions: ["js"] }], limit: 10 },
+        () => {}
+      );
+      await sub.removeActions(["error"]);
+      await watcher.watchFiles([path.join(tempDirPath, "a")], () => {});
+
+      assert.deepStrictEqual(await watcher.exportConfig(), {
+        watches: [
+          { watchId: 0, root: tempDirPath, ignoreTempFiles: true },
+          {
+            watchId: 1,
+            root: path.join(tempDirPath, "sub"),
+            actions: ["modified", "created", "deleted", "renamed"],
+            profiles: [{ prefix: "src", extensions: ["js"] }],
+            limit: 10
+          }
+        ]
+      });
+    });
+
+    it("inherits the filters of the watch given as inheritFrom", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const srcPath = path.join(tempDirPath, "src");
+      fs.mkdirSync(srcPath);
+      const parent = await watcher.watchPath(
+        tempDirPath,
+        {
+          ignoreTempFiles: true,
+          actions: ["created"],
+          profiles: [{ prefix: "src", extensions: ["js"] }]
+        },
+        () => {}
+      );
+      const events = [];
+      await watcher.watchPath(
+        srcPath,
+        { inheritFrom: parent, actions: ["created", "deleted"] },
+        e => events.push(...e)
+      );
+      // Changes to the parent afterwards don't carry over
+      await parent.addActions(["modified"]);
+
+      const { watches } = await watcher.exportConfig();
+      assert.deepStrictEqual(watches[1], {
+        watchId: 1,
+        root: srcPath,
+        ignoreTempFiles: true,
+        actions: ["created", "deleted"],
+        profiles: [{ prefix: srcPath, extensions: ["js"] }]
+      });
+
+      fs.writeFileSync(path.join(srcPath, "a.txt"), "");
+      fs.writeFileSync(path.join(srcPath, "b.js"), "");
+      await condition(() => events.length === 1);
+      await watcher.flush();
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(srcPath, "b.js") }
+      ]);
+
+      await assert.rejects(
+        watcher.watchPath(srcPath, { inheritFrom: { watchId: 99 } }, () => {}),
+        /No watch found for id: 99/
+      );
+    });
+
+    it("reports moving a file out of the root and back in as a rename when smartRenames is set", async function() {
+      const rootPath = path.join(tempDirPath, "root");
+      const outsidePath = path.join(tempDirPath, "outside", "a");
+      const aPath = path.join(rootPath, "a");
+      fs.mkdirSync(rootPath);
+      fs.mkdirSync(path.dirname(outsidePath));
+      fs.writeFileSync(aPath, "a");
+
+      const events = [];
+      await watcher.watchPath(rootPath, { smartRenames: true }, e =>
+        events.push(...e)
+      );
+      fs.renameSync(aPath, outsidePath);
+      // Give the backend time to report the deletion, rather than folding the
+      // two moves into a modification
+      await new Promise(resolve => setTimeout(resolve, 250));
+      fs.renameSync(outsidePath, aPath);
+
+      // Polling also reports the root as modified
+      await condition(() => events.some(event => event.path === aPath));
+      await watcher.flush();
+      assert.deepStrictEqual(
+        events.filter(event => event.path !== rootPath),
+        [{ action: "renamed", oldPath: aPath, path: aPath }]
+      );
+    });
+
+    for (const emitRenameAs of ["paired", "split", "both"]) {
+      it(`reports a move within the root according to emitRenameAs: "${emitRenameAs}"`, async function() {
+        // The poll watcher reports moves as a deletion and a creation already
+        if (pollInterval) this.skip();
+
+        const aPath = path.join(tempDirPath, "a");
+        const bPath = path.join(tempDirPath, "b");
+        fs.writeFileSync(aPath, "a");
+
+        const events = [];
+        await watcher.watchPath(tempDirPath, { emitRenameAs }, e =>
+          events.push(...e.filter(event => event.path !== tempDirPath))
+        );
+        fs.renameSync(aPath, bPath);
+        await condition(() => events.some(event => event.path === bPath));
+        await watcher.flush();
+
+        const renamed = { action: "renamed", oldPath: aPath, path: bPath };
+        const split = [
+          { action: "deleted", path: aPath },
+          { action: "created", path: bPath }
+        ];
+        if (emitRenameAs === "paired") {
+          assert.deepStrictEqual(events, [renamed]);
+        } else if (emitRenameAs === "split") {
+          assert.deepStrictEqual(events, split);
+        } else {
+          assert.deepStrictEqual(
+            events,
+            [renamed, ...split].map(event => ({ ...event, renameId: 0 }))
+          );
+        }
+      });
+    }
+
+    it("rejects an unknown emitRenameAs", async () => {
+      await assert.rejects(
+        watcher.watchPath(tempDirPath, { emitRenameAs: "both ways" }, () => {}),
+        /Unsupported emitRenameAs "both ways"/
+      );
+    });
+
+    for (const symlinks of ["exclude", "only"]) {
+      it(`filters events by whether the path is a symlink with symlinks: "${symlinks}"`, async () => {
+        const filePath = path.join(tempDirPath, "file");
+        const linkPath = path.join(tempDirPath, "link");
+        const events = [];
+        await watcher.watchPath(
+          tempDirPath,
+          { symlinks, includeRootEvents: false },
+          e => events.push(...e)
+        );
+
+        fs.writeFileSync(filePath, "");
+        fs.symlinkSync(filePath, linkPath);
+        await condition(() => events.length === 1);
+        // The link's creation has to be seen while it exists for its deletion to be recognized
+        await watcher.flush();
+        fs.unlinkSync(linkPath);
+        if (symlinks === "only") {
+          await condition(() => events.length === 2);
+        } else {
+          await new Promise(resolve => setTimeout(resolve, 300));
+        }
+        await watcher.flush();
+
+        if (symlinks === "exclude") {
+          assert.deepStrictEqual(events, [
+            { action: "created", path: filePath }
+          ]);
+        } else {
+          assert.deepStrictEqual(events, [
+            { action: "created", path: linkPath },
+            { action: "deleted", path: linkPath }
+          ]);
+        }
+      });
+    }
+
+    it("reports deep events once a non-recursive watch is made recursive", async () => {
+      const subPath = path.join(tempDirPath, "sub");
+      fs.mkdirSync(subPath);
+      const events = [];
+      const watch = await watcher.watchPath(
+        tempDirPath,
+        { recursive: false, includeRootEvents: false },
+        // Polling also reports the subdirectory as modified
+        e => events.push(...e.filter(event => event.path !== subPath))
+      );
+
+      fs.writeFileSync(path.join(subPath, "a"), "");
+      fs.writeFileSync(path.join(tempDirPath, "b"), "");
+      await condition(() => events.length === 1);
+      await new Promise(resolve => setTimeout(resolve, 300));
+      await watcher.flush();
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(tempDirPath, "b") }
+      ]);
+      events.length = 0;
+
+      await watch.setRecursive(true);
+      // The rescan is written after the response
+      await condition(() => events.length === 1);
+      assert.deepStrictEqual(events, [{ action: "rescan", path: tempDirPath }]);
+      events.length = 0;
+
+      fs.writeFileSync(path.join(subPath, "c"), "");
+      await condition(() => events.length === 1);
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(subPath, "c") }
+      ]);
+    });
+
+    it("stops delivering events for new paths past maxEntries", async function() {
+      // The poll watcher only notices mtime changes with one-second granularity
+      if (pollInterval) this.skip();
+
+      const [aPath, bPath, cPath] = ["a", "b", "c"].map(name =>
+        path.join(tempDirPath, name)
+      );
+      fs.writeFileSync(aPath, "");
+      const events = [];
+      const watch = await watcher.watchPath(
+        tempDirPath,
+        { maxEntries: 1, includeRootEvents: false },
+        e => events.push(...e)
+      );
+
+      fs.appendFileSync(aPath, "a");
+      await condition(() => events.length === 1);
+      fs.writeFileSync(bPath, "");
+      await condition(() => events.length === 2);
+      assert.deepStrictEqual(events, [
+        { action: "modified", path: aPath },
+        {
+          action: "error",
+          path: tempDirPath,
+          code: "tooManyEntries",
+          description:
+            "Events were emitted for 1 distinct paths, so events for new ones are dropped from now on"
+        }
+      ]);
+      events.length = 0;
+
+      fs.appendFileSync(aPath, "a");
+      await condition(() => events.length === 1);
+      fs.writeFileSync(cPath, "");
+      await new Promise(resolve => setTimeout(resolve, 300));
+      await watcher.flush();
+      assert.deepStrictEqual(events, [{ action: "modified", path: aPath }]);
+      events.length = 0;
+
+      await watch.setMaxEntries(2);
+      fs.unlinkSync(cPath);
+      await condition(() => events.length === 1);
+      assert.deepStrictEqual(events, [{ action: "deleted", path: cPath }]);
+    });
+
+    it("ignores the patterns in a rules file and reloads them when it changes", async function() {
+      // The poll watcher only notices mtime changes with one-second granularity
+      if (pollInterval) this.skip();
+
+      const rootPath = path.join(tempDirPath, "root");
+      const rulesPath = path.join(tempDirPath, ".watchignore");
+      const logPath = path.join(rootPath, "b.log");
+      fs.mkdirSync(rootPath);
+      fs.writeFileSync(rulesPath, "# Build output\n*.log\n\n");
+      const events = [];
+      const watch = await watcher.watchPath(rootPath, {}, e =>
+        events.push(...e)
+      );
+      await watch.reloadRules(rulesPath, { watch: true });
+
+      fs.writeFileSync(path.join(rootPath, "a.log"), "");
+      fs.writeFileSync(path.join(rootPath, "a.txt"), "");
+      await condition(() => events.length === 1);
+      await watcher.flush();
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(rootPath, "a.txt") }
+      ]);
+      assert.deepStrictEqual(await watch.wouldEmit(logPath, "created"), {
+        emit: false,
+        reason: "rules"
+      });
+      events.length = 0;
+
+      fs.writeFileSync(rulesPath, "*.txt\n");
+      await new Promise(resolve => setTimeout(resolve, 300));
+      await watcher.flush();
+      assert.deepStrictEqual(await watch.wouldEmit(logPath, "created"), {
+        emit: true,
+        reason: undefined
+      });
+      fs.writeFileSync(path.join(rootPath, "b.txt"), "");
+      fs.writeFileSync(logPath, "");
+      await condition(() => events.length === 1);
+      await watcher.flush();
+      assert.deepStrictEqual(events, [{ action: "created", path: logPath }]);
+
+      await assert.rejects(
+        watch.reloadRules(path.join(tempDirPath, "missing")),
+        /Error reading rules file/
+      );
+    });
+
+    it("leaves out the paths ignored by .gitignore files when gitignoreFiles is set", async function() {
+      const subPath = path.join(tempDirPath, "sub");
+      fs.mkdirSync(subPath);
+      fs.mkdirSync(path.join(tempDirPath, "build"));
+      fs.writeFileSync(
+        path.join(tempDirPath, ".gitignore"),
+        "*.log\n!keep.log\nbuild/\n/top.txt\n"
+      );
+      fs.writeFileSync(path.join(subPath, ".gitignore"), "!debug.log\n");
+      const kept = [
+        path.join(tempDirPath, "keep.log"),
+        path.join(subPath, "debug.log"),
+        path.join(subPath, "top.txt"),
+        path.join(subPath, "build")
+      ];
+      const ignored = [
+        path.join(tempDirPath, "a.log"),
+        path.join(subPath, "a.log"),
+        path.join(tempDirPath, "top.txt"),
+        path.join(tempDirPath, "build", "out.js")
+      ];
+      const paths = new Set();
+      const watch = await watcher.watchPath(
+        tempDirPath,
+        { gitignoreFiles: true },
+        e => e.forEach(event => paths.add(event.path))
+      );
+
+      for (const file of [...ignored, ...kept]) {
+        fs.writeFileSync(file, "");
+      }
+      await condition(() => kept.every(file => paths.has(file)));
+      await new Promise(resolve => setTimeout(resolve, 300));
+      await watcher.flush();
+      assert.deepStrictEqual(ignored.filter(file => paths.has(file)), []);
+      assert.deepStrictEqual(await watch.wouldEmit(ignored[0], "created"), {
+        emit: false,
+        reason: "gitignoreFiles"
+      });
+    });
+
+    it("classifies modifications by their size change when trackSizeDelta is set", async function() {
+      // The poll watcher only notices mtime changes with one-second granularity
+      if (pollInterval) this.skip();
+
+      const logPath = path.join(tempDirPath, "log");
+      fs.writeFileSync(logPath, "abc");
+      const events = [];
+      await watcher.watchPath(tempDirPath, { trackSizeDelta: true }, e =>
+        events.push(...e.filter(event => event.path === logPath))
+      );
+      const change = async update => {
+        const count = events.length;
+        update();
+        await condition(() => events.length > count);
+        assert.strictEqual(events.length, count + 1);
+        const { sizeBefore, sizeAfter, kind } = events[count];
+        return { sizeBefore, sizeAfter, kind };
+      };
+
+      // The size before the first modification wasn't seen
+      assert.deepStrictEqual(
+        await change(() => fs.appendFileSync(logPath, "d")),
+        { sizeBefore: undefined, sizeAfter: 4, kind: "unknown" }
+      );
+      assert.deepStrictEqual(
+        await change(() => fs.appendFileSync(logPath, "ef")),
+        { sizeBefore: 4, sizeAfter: 6, kind: "append" }
+      );
+      assert.deepStrictEqual(
+        await change(() => fs.truncateSync(logPath, 0)),
+        { sizeBefore: 6, sizeAfter: 0, kind: "truncate" }
+      );
+      assert.deepStrictEqual(
+        await change(() => fs.writeFileSync(logPath, "xy")),
+        { sizeBefore: 0, sizeAfter: 2, kind: "append" }
+      );
+      assert.deepStrictEqual(
+        await change(() => fs.writeFileSync(logPath, "zz")),
+        { sizeBefore: 2, sizeAfter: 2, kind: "rewrite" }
+      );
+    });
+
+    it("reports a native backend that stays silent when watchdogMs is set", async function() {
+      // Only native watches are checked
+      if (pollInterval) this.skip();
+      this.timeout(5000);
+
+      // Replacing the root leaves inotify watching the old directory, so
+      // nothing is reported for the new one
+      const rootPath = path.join(tempDirPath, "root");
+      fs.mkdirSync(rootPath);
+      const events = [];
+      await watcher.watchPath(
+        rootPath,
+        { watchdogMs: 300, watchdogFallback: true },
+        e => events.push(...e)
+      );
+      fs.renameSync(rootPath, path.join(tempDirPath, "old"));
+      fs.mkdirSync(rootPath);
+      await new Promise(resolve => setTimeout(resolve, 700));
+      const count = events.length;
+      fs.writeFileSync(path.join(rootPath, "a"), "");
+
+      await condition(() => events.some(event => event.code === "backendSilent"));
+      assert.deepStrictEqual(events.slice(count), [
+        {
+          action: "error",
+          path: rootPath,
+          description: `The native backend reported nothing for 300ms although ${JSON.stringify(
+            rootPath
+          )} changed, so it's polled from now on`,
+          code: "backendSilent"
+        },
+        { action: "rescan", path: rootPath }
+      ]);
+
+      // The poll backend picks up the new directory
+      const bPath = path.join(rootPath, "b");
+      fs.writeFileSync(bPath, "");
+      await condition(() => events.some(event => event.path === bPath));
+    });
+
+    it("only reports new files and deletions when createdOnly is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const existingPath = path.join(tempDirPath, "existing");
+      const newPath = path.join(tempDirPath, "new");
+      fs.writeFileSync(existingPath, "");
+      const events = [];
+      await watcher.watchPath(tempDirPath, { createdOnly: true }, e =>
+        events.push(...e)
+      );
+
+      fs.appendFileSync(existingPath, "more");
+      fs.writeFileSync(newPath, "");
+      await condition(() => events.some(event => event.path === newPath));
+      // Modifying the new file after it was reported is left out as well
+      fs.appendFileSync(newPath, "more");
+      fs.unlinkSync(existingPath);
+      await condition(() => events.some(event => event.path === existingPath));
+      await watcher.flush();
+
+      assert.deepStrictEqual(events, [
+        { action: "created", path: newPath },
+        { action: "deleted", path: existingPath }
+      ]);
+    });
+
+    it("only emits creations and modifications of files within minSize and maxSize", async function() {
+      const events = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { minSize: 2, maxSize: 4 },
+        e => events.push(...e)
+      );
+
+      const smallPath = path.join(tempDirPath, "small");
+      const largePath = path.join(tempDirPath, "large");
+      const fittingPath = path.join(tempDirPath, "fitting");
+      fs.writeFileSync(smallPath, "a");
+      fs.writeFileSync(largePath, "abcde");
+      fs.writeFileSync(fittingPath, "abc");
+      await condition(() => events.some(event => event.path === fittingPath));
+      await watcher.flush();
+      assert(events.every(event => event.path === fittingPath));
+
+      // Deleted files can't be measured, so their deletions always get through
+      fs.unlinkSync(smallPath);
+      await condition(() =>
+        events.some(event => event.action === "deleted" && event.path === smallPath)
+      );
+    });
+
+    it("rejects a minSize greater than maxSize", async function() {
+      await assert.rejects(
+        watcher.watchPath(tempDirPath, { minSize: 5, maxSize: 4 }, () => {}),
+        /greater than maxSize/
+      );
+    });
+
+    it("only emits events on the file systems listed in filesystems", async function() {
+      if (process.platform !== "linux") this.skip();
+      const fsType = execFileSync("findmnt", ["-no", "FSTYPE", "-T", tempDirPath])
+        .toString()
+        .trim();
+      const allowedEvents = [];
+      const otherEvents = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { filesystems: [fsType] },
+        e => allowedEvents.push(...e)
+      );
+      await watcher.watchPath(
+        tempDirPath,
+        { filesystems: ["no-such-fs"] },
+        e => otherEvents.push(...e)
+      );
+
+      const filePath = path.join(tempDirPath, "file");
+      fs.writeFileSync(filePath, "");
+      await condition(() => allowedEvents.some(event => event.path === filePath));
+      await watcher.flush();
+      assert.deepStrictEqual(otherEvents, []);
+    });
+
+    it("replays the last events a watch emitted when history is set", async function() {
+      const events = [];
+      const watch = await watcher.watchPath(
+        tempDirPath,
+        { history: 2 },
+        e => events.push(...e)
+      );
+
+      for (const name of ["a", "b", "c"]) {
+        fs.writeFileSync(path.join(tempDirPath, name), "");
+        await condition(() => events.length > 0);
+        events.length = 0;
+      }
+      await watcher.flush();
+      assert.deepStrictEqual(await watch.history(), [
+        { action: "created", path: path.join(tempDirPath, "b") },
+        { action: "created", path: path.join(tempDirPath, "c") }
+      ]);
+    });
+
+    it("emits an idle event once a watch has been quiet for idleAfterMs", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { idleAfterMs: 200 },
+        // Polling also reports the root as modified
+        e => events.push(...e.filter(event => event.path !== tempDirPath))
+      );
+
+      const aPath = path.join(tempDirPath, "a");
+      fs.writeFileSync(aPath, "");
+      await condition(() => events.some(event => event.action === "idle"));
+      assert.deepStrictEqual(events, [
+        { action: "created", path: aPath },
+        { action: "idle" }
+      ]);
+
+      // Only another event re-arms it
+      await new Promise(resolve => setTimeout(resolve, 400));
+      assert.strictEqual(events.length, 2);
+      fs.unlinkSync(aPath);
+      await condition(() => events.length === 4);
+      assert.deepStrictEqual(events.slice(2), [
+        { action: "deleted", path: aPath },
+        { action: "idle" }
+      ]);
+    });
+
+    it("delivers counts of changes instead of events when summaryIntervalMs is set", async function() {
+      const events = [];
+      const watch = await watcher.watchPath(
+        tempDirPath,
+        // Polling also reports the root as modified
+        { summaryIntervalMs: 200, includeRootEvents: false },
+        e => events.push(...e)
+      );
+      const total = action =>
+        events.reduce((sum, event) => sum + event[action], 0);
+
+      for (const name of ["a", "b", "c"]) {
+        fs.writeFileSync(path.join(tempDirPath, name), "");
+      }
+      await condition(() => total("created") === 3);
+      fs.unlinkSync(path.join(tempDirPath, "a"));
+      await condition(() => total("deleted") === 1);
+      for (const event of events) {
+        assert.strictEqual(event.action, "summary");
+        assert.strictEqual(event.watchId, watch.watchId);
+        assert(event.windowMs >= 200);
+      }
+      assert.strictEqual(total("modified"), 0);
+      assert.strictEqual(total("renamed"), 0);
+    });
+
+    it("maintains a hash of the tree when treeHash is set", async function() {
+      const events = [];
+      const aPath = path.join(tempDirPath, "a");
+      fs.writeFileSync(aPath, "");
+      const watch = await watcher.watchPath(
+        tempDirPath,
+        { treeHash: true },
+        e => events.push(...e)
+      );
+      const initialHash = await watch.treeHash();
+      assert.match(initialHash, /^[0-9a-f]{16}$/);
+
+      const bPath = path.join(tempDirPath, "b");
+      fs.writeFileSync(bPath, "b");
+      await condition(() => events.some(event => event.path === bPath));
+      await watcher.flush();
+      assert.notStrictEqual(await watch.treeHash(), initialHash);
+
+      fs.unlinkSync(bPath);
+      await condition(() => events.some(event => event.action === "deleted"));
+      await watcher.flush();
+      assert.strictEqual(await watch.treeHash(), initialHash);
+    });
+
+    it("reports watches being added and removed after subscribeLifecycle", async function() {
+      const lifecycle = [];
+      await watcher.watchPath(tempDirPath, () => {});
+      await watcher.subscribeLifecycle(change => lifecycle.push(change));
+
+      const watch = await watcher.watchPath(tempDirPath, () => {});
+      await watch.dispose();
+      await condition(() => lifecycle.length === 2);
+      assert.deepStrictEqual(lifecycle, [
+        { kind: "watchAdded", watchId: 1, detail: "watch" },
+        { kind: "watchRemoved", watchId: 1, detail: "unwatch" }
+      ]);
+    });
+
+    it("asks for a rescan after changing the debounce interval with setDebounce", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, e => events.push(...e));
+      await watcher.setDebounce(20);
+      await condition(() => events.length === 1);
+      assert.deepStrictEqual(events, [{ action: "rescan", path: tempDirPath }]);
+
+      const aPath = path.join(tempDirPath, "a");
+      fs.writeFileSync(aPath, "");
+      await condition(() => events.some(event => event.path === aPath));
+    });
+
+    it("debounces a single watch differently with its setDebounce", async function() {
+      this.timeout(5000);
+
+      const slowEvents = [];
+      const events = [];
+      const slowWatch = await watcher.watchPath(tempDirPath, e =>
+        slowEvents.push(...e)
+      );
+      await watcher.watchPath(tempDirPath, e => events.push(...e));
+      if (pollInterval) {
+        await assert.rejects(slowWatch.setDebounce(1000), /polled/);
+        return;
+      }
+
+      await slowWatch.setDebounce(1000);
+      await condition(() => slowEvents.length === 1);
+      assert.deepStrictEqual(slowEvents, [
+        { action: "rescan", path: tempDirPath }
+      ]);
+
+      const aPath = path.join(tempDirPath, "a");
+      fs.writeFileSync(aPath, "");
+      await condition(() => events.some(event => event.path === aPath));
+      assert.strictEqual(slowEvents.length, 1);
+      await condition(() => slowEvents.some(event => event.path === aPath));
+
+      await slowWatch.setDebounce();
+      await condition(() => slowEvents.length === 3);
+      assert.deepStrictEqual(slowEvents[2], {
+        action: "rescan",
+        path: tempDirPath
+      });
+    });
+
+    it("drops events while paused and asks for a rescan on resuming", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, e => events.push(...e));
+
+      assert.strictEqual(await watcher.pauseAll(), 1);
+      assert.strictEqual(await watcher.pauseAll(), 0);
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      if (pollInterval) await new Promise(resolve => setTimeout(resolve, 50));
+      await watcher.flush();
+      assert.deepStrictEqual(events, []);
+
+      assert.strictEqual(await watcher.resumeAll(), 1);
+      await condition(() => events.length === 1);
+      assert.deepStrictEqual(events, [{ action: "rescan", path: tempDirPath }]);
+
+      // Polling may still pick up the earlier change after resuming
+      const bPath = path.join(tempDirPath, "b");
+      fs.writeFileSync(bPath, "");
+      await condition(() => events.some(event => event.path === bPath));
+    });
+
+    it("delivers the events buffered while paused on resuming", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      const otherEvents = [];
+      const watch = await watcher.watchPath(tempDirPath, e =>
+        events.push(...e)
+      );
+      await watcher.watchPath(tempDirPath, e => otherEvents.push(...e));
+
+      await watch.bufferedPause(10);
+      const aPath = path.join(tempDirPath, "a");
+      fs.writeFileSync(aPath, "");
+      // The other watch isn't paused
+      await condition(() => otherEvents.length === 1);
+      await watcher.flush();
+      assert.deepStrictEqual(events, []);
+      assert.strictEqual(await watcher.debugQueue(), 1);
+
+      await watch.resume();
+      await condition(() => events.length === 1);
+      assert.deepStrictEqual(events, [{ action: "created", path: aPath }]);
+    });
+
+    it("asks for a rescan on resuming once the pause buffer overflows", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      const watch = await watcher.watchPath(tempDirPath, e =>
+        events.push(...e)
+      );
+
+      await watch.bufferedPause(1);
+      for (const name of ["a", "b", "c"]) {
+        fs.writeFileSync(path.join(tempDirPath, name), "");
+        if (pollInterval) await new Promise(resolve => setTimeout(resolve, 50));
+        await watcher.flush();
+      }
+
+      await watch.resume();
+      await condition(() => events.length === 2);
+      assert.strictEqual(events[0].action, "created");
+      assert.deepStrictEqual(events[1], { action: "rescan", path: tempDirPath });
+    });
+
+    it("reports subdirectories that can't be read", async function() {
+      // Permissions aren't enforced for root, and work differently on Windows
+      if (process.platform === "win32" || process.getuid() === 0) this.skip();
+
+      const lockedPath = path.join(tempDirPath, "locked");
+      fs.mkdirSync(lockedPath);
+      fs.chmodSync(lockedPath, 0o000);
+      try {
+        if (process.platform === "linux" && !pollInterval) {
+          // inotify refuses to watch the tree at all
+          await assert.rejects(
+            () => watcher.watchPath(tempDirPath, () => {}),
+            /unreadable directories/
+          );
+        } else {
+          const sub = await watcher.watchPath(tempDirPath, () => {});
+          assert.deepStrictEqual(sub.unwatchedSubdirs, [lockedPath]);
+        }
+      } finally {
+        fs.chmodSync(lockedPath, 0o755);
+      }
+    });
+
+    it("does not report dangling symlinks as subdirectories that can't be read", async function() {
+      // Creating symlinks takes extra privileges on Windows
+      if (process.platform === "win32") this.skip();
+
+      fs.symlinkSync(
+        path.join(tempDirPath, "missing"),
+        path.join(tempDirPath, "dangling")
+      );
+      const sub = await watcher.watchPath(tempDirPath, () => {});
+      assert.deepStrictEqual(sub.unwatchedSubdirs, []);
+    });
+
+    it("releases everything it registered when watching a tree fails partway", async function() {
+      // Only inotify gives up partway, and only for users whose permissions are enforced
+      if (process.platform !== "linux" || pollInterval || process.getuid() === 0)
+        this.skip();
+
+      fs.mkdirSync(path.join(tempDirPath, "a"));
+      fs.mkdirSync(path.join(tempDirPath, "a", "b"));
+      const lockedPath = path.join(tempDirPath, "locked");
+      fs.mkdirSync(lockedPath);
+      fs.chmodSync(lockedPath, 0o000);
+      try {
+        await assert.rejects(() => watcher.watchPath(tempDirPath, () => {}));
+      } finally {
+        fs.chmodSync(lockedPath, 0o755);
+      }
+
+      assert.deepStrictEqual(inotifyWatchDescriptors(watcher), []);
+    });
+
+    it("watches a path that does not exist yet once it's created", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const rootPath = path.join(tempDirPath, "out", "build");
+      const events = [];
+      const sub = await watcher.watchPath(rootPath, e => events.push(...e));
+      assert.deepStrictEqual(sub.unwatchedSubdirs, []);
+      assert.deepStrictEqual(await watcher.isWatched(path.join(tempDirPath, "x")), []);
+
+      fs.writeFileSync(path.join(tempDirPath, "sibling"), "");
+      fs.mkdirSync(path.join(tempDirPath, "out"));
+      fs.mkdirSync(rootPath);
+      await condition(() => events.length === 1);
+      assert.deepStrictEqual(events, [{ action: "created", path: rootPath }]);
+
+      fs.writeFileSync(path.join(rootPath, "a"), "");
+      await condition(() => events.length === 2);
+      await watcher.flush();
+      assert.deepStrictEqual(events[1], {
+        action: "created",
+        path: path.join(rootPath, "a")
+      });
+    });
+
+    it("delivers pending events before shutting down", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, e => events.push(...e));
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      await watcher.shutdown(2000);
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(tempDirPath, "a") }
+      ]);
+    });
+
+    it("shuts down without waiting longer than drainMs", async function() {
+      // Debounce events for much longer than the drain allows
+      await watcher.kill();
+      watcher = new Watcher({ pollInterval, debounceMs: 10000 });
+      const events = [];
+      await watcher.watchPath(tempDirPath, e => events.push(...e));
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      const start = Date.now();
+      await watcher.shutdown(200);
+      assert(Date.now() - start < 2000);
+    });
+
+    it("reports the root's creation as rootCreated when rootCreatedEvent is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const rootPath = path.join(tempDirPath, "out");
+      const events = [];
+      await watcher.watchPath(rootPath, { rootCreatedEvent: true }, e =>
+        events.push(...e)
+      );
+
+      fs.mkdirSync(rootPath);
+      await condition(() => events.length === 1);
+      fs.writeFileSync(path.join(rootPath, "a"), "");
+      await condition(() => events.length === 2);
+      await watcher.flush();
+      assert.deepStrictEqual(events, [
+        { action: "rootCreated", path: rootPath },
+        { action: "created", path: path.join(rootPath, "a") }
+      ]);
+    });
+
+    it("reports the contents of the root on every change when listingMode is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, { listingMode: true }, e =>
+        events.push(...e)
+      );
+
+      fs.mkdirSync(path.join(tempDirPath, "sub"));
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      fs.writeFileSync(path.join(tempDirPath, "b"), "");
+      const listing = {
+        action: "listing",
+        path: tempDirPath,
+        entries: ["a", "b", "sub"].map(name => path.join(tempDirPath, name))
+      };
+      await condition(
+        () =>
+          events.length > 0 &&
+          JSON.stringify(events[events.length - 1]) === JSON.stringify(listing)
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, "sub", "c"), "");
+      fs.unlinkSync(path.join(tempDirPath, "a"));
+      await condition(() => events[events.length - 1].entries.length === 2);
+      await watcher.flush();
+      assert(events.every(event => event.action === "listing"));
+      assert.deepStrictEqual(events[events.length - 1], {
+        action: "listing",
+        path: tempDirPath,
+        entries: ["b", "sub"].map(name => path.join(tempDirPath, name))
+      });
+    });
+
+    it("reports only what was added and removed when listingDelta is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      const events = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { listingMode: true, listingDelta: true },
+        e => events.push(...e)
+      );
+
+      fs.mkdirSync(path.join(tempDirPath, "sub"));
+      fs.writeFileSync(path.join(tempDirPath, "b"), "");
+      const added = () => events.flatMap(event => event.added);
+      await condition(() => added().length === 2);
+      fs.writeFileSync(path.join(tempDirPath, "sub", "c"), "");
+      fs.unlinkSync(path.join(tempDirPath, "a"));
+      await condition(() => events.some(event => event.removed.length > 0));
+      await watcher.flush();
+
+      assert(events.every(event => event.action === "listingDelta"));
+      assert.deepStrictEqual(
+        added().sort(),
+        ["b", "sub"].map(name => path.join(tempDirPath, name))
+      );
+      assert.deepStrictEqual(events.flatMap(event => event.removed), [
+        path.join(tempDirPath, "a")
+      ]);
+    });
+
+    it("stops watching once a watch has emitted limit events", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { limit: 2, ignoreTempFiles: true },
+        e => events.push(...e)
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, "a.swp"), "");
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      fs.writeFileSync(path.join(tempDirPath, "b"), "");
+      fs.writeFileSync(path.join(tempDirPath, "c"), "");
+      await condition(() => events.length === 2);
+      await watcher.flush();
+      assert.deepStrictEqual(await watcher.isWatched(tempDirPath), []);
+
+      fs.writeFileSync(path.join(tempDirPath, "d"), "");
+      await watcher.flush();
+      assert.strictEqual(events.length, 2);
+      assert(events.every(event => event.action === "created"));
+      assert(!events.some(event => event.path.endsWith(".swp")));
+    });
+
+    it("lists the ancestors of event paths when includeAncestors is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      fs.mkdirSync(path.join(tempDirPath, "a", "b"), { recursive: true });
+      const events = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { includeAncestors: true },
+        e => events.push(...e)
+      );
+      const eventFor = name =>
+        events.find(event => event.path === path.join(tempDirPath, name));
+
+      fs.writeFileSync(path.join(tempDirPath, "a", "b", "c"), "");
+      await condition(() => eventFor("a/b/c"));
+      assert.deepStrictEqual(eventFor("a/b/c").ancestors, [
+        path.join(tempDirPath, "a", "b"),
+        path.join(tempDirPath, "a")
+      ]);
+
+      // Renames list the ancestors of their new path
+      fs.renameSync(
+        path.join(tempDirPath, "a", "b", "c"),
+        path.join(tempDirPath, "a", "d")
+      );
+      await condition(() => eventFor("a/d"));
+      assert.deepStrictEqual(eventFor("a/d").ancestors, [
+        path.join(tempDirPath, "a")
+      ]);
+    });
+
+    it("omits events for the root when includeRootEvents is false", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { includeRootEvents: false },
+        e => events.push(...e)
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      await condition(() => events.length > 0);
+      await watcher.flush();
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(tempDirPath, "a") }
+      ]);
+      assert.deepStrictEqual(await watcher.isWatched(tempDirPath), []);
+    });
+
+    it("describes the entries a watch reports on with snapshot", async function() {
+      fs.mkdirSync(path.join(tempDirPath, "sub"));
+      fs.writeFileSync(path.join(tempDirPath, "sub", "a"), "abc");
+      fs.writeFileSync(path.join(tempDirPath, "sub", "a.swp"), "");
+      const watch = await watcher.watchPath(
+        tempDirPath,
+        { ignoreTempFiles: true },
+        () => {}
+      );
+
+      const entries = await watch.snapshot();
+      assert.deepStrictEqual(
+        entries.map(entry => ({ path: entry.path, size: entry.size })),
+        [
+          { path: path.join(tempDirPath, "sub"), size: undefined },
+          { path: path.join(tempDirPath, "sub", "a"), size: 3 }
+        ]
+      );
+      assert.strictEqual(
+        entries[1].mtimeMs,
+        Math.floor(fs.statSync(path.join(tempDirPath, "sub", "a")).mtimeMs)
+      );
+    });
+
+    it("holds events until the flushOn file changes", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const batches = [];
+      await watcher.watchPath(tempDirPath, { flushOn: ".build-ready" }, e =>
+        batches.push(e.filter(event => event.path !== tempDirPath))
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      fs.writeFileSync(path.join(tempDirPath, "b"), "");
+      await watcher.flush();
+      assert(batches.every(batch => batch.length === 0));
+
+      fs.writeFileSync(path.join(tempDirPath, ".build-ready"), "");
+      await condition(() => batches.some(batch => batch.length > 0));
+      const delivered = batches.filter(batch => batch.length > 0);
+      assert.strictEqual(delivered.length, 1);
+      // The debouncer reports changes made together in no particular order
+      const held = delivered[0].slice(0, -1).map(event => event.path);
+      assert.deepStrictEqual(held.sort(), [
+        path.join(tempDirPath, "a"),
+        path.join(tempDirPath, "b")
+      ]);
+      assert.deepStrictEqual(delivered[0][delivered[0].length - 1], {
+        action: "created",
+        path: path.join(tempDirPath, ".build-ready")
+      });
+    });
+
+    it("stops watching once durationMs has passed", async function() {
+      // The poll watcher only notices mtime changes with one-second granularity
+      if (pollInterval) this.skip();
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      const events = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { durationMs: 500, trailingQuietMs: 60000 },
+        e => events.push(...e)
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "changed");
+      // Only emitted once the watch's time is up, long before it has been quiet for long enough
+      await condition(() => events.length === 1);
+      await watcher.flush();
+      assert.deepStrictEqual(await watcher.isWatched(tempDirPath), []);
+      assert.deepStrictEqual(events, [
+        { action: "modified", path: path.join(tempDirPath, "a") }
+      ]);
+
+      fs.writeFileSync(path.join(tempDirPath, "b"), "");
+      await watcher.flush();
+      assert.strictEqual(events.length, 1);
+    });
+
+    it("numbers events across all watches when globalSeq is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      await watcher.kill();
+      watcher = new Watcher({ pollInterval, globalSeq: true });
+      fs.mkdirSync(path.join(tempDirPath, "sub"));
+      const events = [];
+      await watcher.watchPath(tempDirPath, e => events.push(...e));
+      await watcher.watchPath(path.join(tempDirPath, "sub"), e =>
+        events.push(...e)
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      fs.writeFileSync(path.join(tempDirPath, "sub", "b"), "");
+      await condition(() => events.length === 3);
+      await watcher.flush();
+      assert.deepStrictEqual(events.map(event => event.globalSeq), [1, 2, 3]);
+    });
+
+    it("gives every event a random UUID when eventIds is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      await watcher.kill();
+      watcher = new Watcher({ pollInterval, eventIds: true });
+      const events = [];
+      await watcher.watchPath(tempDirPath, e => events.push(...e));
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+      fs.writeFileSync(path.join(tempDirPath, "b"), "");
+      await condition(() => events.length === 2);
+      const uuid = /^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$/;
+      assert(events.every(event => uuid.test(event.eventId)));
+      assert.notStrictEqual(events[0].eventId, events[1].eventId);
+    });
+
+    it("reports inodes for created and deleted files when includeInode is set", async function() {
+      if (process.platform === "win32") this.skip();
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, { includeInode: true }, e =>
+        events.push(...e)
+      );
+
+      const filePath = path.join(tempDirPath, "a");
+      fs.writeFileSync(filePath, "");
+      const { ino, dev } = fs.statSync(filePath);
+
+      await condition(() => events.length === 1);
+      fs.unlinkSync(filePath);
+      await condition(() => events.length === 2);
+
+      assert.deepStrictEqual(events, [
+        { action: "created", path: filePath, inode: ino, dev },
+        { action: "deleted", path: filePath, inode: ino, dev }
+      ]);
+    });
+
+    it("delivers batched events to every watch when batchEmit is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const batchWatcher = new Watcher({ pollInterval, batchEmit: true });
+      try {
+        const events1 = [];
+        const events2 = [];
+        await batchWatcher.watchPath(tempDirPath, e => events1.push(...e));
+        await batchWatcher.watchPath(tempDirPath, e => events2.push(...e));
+
+        fs.writeFileSync(path.join(tempDirPath, "a"), "");
+
+        await condition(() => events1.length === 1 && events2.length === 1);
+        assert.deepStrictEqual(events1, events2);
+      } finally {
+        await batchWatcher.kill();
+      }
+    });
+
+    it("only emits modifications that change an attribute in modifiedOn", async function() {
+      // The poll watcher only notices mtime changes with one-second granularity
+      if (pollInterval) this.skip();
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, { modifiedOn: ["content"] }, e =>
+        events.push(...e)
+      );
+
+      const filePath = path.join(tempDirPath, "a");
+      fs.writeFileSync(filePath, "x");
+      await condition(() => events.length === 1);
+
+      // Rewriting the same content is suppressed
+      await new Promise(resolve => setTimeout(resolve, 50));
+      fs.writeFileSync(filePath, "x");
+      await new Promise(resolve => setTimeout(resolve, 50));
+      await watcher.flush();
+      assert.strictEqual(events.length, 1);
+
+      fs.writeFileSync(filePath, "y");
+      await condition(() => events.length === 2);
+      assert.deepStrictEqual(events[1], { action: "modified", path: filePath });
+    });
+
+    it("drops modifications that leave contents unchanged when suppressNopChanges is set", async function() {
+      // The poll watcher only notices mtime changes with one-second granularity
+      if (pollInterval) this.skip();
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, { suppressNopChanges: true }, e =>
+        events.push(...e)
+      );
+
+      const filePath = path.join(tempDirPath, "a");
+      fs.writeFileSync(filePath, "x");
+      await condition(() => events.length === 1);
+
+      // Touching the file or rewriting the same content is suppressed
+      await new Promise(resolve => setTimeout(resolve, 50));
+      const now = new Date();
+      fs.utimesSync(filePath, now, now);
+      await new Promise(resolve => setTimeout(resolve, 50));
+      fs.writeFileSync(filePath, "x");
+      await new Promise(resolve => setTimeout(resolve, 50));
+      await watcher.flush();
+      assert.strictEqual(events.length, 1);
+
+      fs.writeFileSync(filePath, "y");
+      await condition(() => events.length === 2);
+      assert.deepStrictEqual(events[1], { action: "modified", path: filePath });
+    });
+
+    it("reports entries changed after sinceMs when the watch starts", async () => {
+      fs.writeFileSync(path.join(tempDirPath, "old"), "");
+      await new Promise(resolve => setTimeout(resolve, 50));
+      const sinceMs = Date.now();
+      await new Promise(resolve => setTimeout(resolve, 50));
+      fs.writeFileSync(path.join(tempDirPath, "new"), "");
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, { sinceMs }, e => events.push(...e));
+
+      await condition(() => events.length === 1);
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(tempDirPath, "new") }
+      ]);
+    });
+
+    it("surrounds the events emitted together with markers when batchMarkers is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const aPath = path.join(tempDirPath, "a");
+      const bPath = path.join(tempDirPath, "b");
+      const cPath = path.join(tempDirPath, "c");
+      fs.writeFileSync(aPath, "");
+      fs.writeFileSync(bPath, "");
+      const batches = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { sinceMs: Date.now() - 60 * 1000, batchMarkers: true },
+        events => batches.push(events)
+      );
+
+      await condition(() => batches.length === 1);
+      const [begin, ...rest] = batches[0];
+      const end = rest.pop();
+      assert.deepStrictEqual(begin, { action: "batchBegin", batchId: 0 });
+      assert.deepStrictEqual(end, { action: "batchEnd", batchId: 0 });
+      assert.deepStrictEqual(rest.map(event => event.path).sort(), [
+        aPath,
+        bPath
+      ]);
+
+      fs.writeFileSync(cPath, "");
+      await condition(() =>
+        batches.some(events => events.some(event => event.path === cPath))
+      );
+      const batch = batches.find(events =>
+        events.some(event => event.path === cPath)
+      );
+      const batchId = batches.indexOf(batch);
+      // Polling can also report the root as modified in the same batch
+      assert.deepStrictEqual(
+        batch.filter(event => event.path !== tempDirPath),
+        [
+          { action: "batchBegin", batchId },
+          { action: "created", path: cPath },
+          { action: "batchEnd", batchId }
+        ]
+      );
+    });
+
+    it("reports symlink cycles instead of walking them forever", async function() {
+      // Creating symlinks requires extra privileges on Windows
+      if (process.platform === "win32") this.skip();
+
+      const sinceMs = Date.now() - 60 * 1000;
+      fs.mkdirSync(path.join(tempDirPath, "sub"));
+      fs.symlinkSync(tempDirPath, path.join(tempDirPath, "sub", "loop"));
+
+      const events = [];
+      const sub = await watcher.watchPath(tempDirPath, { sinceMs }, e =>
+        events.push(...e)
+      );
+      assert.deepStrictEqual(sub.unwatchedSubdirs, []);
+
+      await condition(() => events.some(event => event.action === "error"));
+      const errors = events.filter(event => event.action === "error");
+      assert.strictEqual(errors.length, 1);
+      assert.strictEqual(errors[0].path, path.join(tempDirPath, "sub", "loop"));
+      assert(/Symlink cycle/.test(errors[0].description));
+    });
+
+    it("includes content hashes of small files when hash is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { hash: "sha256", hashMaxBytes: 5 },
+        e => events.push(...e)
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, "small"), "hello");
+      fs.writeFileSync(path.join(tempDirPath, "large"), "hello world");
+
+      await condition(() => events.length === 2);
+      const hashes = {};
+      for (const event of events) hashes[path.basename(event.path)] = event.hash;
+      assert.deepStrictEqual(hashes, {
+        small:
+          "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+        large: null
+      });
+    });
+
+    it("replaces the prefix given by rewrite in emitted paths", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const subPath = path.join(tempDirPath, "sub");
+      const bPath = path.join(tempDirPath, "b");
+      fs.mkdirSync(subPath);
+      const events = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { rewrite: [subPath, "/served"], hash: "sha256" },
+        e => events.push(...e)
+      );
+
+      fs.writeFileSync(path.join(subPath, "a"), "hello");
+      fs.writeFileSync(bPath, "hello");
+      await condition(
+        () =>
+          events.some(event => event.path === "/served/a") &&
+          events.some(event => event.path === bPath)
+      );
+      // Files are still hashed where they actually are
+      for (const event of events.filter(event => event.hash !== undefined)) {
+        assert.strictEqual(
+          event.hash,
+          "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+      }
+
+      fs.renameSync(path.join(subPath, "a"), path.join(subPath, "c"));
+      await condition(() => events.some(event => event.path === "/served/c"));
+      if (!pollInterval) {
+        assert(
+          events.some(
+            event =>
+              event.action === "renamed" &&
+              event.oldPath === "/served/a" &&
+              event.path === "/served/c"
+          )
+        );
+      }
+      assert(events.every(event => !event.path.startsWith(subPath)));
+    });
+
+    it("rejects a rewrite with an empty prefix", async () => {
       await assert.rejects(
         () =>
-          watcher.watchPath(path.join(tempDirPath, "does-not-exist"), () => {}),
-        "No path was found"
+          watcher.watchPath(tempDirPath, { rewrite: ["", "/served"] }, () => {}),
+        /rewrite's prefix to replace can't be empty/
+      );
+    });
+
+    it("rejects unsupported hash algorithms", async () => {
+      await assert.rejects(
+        () => watcher.watchPath(tempDirPath, { hash: "md5" }, () => {}),
+        /Unsupported hash algorithm "md5"/
+      );
+    });
+
+    it("only emits events for files whose first line matches contentMatch", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, { contentMatch: "^#!.*python" }, e =>
+        events.push(...e)
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "#!/usr/bin/env python3\nprint()\n");
+      fs.writeFileSync(path.join(tempDirPath, "b"), "#!/bin/sh\n# python\n");
+
+      await condition(() => events.length === 1);
+      await watcher.flush();
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(tempDirPath, "a") }
+      ]);
+    });
+
+    it("only emits events for the files given to watchFiles", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      fs.mkdirSync(path.join(tempDirPath, "a"));
+      fs.mkdirSync(path.join(tempDirPath, "b"));
+
+      // The files' directories are already covered by this watch, and must
+      // keep being watched once it's disposed
+      const treeSubscription = await watcher.watchPath(tempDirPath, () => {});
+      const events = [];
+      await watcher.watchFiles(
+        [
+          path.join(tempDirPath, "a", "x"),
+          path.join(tempDirPath, "a", "y"),
+          path.join(tempDirPath, "b", "z")
+        ],
+        e => events.push(...e)
+      );
+      await treeSubscription.dispose();
+
+      fs.writeFileSync(path.join(tempDirPath, "a", "x"), "");
+      fs.writeFileSync(path.join(tempDirPath, "a", "sibling"), "");
+      fs.writeFileSync(path.join(tempDirPath, "b", "z"), "");
+      fs.writeFileSync(path.join(tempDirPath, "c"), "");
+
+      await condition(() => events.length === 2);
+      await watcher.flush();
+      events.sort((a, b) => a.path.localeCompare(b.path));
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(tempDirPath, "a", "x") },
+        { action: "created", path: path.join(tempDirPath, "b", "z") }
+      ]);
+    });
+
+    it("filters each subtree according to the first matching profile", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      fs.mkdirSync(path.join(tempDirPath, "src"));
+      fs.mkdirSync(path.join(tempDirPath, "config"));
+      const events = [];
+      const profiles = [
+        { prefix: "src", ignore: ["*.log"] },
+        { prefix: "config", extensions: ["json"] }
+      ];
+      await watcher.watchPath(tempDirPath, { profiles }, e => events.push(...e));
+
+      fs.writeFileSync(path.join(tempDirPath, "src", "a.txt"), "");
+      fs.writeFileSync(path.join(tempDirPath, "src", "debug.log"), "");
+      fs.writeFileSync(path.join(tempDirPath, "config", "b.txt"), "");
+      fs.writeFileSync(path.join(tempDirPath, "config", "c.json"), "");
+      fs.writeFileSync(path.join(tempDirPath, "d.json"), "");
+
+      await condition(() => events.length === 2);
+      await watcher.flush();
+      events.sort((a, b) => a.path.localeCompare(b.path));
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(tempDirPath, "config", "c.json") },
+        { action: "created", path: path.join(tempDirPath, "src", "a.txt") }
+      ]);
+    });
+
+    it("filters the events of each watch on a shared root separately", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const rsEvents = [];
+      const mdEvents = [];
+      await watcher.watchPath(
+        tempDirPath,
+        { profiles: [{ prefix: "", extensions: ["rs"] }] },
+        e => rsEvents.push(...e)
       );
+      const nativeWatches =
+        process.platform === "linux" && !pollInterval
+          ? inotifyWatchDescriptors(watcher).length
+          : 0;
+      await watcher.watchPath(
+        tempDirPath,
+        { profiles: [{ prefix: "", extensions: ["md"] }] },
+        e => mdEvents.push(...e)
+      );
+      if (process.platform === "linux" && !pollInterval) {
+        // The second watch reuses the root's registration instead of adding one
+        assert.strictEqual(inotifyWatchDescriptors(watcher).length, nativeWatches);
+      }
+
+      fs.writeFileSync(path.join(tempDirPath, "main.rs"), "");
+      fs.writeFileSync(path.join(tempDirPath, "README.md"), "");
+      fs.writeFileSync(path.join(tempDirPath, "notes.txt"), "");
+      await condition(() => rsEvents.length === 1 && mdEvents.length === 1);
+      await watcher.flush();
+      assert.deepStrictEqual(rsEvents, [
+        { action: "created", path: path.join(tempDirPath, "main.rs") }
+      ]);
+      assert.deepStrictEqual(mdEvents, [
+        { action: "created", path: path.join(tempDirPath, "README.md") }
+      ]);
+    });
+
+    it("rejects invalid contentMatch patterns", async () => {
+      await assert.rejects(
+        () => watcher.watchPath(tempDirPath, { contentMatch: "(a" }, () => {}),
+        /Invalid pattern/
+      );
+    });
+
+    it("only emits the actions enabled for a watch", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      const sub = await watcher.watchPath(
+        tempDirPath,
+        { actions: ["deleted"] },
+        e => events.push(...e)
+      );
+
+      const filePath = path.join(tempDirPath, "a");
+      fs.writeFileSync(filePath, "");
+      if (pollInterval) await new Promise(resolve => setTimeout(resolve, 50));
+      await watcher.flush();
+      assert.deepStrictEqual(events, []);
+
+      assert.deepStrictEqual(await sub.addActions(["created"]), [
+        "created",
+        "deleted"
+      ]);
+      fs.writeFileSync(path.join(tempDirPath, "b"), "");
+      await condition(() => events.length === 1);
+
+      assert.deepStrictEqual(await sub.removeActions(["created"]), [
+        "deleted"
+      ]);
+      fs.unlinkSync(filePath);
+      await condition(() => events.length === 2);
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(tempDirPath, "b") },
+        { action: "deleted", path: filePath }
+      ]);
+    });
+
+    it("reports the actions a watch emits after its other options with effectiveActions", async () => {
+      const sub = await watcher.watchPath(
+        tempDirPath,
+        { actions: ["modified", "created", "renamed"], createdOnly: true },
+        () => {}
+      );
+      assert.deepStrictEqual(await sub.effectiveActions(), [
+        "created",
+        "renamed"
+      ]);
+
+      await sub.removeActions(["renamed"]);
+      assert.deepStrictEqual(await sub.effectiveActions(), ["created"]);
+    });
+
+    it("tells which option keeps an event from being emitted with wouldEmit", async () => {
+      const smallPath = path.join(tempDirPath, "small");
+      const largePath = path.join(tempDirPath, "large");
+      fs.writeFileSync(smallPath, "a");
+      fs.writeFileSync(largePath, "abcdefgh");
+      const sub = await watcher.watchPath(
+        tempDirPath,
+        { actions: ["created", "modified"], ignoreTempFiles: true, minSize: 4 },
+        () => {}
+      );
+
+      assert.deepStrictEqual(await sub.wouldEmit(largePath, "created"), {
+        emit: true,
+        reason: undefined
+      });
+      assert.deepStrictEqual(await sub.wouldEmit(smallPath, "modified"), {
+        emit: false,
+        reason: "minSize"
+      });
+      assert.deepStrictEqual(await sub.wouldEmit(largePath, "deleted"), {
+        emit: false,
+        reason: "actions"
+      });
+      assert.deepStrictEqual(
+        await sub.wouldEmit(path.join(tempDirPath, "a.swp"), "created"),
+        { emit: false, reason: "ignoreTempFiles" }
+      );
+      assert.deepStrictEqual(
+        await sub.wouldEmit(path.dirname(tempDirPath), "created"),
+        { emit: false, reason: "root" }
+      );
+    });
+
+    it("ignores editor temporary files when ignoreTempFiles is set", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, { ignoreTempFiles: true }, e =>
+        events.push(...e)
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, ".a.swp"), "");
+      fs.writeFileSync(path.join(tempDirPath, "a~"), "");
+      fs.writeFileSync(path.join(tempDirPath, "b"), "");
+
+      await condition(() => events.length === 1);
+      await watcher.flush();
+      assert.deepStrictEqual(events, [
+        { action: "created", path: path.join(tempDirPath, "b") }
+      ]);
+    });
+
+    it("reports the exact bytes of non-UTF-8 paths when binaryPaths is set", async function() {
+      // Other platforms' file systems don't allow such names
+      if (process.platform !== "linux") this.skip();
+      if (pollInterval) this.timeout(5000);
+
+      const events = [];
+      await watcher.watchPath(tempDirPath, { binaryPaths: true }, e =>
+        events.push(...e)
+      );
+
+      const binaryPath = Buffer.concat([
+        Buffer.from(path.join(tempDirPath, "a")),
+        Buffer.from([0xff])
+      ]);
+      fs.writeFileSync(binaryPath, "");
+      fs.writeFileSync(path.join(tempDirPath, "b"), "");
+
+      // The poll watcher can come across the two files in separate scans
+      const paths = [
+        path.join(tempDirPath, "a\ufffd"),
+        path.join(tempDirPath, "b")
+      ];
+      await condition(() =>
+        paths.every(file => events.some(event => event.path === file))
+      );
+      await watcher.flush();
+      events.sort((a, b) => a.path.localeCompare(b.path));
+      assert.deepStrictEqual(events, [
+        {
+          action: "created",
+          path: path.join(tempDirPath, "a\ufffd"),
+          pathEncoding: "bytes",
+          pathBytes: binaryPath.toString("base64")
+        },
+        {
+          action: "created",
+          path: path.join(tempDirPath, "b"),
+          pathEncoding: "utf8"
+        }
+      ]);
+    });
+
+    it("emits a single modification once writes stop when trailingQuietMs is set", async function() {
+      // The poll watcher only notices mtime changes with one-second granularity
+      if (pollInterval) this.skip();
+
+      const filePath = path.join(tempDirPath, "log");
+      fs.writeFileSync(filePath, "");
+      const events = [];
+      await watcher.watchPath(tempDirPath, { trailingQuietMs: 300 }, e =>
+        events.push(...e)
+      );
+
+      // Append to the file in a steady stream, faster than the quiet period
+      for (let i = 0; i < 30; i++) {
+        fs.appendFileSync(filePath, `line ${i}\n`);
+        await new Promise(resolve => setTimeout(resolve, 20));
+      }
+      assert.deepStrictEqual(events, []);
+
+      await condition(() => events.length === 1);
+      await new Promise(resolve => setTimeout(resolve, 400));
+      assert.deepStrictEqual(events, [{ action: "modified", path: filePath }]);
+    });
+
+    it("merges repeated modifications within the window when coalesceMs is set", async function() {
+      // The poll watcher only notices mtime changes with one-second granularity
+      if (pollInterval) this.skip();
+
+      const filePath = path.join(tempDirPath, "a");
+      fs.writeFileSync(filePath, "");
+      const events = [];
+      await watcher.watchPath(tempDirPath, { coalesceMs: 1500 }, e =>
+        events.push(...e)
+      );
+
+      // Write more slowly than the debouncer merges events, so that each write gets through it
+      for (let i = 0; i < 5; i++) {
+        fs.appendFileSync(filePath, `line ${i}\n`);
+        await new Promise(resolve => setTimeout(resolve, 150));
+      }
+      await watcher.flush();
+      assert.deepStrictEqual(events, []);
+
+      await condition(() => events.length === 1);
+      await new Promise(resolve => setTimeout(resolve, 300));
+      assert.deepStrictEqual(events, [{ action: "modified", path: filePath }]);
+    });
+
+    it("counts held modifications as pending in debugQueue", async function() {
+      // The poll watcher only notices mtime changes with one-second granularity
+      if (pollInterval) this.skip();
+
+      const filePath = path.join(tempDirPath, "a");
+      fs.writeFileSync(filePath, "");
+      await watcher.watchPath(tempDirPath, { trailingQuietMs: 60000 }, () => {});
+      assert.strictEqual(await watcher.debugQueue(), 0);
+
+      fs.appendFileSync(filePath, "more");
+      await watcher.flush();
+      assert.strictEqual(await watcher.debugQueue(), 1);
+    });
+
+    it("reports the subprocess's monotonic and wall-clock time", async () => {
+      const before = Date.now();
+      const first = await watcher.time();
+      const after = Date.now();
+      assert(before <= first.wallMs && first.wallMs <= after);
+
+      await new Promise(resolve => setTimeout(resolve, 50));
+      const second = await watcher.time();
+      assert(second.monotonicMs - first.monotonicMs >= 50);
+    });
+
+    it("summarizes request latencies when measureLatency is set", async () => {
+      await assert.rejects(watcher.latencies(), /--measure-latency/);
+
+      await watcher.kill();
+      watcher = new Watcher({ pollInterval, measureLatency: true });
+      assert.deepStrictEqual(await watcher.latencies(), {
+        p50Ms: 0,
+        p95Ms: 0,
+        maxMs: 0,
+        count: 0
+      });
+      await watcher.watchPath(tempDirPath, () => {});
+      await watcher.isWatched(tempDirPath);
+      const { p50Ms, p95Ms, maxMs, count } = await watcher.latencies();
+      // The first latencies request is measured as well
+      assert.strictEqual(count, 3);
+      assert(0 < p50Ms && p50Ms <= p95Ms && p95Ms <= maxMs);
+    });
+
+    it("reports the inotify limits and the descriptors in use with systemInfo", async () => {
+      fs.mkdirSync(path.join(tempDirPath, "sub"));
+      await watcher.watchPath(tempDirPath, () => {});
+
+      const info = await watcher.systemInfo();
+      if (process.platform !== "linux") {
+        assert.deepStrictEqual(info, {
+          maxUserWatches: null,
+          maxUserInstances: null,
+          currentInstanceWatches: null
+        });
+        return;
+      }
+      const limit = name =>
+        Number(fs.readFileSync(`/proc/sys/fs/inotify/${name}`, "utf8"));
+      assert.deepStrictEqual(info, {
+        maxUserWatches: limit("max_user_watches"),
+        maxUserInstances: limit("max_user_instances"),
+        currentInstanceWatches: inotifyWatchDescriptors(watcher).length
+      });
+      if (!pollInterval) assert(info.currentInstanceWatches >= 2);
+    });
+
+    it("delivers events once per watch when watches use different backends", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      const nativeEvents = [];
+      const pollEvents = [];
+      await watcher.watchPath(tempDirPath, { backend: "native" }, e =>
+        nativeEvents.push(...e)
+      );
+      await watcher.watchPath(tempDirPath, { pollIntervalMs: 10 }, e =>
+        pollEvents.push(...e)
+      );
+
+      fs.writeFileSync(path.join(tempDirPath, "a"), "");
+
+      await condition(() => nativeEvents.length === 1 && pollEvents.length === 1);
+      await watcher.flush();
+      const expected = [{ action: "created", path: path.join(tempDirPath, "a") }];
+      assert.deepStrictEqual(nativeEvents, expected);
+      assert.deepStrictEqual(pollEvents, expected);
+    });
+
+    it("translates paths according to pathMap", async function() {
+      if (pollInterval) this.timeout(5000);
+
+      // Pretend the watcher sees tempDirPath's files under a different directory
+      const containerPath = fs.realpathSync(temp.mkdirSync());
+      const mappedWatcher = new Watcher({
+        pollInterval,
+        pathMap: [`${tempDirPath}:${containerPath}`]
+      });
+      try {
+        const events = [];
+        await mappedWatcher.watchPath(tempDirPath, e => events.push(...e));
+
+        fs.writeFileSync(path.join(containerPath, "a"), "");
+
+        await condition(() => events.length === 1);
+        assert.deepStrictEqual(events, [
+          { action: "created", path: path.join(tempDirPath, "a") }
+        ]);
+      } finally {
+        await mappedWatcher.kill();
+      }
+    });
+
+    it("reports queue overflows so that clients can rescan", async function() {
+      // Overflowing the queue relies on inotify's limits
+      if (process.platform !== "linux" || pollInterval) this.skip();
+      const maxQueuedEvents = Number(
+        fs.readFileSync("/proc/sys/fs/inotify/max_queued_events", "utf8")
+      );
+      if (maxQueuedEvents > 100000) this.skip();
+      this.timeout(30000);
+
+      // The overflow is reported without being debounced. Holding back everything else keeps
+      // notify's debouncer idle while the queue is drained, which it otherwise can't be relied on
+      // to survive.
+      const slowWatcher = new Watcher({ debounceMs: 60000 });
+      try {
+        const events = [];
+        await slowWatcher.watchPath(tempDirPath, e => events.push(...e));
+
+        // Suspend the subprocess so that it can't drain the queue while we flood it
+        slowWatcher.childProcess.kill("SIGSTOP");
+        try {
+          for (let i = 0; i <= maxQueuedEvents; i++) {
+            fs.writeFileSync(path.join(tempDirPath, `f${i}`), "");
+          }
+        } finally {
+          slowWatcher.childProcess.kill("SIGCONT");
+        }
+
+        // notify only reads a small chunk of the queue each time it's woken up by a new event,
+        // so keep generating events until it gets to the overflow
+        const pokePath = path.join(tempDirPath, "poke");
+        const deadline = Date.now() + 20000;
+        while (events.length < 2 && Date.now() < deadline) {
+          fs.writeFileSync(pokePath, "");
+          await new Promise(resolve => setTimeout(resolve, 1));
+        }
+
+        assert.deepStrictEqual(events, [
+          { action: "queueOverflow" },
+          { action: "rescan", path: tempDirPath }
+        ]);
+      } finally {
+        await slowWatcher.kill();
+      }
+    });
+
+    it("reports queue overflows to watches in listing mode", async function() {
+      // Overflowing the queue relies on inotify's limits
+      if (process.platform !== "linux" || pollInterval) this.skip();
+      const maxQueuedEvents = Number(
+        fs.readFileSync("/proc/sys/fs/inotify/max_queued_events", "utf8")
+      );
+      if (maxQueuedEvents > 100000) this.skip();
+      this.timeout(30000);
+
+      const slowWatcher = new Watcher({ debounceMs: 60000 });
+      try {
+        const events = [];
+        await slowWatcher.watchPath(tempDirPath, { listingMode: true }, e =>
+          events.push(...e)
+        );
+
+        slowWatcher.childProcess.kill("SIGSTOP");
+        try {
+          for (let i = 0; i <= maxQueuedEvents; i++) {
+            fs.writeFileSync(path.join(tempDirPath, `f${i}`), "");
+          }
+        } finally {
+          slowWatcher.childProcess.kill("SIGCONT");
+        }
+
+        const pokePath = path.join(tempDirPath, "poke");
+        const deadline = Date.now() + 20000;
+        while (events.length < 2 && Date.now() < deadline) {
+          fs.writeFileSync(pokePath, "");
+          await new Promise(resolve => setTimeout(resolve, 1));
+        }
+
+        // The overflow comes with a full listing to start over from
+        assert.deepStrictEqual(
+          events.map(event => [event.action, event.path]),
+          [["queueOverflow", undefined], ["listing", tempDirPath]]
+        );
+      } finally {
+        await slowWatcher.kill();
+      }
+    });
+
+    it("receives keepalives while idle when keepaliveMs is set", async () => {
+      const keepaliveWatcher = new Watcher({ pollInterval, keepaliveMs: 20 });
+      try {
+        await condition(() => keepaliveWatcher.lastKeepalive !== undefined);
+        assert(Math.abs(keepaliveWatcher.lastKeepalive - Date.now()) < 5000);
+      } finally {
+        await keepaliveWatcher.kill();
+      }
+    });
+
+    it("rejects watches beyond the maxWatches limit", async () => {
+      const limitedWatcher = new Watcher({ pollInterval, maxWatches: 1 });
+      try {
+        const sub = await limitedWatcher.watchPath(tempDirPath, () => {});
+        await assert.rejects(
+          () => limitedWatcher.watchPath(tempDirPath, () => {}),
+          /1 watches are active and the limit is 1/
+        );
+
+        // Disposing of a watch frees up room for another
+        await sub.dispose();
+        await limitedWatcher.watchPath(tempDirPath, () => {});
+      } finally {
+        await limitedWatcher.kill();
+      }
     });
 
     it("allows dispose to be called multiple times without errors", async () => {
@@ -116,6 +2724,23 @@ for (const pollInterval of [0, 1]) {
     });
   });
 
+  // The watch descriptors of the subprocess's inotify instances
+  function inotifyWatchDescriptors(watcher) {
+    const pid = watcher.childProcess.pid;
+    const descriptors = fs
+      .readdirSync(`/proc/${pid}/fd`)
+      .filter(fd => {
+        try {
+          return fs.readlinkSync(`/proc/${pid}/fd/${fd}`) === "anon_inode:inotify";
+        } catch (error) {
+          return false;
+        }
+      })
+      .map(fd => fs.readFileSync(`/proc/${pid}/fdinfo/${fd}`, "utf8"))
+      .map(info => info.split("\n").filter(line => line.startsWith("inotify wd:")));
+    return [].concat(...descriptors);
+  }
+
   function condition(fn) {
     return new Promise((resolve, reject) => {
       const timeoutError = new Error("Condition timed out");